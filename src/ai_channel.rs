@@ -1,63 +1,480 @@
+mod anthropic;
+mod attachments;
+mod blocklist;
+mod budget;
+mod cache_control;
+mod code_blocks;
+mod commands;
+mod context_length;
+mod debug_echo;
+mod embed;
+mod error_log;
+mod feedback;
+mod history;
+mod image_cache;
+mod mentions;
+mod moderation;
+mod prompt;
+mod rate_limit;
+mod raw_log;
+mod reactions;
+mod reasoning;
+mod retrieval;
+mod split;
+mod stream;
+mod summary;
+mod tokens;
+mod usage;
 mod user_message;
+mod webhook;
 
-use std::{collections::VecDeque, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Context;
 use async_openai::{
     Client as AIClient,
     config::OpenAIConfig,
     types::{
-        ChatChoice, ChatCompletionRequestMessage, ChatCompletionResponseMessage,
-        CreateChatCompletionRequestArgs,
+        ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+        ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
+        ChatCompletionRequestToolMessage, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, ImageDetail, Stop,
     },
 };
-use serde::Deserialize;
+use async_trait::async_trait;
+use blocklist::{Blocklist, BlocklistAction};
+use budget::DailyBudget;
+pub use commands::register_commands;
+use error_log::FailedGeneration;
+use history::{HistoryTrimmer, MessageCount, Summarizing, TokenBudget};
+use image_cache::ImageCache;
+use prompt::PromptVariables;
+use rate_limit::RateLimited;
+use reactions::SeenMessages;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use retrieval::{Retrieval, latest_query};
+use serde::{Deserialize, Serialize};
+use split::split_message;
+use stream::stream_response;
+use tokens::Tokenizer;
 use tokio::{
-    sync::{broadcast, mpsc},
-    time::{Instant, sleep_until},
+    select,
+    sync::{Semaphore, SemaphorePermit, broadcast, mpsc, watch},
+    task::JoinSet,
+    time::{Instant, sleep, sleep_until},
 };
-use tracing::{debug, error};
+use tracing::{Instrument, debug, error, field, info, info_span, warn};
 use twilight_gateway::Event;
 use twilight_http::Client;
-use twilight_model::id::{Id, marker::ChannelMarker};
-use user_message::queue_messages;
+use twilight_model::{
+    channel::message::{AllowedMentions, MentionType},
+    id::{
+        Id,
+        marker::{ChannelMarker, MessageMarker, RoleMarker, UserMarker},
+    },
+    util::Timestamp,
+};
+use usage::{CostAttribution, TokenUsage, UserUsage};
+use user_message::{
+    ImageOutput, QueueOverflow, QueuedEvent, ResizeFilter, ServeState, TimestampFormat,
+    UserMessage, queue_messages,
+};
+use webhook::WebhookPoster;
 
 use crate::{
-    config::file_watch::{load_prompt, monitor_prompt},
-    error::send_error_msg,
+    config::{
+        env_vars,
+        file_watch::{load_file, load_prompt, monitor_file, monitor_prompt},
+    },
+    error::{edit_to_error_msg, send_error_msg},
+    health::ChannelHealth,
+    metrics,
+    presence::Presence,
+    tools::{ToolKind, ToolRegistry},
 };
 
 #[derive(Debug, Deserialize)]
 pub struct Configuration {
     channel_id: Id<ChannelMarker>,
+    /// The API key, which can reference environment variables like `${OPENAI_API_KEY}`.
+    #[serde(deserialize_with = "env_vars::deserialize")]
     llm_api_key: String,
-    /// The base API endpoint to use. If not set the OpenAI API will be used.
+    /// The base API endpoint to use. If not set the OpenAI API will be used. Like `llm_api_key`,
+    /// this can reference environment variables.
+    #[serde(default, deserialize_with = "env_vars::deserialize_optional")]
     llm_api_base: Option<String>,
+    /// The kind of api at `llm_api_base`.
+    #[serde(default)]
+    backend: Backend,
+    /// Extra headers sent with every request to the LLM api, such as the attribution headers of
+    /// OpenRouter. The values can reference environment variables, like `llm_api_key`.
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// The `User-Agent` sent with requests to the LLM api. If not set, reqwest doesn't send one.
+    user_agent: Option<String>,
     model_name: String,
     /// The maximum amount of messages to include as history when generating a response. This does
     /// *not* include the channel prompt.
     ///
     /// When this limit is reached, the bot will remove messages until it the history has
-    /// `min_history_size` messages.
+    /// `min_history_size` messages. Not used when `tokenizer` is set.
     #[serde(default = "default_max_history_size")]
     max_history_size: u32,
     /// The minimum amount of messages that should be kept when downsizing the message history.
     #[serde(default = "default_min_history_size")]
     min_history_size: u32,
+    /// The method used to estimate the size of the history in tokens. When set, the history is
+    /// limited by `max_context_tokens` instead of `max_history_size` and `min_history_size`.
+    tokenizer: Option<Tokenizer>,
+    /// The maximum amount of tokens the channel prompt and history may use together. Only used when
+    /// `tokenizer` is set.
+    #[serde(default = "default_max_context_tokens")]
+    max_context_tokens: u32,
     /// If set to true, the LLM will also be able to see images sent by users. This requires the LLM
     /// used supports images as input.
     ///
     /// WARNING: this can be expensive.
     #[serde(default)]
     image_support: bool,
+    /// If set to true, messages removed from the history are summarized by the LLM and the summary
+    /// is kept at the start of the history.
+    #[serde(default)]
+    summarize_on_truncate: bool,
+    /// The model used to summarize removed messages. If not set, `model_name` is used.
+    summary_model: Option<String>,
+    /// If set to true, messages removed from the history are embedded, and the ones most similar
+    /// to the latest message are included in the request.
+    #[serde(default)]
+    retrieval: bool,
+    /// The amount of removed messages included with each request when `retrieval` is true.
+    #[serde(default = "default_retrieval_top_k")]
+    retrieval_top_k: usize,
+    /// The model used to embed messages for `retrieval`.
+    #[serde(default = "default_embedding_model")]
+    embedding_model: String,
+    /// The file the embedded messages are saved to, so they are kept when the bot restarts. If not
+    /// set, they are only kept in memory.
+    retrieval_index_path: Option<Box<Path>>,
+    /// The amount of the latest messages in the channel to start the history with when the bot
+    /// starts. Set to 0 to start with an empty history.
+    #[serde(default)]
+    seed_history_from_channel: u16,
+    /// If set to true, each batch of messages is answered on its own with only the prompt, and no
+    /// history is kept between them.
+    #[serde(default)]
+    stateless: bool,
     /// The maximum size images are allowed to be before sent to the API.
     ///
     /// Images that have one or both dimensions bigger than this value will be downsized.
     #[serde(default = "default_max_image_size")]
     max_image_size: u32,
+    /// The detail level images are sent with. Higher detail lets the LLM see more of the image, but
+    /// costs a lot more tokens.
+    #[serde(default = "default_image_detail")]
+    image_detail: ImageDetail,
+    /// The filter used to downsize images. Sharper filters keep text in screenshots readable.
+    #[serde(default)]
+    image_resize_filter: ResizeFilter,
+    /// The format images are re-encoded in before they are sent.
+    #[serde(default)]
+    image_format: ImageOutput,
+    /// The quality from 1 to 100 images are encoded with, when `image_format` is JPEG.
+    #[serde(default = "default_image_jpeg_quality")]
+    image_jpeg_quality: u8,
+    /// The maximum size of text files attached to messages that are included in the message, in
+    /// bytes. Larger and non-text files are replaced by a note saying they were omitted. Set to 0
+    /// to ignore attachments.
+    #[serde(default)]
+    max_attachment_bytes: u64,
+    /// Fenced code blocks in user messages with more lines than this are truncated, keeping
+    /// `code_block_kept_lines` at their start and end. If not set, the amount of lines is not
+    /// limited.
+    max_code_block_lines: Option<usize>,
+    /// Fenced code blocks in user messages with more characters than this are truncated like
+    /// `max_code_block_lines`. If not set, the amount of characters is not limited.
+    max_code_block_chars: Option<usize>,
+    /// The amount of lines kept at the start and at the end of truncated code blocks.
+    #[serde(default = "default_code_block_kept_lines")]
+    code_block_kept_lines: usize,
+    /// The maximum amount of images sent from a single message. Further images are replaced by a
+    /// note saying they were omitted.
+    max_images_per_message: Option<usize>,
+    /// The maximum amount of images sent in a single request. The oldest images in the history are
+    /// replaced by a note saying they were omitted.
+    max_images_per_request: Option<usize>,
+    /// The maximum size of the cache of downsized images, in megabytes. Set to 0 to disable it.
+    #[serde(default = "default_image_cache_mb")]
+    image_cache_mb: usize,
+    /// The maximum amount of discord messages a single response may be split into. Any content
+    /// beyond that is not sent.
+    #[serde(default = "default_max_messages_per_response")]
+    max_messages_per_response: usize,
+    /// The maximum amount of characters in each discord message of a response, which can't be more
+    /// than discord's limit of 2000.
+    #[serde(default = "default_max_response_chars")]
+    max_response_chars: usize,
+    /// If set to true, responses that start with a `# Title` line are sent as an embed, see
+    /// [`embed::structured_embed`].
+    #[serde(default)]
+    use_embeds: bool,
+    /// Posted as soon as a response starts being generated, and edited into the response once it
+    /// is done. Not used when `streaming` is true. If not set, no placeholder is posted.
+    placeholder_message: Option<String>,
+    /// If set to true, the response is posted while it is being generated and the message is
+    /// edited as more of the response comes in.
+    #[serde(default)]
+    streaming: bool,
+    /// The sampling temperature to use. Lower values make the responses more focused and
+    /// deterministic. If not set the API's default is used.
+    temperature: Option<f32>,
+    /// The nucleus sampling probability mass to use. If not set the API's default is used.
+    top_p: Option<f32>,
+    /// Penalises tokens based on how often they already appear in the history. If not set the
+    /// API's default is used.
+    frequency_penalty: Option<f32>,
+    /// Penalises tokens that already appear in the history at all. If not set the API's default is
+    /// used.
+    presence_penalty: Option<f32>,
+    /// The seed used to sample the response, so the same request gets the same response as often as
+    /// possible. If not set, responses are sampled randomly.
+    seed: Option<i64>,
+    /// Sequences that make the LLM stop generating the response when it generates them. The
+    /// sequence itself is not included in the response.
+    #[serde(default)]
+    stop: Vec<String>,
+    /// The cache control hint added to the channel prompt and the end of the history in requests,
+    /// for APIs that support explicit cache breakpoints. If not set, nothing is added.
+    extra_cache_control: Option<serde_json::Value>,
+    /// Extra parameters added to the body of requests, for parameters of the API that aren't
+    /// supported by the configuration. These override the parameters set by the bot.
+    #[serde(default)]
+    extra_params: serde_json::Map<String, serde_json::Value>,
+    /// The maximum amount of tokens the LLM may generate for a single response. When set to 0, no
+    /// limit is sent and the API's default is used.
+    #[serde(
+        default = "default_max_response_tokens",
+        deserialize_with = "deserialize_max_response_tokens"
+    )]
+    max_response_tokens: Option<u32>,
+    /// If set to true, responses are sent as a discord reply to the latest message they respond
+    /// to.
+    #[serde(default)]
+    reply_to_trigger: bool,
+    /// If set, responses are posted through a webhook of the channel with this name, so one bot
+    /// can act as several assistants. The bot creates the webhook, which requires the Manage
+    /// Webhooks permission. Webhooks can't reply to messages.
+    webhook_username: Option<String>,
+    /// The avatar responses posted through the webhook are shown with.
+    webhook_avatar_url: Option<String>,
+    /// The types of mentions in responses that ping who they mention. By default none do, so the
+    /// LLM can't be tricked into pinging everyone.
+    #[serde(default)]
+    allowed_mentions: Vec<MentionType>,
+    /// If set to true, responses sent as a reply ping the author of the message they reply to.
+    #[serde(default)]
+    ping_on_reply: bool,
+    /// If set to true, the typing indicator is shown in the channel while a response is being
+    /// generated.
+    #[serde(default)]
+    show_typing: bool,
+    /// The tools the LLM can use while generating a response. Tools are not available when
+    /// `streaming` is enabled.
+    #[serde(default)]
+    tools: Vec<ToolKind>,
+    /// The minimum time to wait between responses, in milliseconds. Lower values make the bot
+    /// respond quicker, but generate more (paid) responses in busy channels.
+    #[serde(default = "default_response_cooldown_ms")]
+    response_cooldown_ms: u64,
+    /// How long to wait for follow-up messages after a message arrives before generating a
+    /// response, in milliseconds. Higher values batch more messages into one response, which
+    /// costs less but makes the bot slower to respond.
+    #[serde(default)]
+    batch_window_ms: u64,
+    /// The role the channel prompt is sent as.
+    #[serde(default)]
+    prompt_role: PromptRole,
+    /// The name the bot's messages in the history are labeled with, which can make some models
+    /// more consistent over multiple turns.
+    assistant_name: Option<String>,
+    /// A file that is sent as a second prompt after the main prompt, for small instructions that
+    /// change often. Keeping them out of the main prompt lets the LLM api cache it.
+    prompt_append_path: Option<Box<Path>>,
+    /// An environment variable that is sent after the main prompt like `prompt_append_path`. It is
+    /// read again for every response.
+    prompt_append_env: Option<String>,
+    /// The language the bot must always respond in, e.g. "German". An instruction to do so is
+    /// sent after the history, which the model follows more reliably than the prompt.
+    force_language: Option<String>,
+    /// The response the model gives when it chooses not to respond. This only applies when it is
+    /// the entire response.
+    #[serde(default = "default_no_response_marker")]
+    no_response_marker: String,
+    /// What to do with the history when the model chooses not to respond.
+    #[serde(default)]
+    no_response_action: NoResponseAction,
+    /// If set to true, `mention_fallback_response` is sent instead when the model chooses not to
+    /// respond to messages that mention the bot.
+    #[serde(default)]
+    force_reply_on_mention: bool,
+    /// The response sent when the model chooses not to respond to a mention.
+    #[serde(default = "default_mention_fallback_response")]
+    mention_fallback_response: String,
+    /// If set to true, the history of the channel and its threads is cleared when the prompt file
+    /// changes, so old responses don't conflict with the new prompt.
+    #[serde(default)]
+    clear_history_on_prompt_change: bool,
+    /// The format the LLM must respond in.
+    #[serde(default)]
+    response_format: ResponseFormat,
+    /// The minimum time between messages from the same user that will be responded to, in
+    /// milliseconds. Set to 0 to disable.
+    #[serde(default)]
+    per_user_cooldown_ms: u64,
+    /// If set to true, messages from users on cooldown are still added to the history as context,
+    /// but don't trigger a response. Otherwise they are ignored.
+    #[serde(default)]
+    keep_cooldown_messages: bool,
+    /// What to do with new messages when too many are waiting to be handled, such as when the
+    /// LLM is slow to respond in a busy channel.
+    #[serde(default)]
+    queue_overflow: QueueOverflow,
+    /// Messages with fewer characters than this, not counting mentions of the bot, are added to
+    /// the history without triggering a response.
+    #[serde(default)]
+    min_message_chars: usize,
+    /// If set to true, messages that only contain emojis are added to the history without
+    /// triggering a response.
+    #[serde(default)]
+    ignore_only_emoji: bool,
+    /// If set to true, messages from other bots are ignored, unless they are in `allowed_bot_ids`.
+    /// The bot's own messages are always ignored.
+    #[serde(default = "default_ignore_bots")]
+    ignore_bots: bool,
+    /// The bots whose messages are responded to even if `ignore_bots` is true.
+    #[serde(default)]
+    allowed_bot_ids: Vec<Id<UserMarker>>,
+    /// If this or `allowed_role_ids` is set, only messages from these users or users with one of
+    /// the roles are used. Otherwise messages from everyone are used.
+    #[serde(default)]
+    allowed_user_ids: Vec<Id<UserMarker>>,
+    /// See `allowed_user_ids`.
+    #[serde(default)]
+    allowed_role_ids: Vec<Id<RoleMarker>>,
+    /// If set to true, the bot also responds in threads created from the channel. Each thread has
+    /// its own history.
+    #[serde(default)]
+    include_threads: bool,
+    /// If set to true, user, role and channel mentions are replaced by their names before being
+    /// sent to the LLM.
+    #[serde(default)]
+    resolve_mentions: bool,
+    /// How the time each message was sent is included in the messages sent to the LLM.
+    #[serde(default)]
+    include_timestamps: TimestampFormat,
+    /// Adding this reaction to the bot's last response makes it regenerate the response. Set to an
+    /// empty string to disable.
+    #[serde(default = "default_regenerate_emoji")]
+    regenerate_emoji: String,
+    /// The users allowed to regenerate responses. If not set, anyone can.
+    regenerate_allowed_users: Option<Vec<Id<UserMarker>>>,
+    /// Adding this reaction to the bot's last response continues it, if it was truncated. Replying
+    /// "continue" does the same. Set to an empty string to only allow replying.
+    #[serde(default = "default_continue_emoji")]
+    continue_emoji: String,
+    /// Added as a reaction to messages that will be responded to, until the response is sent.
+    /// Set to an empty string to disable.
+    #[serde(default)]
+    seen_emoji: String,
+    /// Added as a reaction to messages once they were responded to, replacing `seen_emoji`. Set to
+    /// an empty string to only remove `seen_emoji`.
+    #[serde(default)]
+    done_emoji: String,
+    /// Added as a reaction to messages that wait for `response_cooldown_ms` to pass, until the
+    /// response to them starts. Set to an empty string to disable.
+    #[serde(default)]
+    cooldown_emoji: String,
+    /// If set to true, responses are checked by the moderation endpoint before they are sent.
+    /// Flagged responses are replaced by `moderation_refusal`.
+    #[serde(default)]
+    moderation: bool,
+    /// Sent instead of responses that are flagged by moderation.
+    #[serde(default = "default_moderation_refusal")]
+    moderation_refusal: String,
+    /// If set to true, responses are refused when the moderation endpoint can't be reached.
+    /// Otherwise they are sent unmoderated.
+    #[serde(default)]
+    moderation_fail_closed: bool,
+    /// The filepath to a list of patterns that must not appear in responses, one regex per line.
+    /// Changes to the file are applied while the bot is running.
+    blocklist_path: Option<Box<Path>>,
+    /// What to do with responses that match the blocklist.
+    #[serde(default)]
+    blocklist_action: BlocklistAction,
+    /// If set to true, user messages that match the blocklist are ignored, so they never reach
+    /// the LLM.
+    #[serde(default)]
+    blocklist_user_messages: bool,
+    /// Models to try in order when generating a response with `model_name` fails.
+    #[serde(default)]
+    fallback_models: Vec<FallbackModel>,
+    /// How long to wait for the LLM api to respond before giving up, in seconds. When streaming,
+    /// this is how long to wait for each part of the response.
+    #[serde(default = "default_request_timeout_secs")]
+    request_timeout_secs: u64,
+    /// How long to keep retrying failed requests to the LLM api, in seconds.
+    #[serde(default = "default_backoff_max_elapsed_secs")]
+    backoff_max_elapsed_secs: u64,
+    /// How long to wait before the first retry of a failed request, in milliseconds.
+    #[serde(default = "default_backoff_initial_interval_ms")]
+    backoff_initial_interval_ms: u64,
+    /// How much longer to wait before each following retry.
+    #[serde(default = "default_backoff_multiplier")]
+    backoff_multiplier: f64,
+    /// The price in dollars of 1000 prompt tokens, used to log the estimated cost of responses.
+    cost_per_1k_prompt: Option<f64>,
+    /// The price in dollars of 1000 completion tokens, used to log the estimated cost of responses.
+    cost_per_1k_completion: Option<f64>,
+    /// The maximum amount of tokens the channel can use each day. Once reached, the bot stops
+    /// responding until the next day. Not limited if this is not set.
+    daily_token_budget: Option<u64>,
+    /// The maximum amount of responses the channel can request each day, like `daily_token_budget`.
+    daily_request_budget: Option<u64>,
+    /// The offset from UTC in hours of the timezone the daily budgets reset at midnight in.
+    #[serde(default)]
+    budget_utc_offset: i32,
+    /// The file the usage of the daily budgets is saved to, so restarting doesn't reset it.
+    budget_state_path: Option<Box<Path>>,
+    /// The file to append a line of JSON to for every response that fails to be generated.
+    error_log_path: Option<Box<Path>>,
+    /// How the tokens of each response are attributed to the users that triggered it, which is
+    /// logged with their running total.
+    #[serde(default)]
+    cost_attribution: CostAttribution,
+    /// The file to append a line of JSON to for every user tokens are attributed to. Only used
+    /// when `cost_attribution` is set.
+    usage_log_path: Option<Box<Path>>,
+    /// Reactions to the bot's responses with these emojis are recorded as feedback, in the
+    /// metrics and `feedback_log_path`. Set to an empty list to disable.
+    #[serde(default = "default_feedback_emojis")]
+    feedback_emojis: Vec<String>,
+    /// The file to append a line of JSON to for every feedback reaction.
+    feedback_log_path: Option<Box<Path>>,
+    /// If set to true, the messages sent to the LLM are posted after each response, to help with
+    /// tuning the prompt.
+    #[serde(default)]
+    debug_echo: bool,
+    /// The user that the messages posted by `debug_echo` are sent to in a DM. If not set, they are
+    /// posted in the channel.
+    debug_echo_user: Option<Id<UserMarker>>,
     /// The filepath to the prompt used for this channel.
     ///
-    /// This should be a plain text file.
+    /// This should be a plain text file. See [`PromptVariables`] for the variables it can contain.
     prompt_path: Box<Path>,
 }
 
@@ -69,225 +486,2943 @@ impl Configuration {
     pub fn get_channel_id(&self) -> &Id<ChannelMarker> {
         &self.channel_id
     }
-}
 
-fn default_max_history_size() -> u32 {
-    40
-}
+    #[cfg(test)]
+    pub fn get_llm_api_key(&self) -> &str {
+        &self.llm_api_key
+    }
 
-fn default_min_history_size() -> u32 {
-    30
-}
+    #[cfg(test)]
+    pub fn get_model_name(&self) -> &str {
+        &self.model_name
+    }
 
-fn default_max_image_size() -> u32 {
-    800
-}
+    /// The API keys of the model and its fallbacks, which must not be shown anywhere.
+    fn api_keys(&self) -> impl Iterator<Item = &str> {
+        [Some(self.llm_api_key.as_str())]
+            .into_iter()
+            .chain(
+                self.fallback_models
+                    .iter()
+                    .map(|model| model.llm_api_key.as_deref()),
+            )
+            .flatten()
+            .filter(|key| !key.is_empty())
+    }
 
-/// Runs the main AI channel logic.
-pub async fn serve(
-    config: Configuration,
-    events: broadcast::Receiver<Arc<Event>>,
-    http: Arc<Client>,
-) {
-    let (prompt_sender, prompt_receiver) = match load_prompt(config.get_prompt_path()).await {
-        Ok(var) => var,
-        Err(err) => {
-            tracing::error!("Unable to read channel prompt: {err}");
-            tracing::error!(
-                "Channel with id '{}' will not be activated",
-                config.get_channel_id()
-            );
-            return;
+    /// The prices of 1000 prompt and completion tokens, if either is configured.
+    fn prices(&self) -> Option<(f64, f64)> {
+        match (self.cost_per_1k_prompt, self.cost_per_1k_completion) {
+            (None, None) => None,
+            (prompt, completion) => Some((prompt.unwrap_or(0.0), completion.unwrap_or(0.0))),
         }
-    };
+    }
 
-    if let Err(err) = monitor_prompt(config.get_prompt_path(), prompt_sender) {
-        tracing::error!(
-            "Unable to watch prompt file at '{}' for channel '{}'. The channel will be active, but the prompt wont be updated unless the program is restarted.",
-            config.get_prompt_path().display(),
-            config.get_channel_id()
-        );
-        tracing::error!("{err}");
-    };
+    /// The maximum amount of characters in each message of a response, within discord's limit.
+    fn max_message_chars(&self) -> usize {
+        self.max_response_chars.clamp(1, MAX_MESSAGE_CHARS)
+    }
 
-    let mut llm_config = OpenAIConfig::new().with_api_key(&config.llm_api_key);
-    if let Some(api_base) = &config.llm_api_base {
-        llm_config = llm_config.with_api_base(api_base);
+    /// The mentions in responses that are allowed to ping who they mention.
+    fn allowed_mentions(&self) -> AllowedMentions {
+        AllowedMentions {
+            parse: self.allowed_mentions.clone(),
+            replied_user: self.ping_on_reply,
+            ..Default::default()
+        }
     }
-    let llm_client = AIClient::with_config(llm_config).with_backoff(
-        backoff::ExponentialBackoffBuilder::new()
-            .with_max_elapsed_time(Some(Duration::from_secs(5)))
-            .build(),
-    );
 
-    let max_history_size = config.max_history_size as usize;
-    let (message_tx, mut message_rx) = mpsc::channel(max_history_size / 2);
+    fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
 
-    // Spawn a task to handle incoming message events and queue them in the channel above.
-    tokio::spawn(queue_messages(events, message_tx, config.channel_id));
+    /// Check if the model chose not to respond. JSON responses are always sent.
+    fn is_no_response(&self, response: &str) -> bool {
+        self.response_format == ResponseFormat::Text && response.trim() == self.no_response_marker
+    }
 
-    let mut last_response_time = Instant::now();
-    let mut last_error_response = None;
-    let mut history = VecDeque::new();
+    /// The response to send instead, if the model chose not to respond even though the bot was
+    /// mentioned and mentions must be replied to.
+    fn forced_reply(&self, response: &str, mentioned: bool) -> Option<&str> {
+        (self.force_reply_on_mention && mentioned && self.is_no_response(response))
+            .then_some(self.mention_fallback_response.as_str())
+    }
 
-    // Batch new messages together to avoid generating a separate response to each one.
-    let mut new_messages = Vec::new();
-    loop {
-        // Wait to avoid getting rate limited by the LLM endpoint.
-        // TODO: this could be handled better.
-        sleep_until(last_response_time + Duration::from_millis(1500)).await;
+    /// A message by the bot, labeled with `assistant_name` if it is set.
+    fn assistant_message(&self, content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+            content: Some(ChatCompletionRequestAssistantMessageContent::Text(
+                content.to_string(),
+            )),
+            name: self.assistant_name.clone(),
+            ..Default::default()
+        })
+    }
 
-        let recv_amt = message_rx
-            .recv_many(&mut new_messages, max_history_size)
-            .await;
+    /// The instruction to respond in `force_language`, if it is set.
+    fn language_instruction(&self) -> Option<ChatCompletionRequestMessage> {
+        let language = self.force_language.as_deref().map(str::trim)?;
+        (!language.is_empty()).then(|| {
+            self.prompt_role.message(&format!(
+                "Always respond in {language}, regardless of the language of the messages."
+            ))
+        })
+    }
 
-        if recv_amt == 0 {
-            // The message ingestion channel has closed, gracefully shut down this task.
-            break;
+    /// The headers sent with every request to the LLM api, with the environment variables in their
+    /// values expanded.
+    fn llm_headers(&self) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|err| format!("header name '{name}' is invalid: {err}"))?;
+            // The value isn't included in the error, as it may be a secret.
+            let value = env_vars::expand(value)
+                .and_then(|value| HeaderValue::from_str(&value).map_err(|err| err.to_string()))
+                .map_err(|err| format!("header '{name}' has an invalid value: {err}"))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    /// Check for settings that would stop the channel from working, returning every problem that
+    /// was found so they can be fixed at once.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        if self.min_history_size > self.max_history_size {
+            problems.push(format!(
+                "min_history_size ({}) must not be larger than max_history_size ({})",
+                self.min_history_size, self.max_history_size
+            ));
+        }
+        // Smaller messages leave no room for code fences and notes, such as the truncation note.
+        if self.max_response_chars < MIN_MESSAGE_CHARS {
+            problems.push(format!(
+                "max_response_chars must be at least {MIN_MESSAGE_CHARS}, but is {}",
+                self.max_response_chars
+            ));
+        }
+        // Every response would be discarded.
+        if self.max_messages_per_response == 0 {
+            problems.push("max_messages_per_response must be at least 1".to_string());
+        }
+        // Half of the history size is used as the size of the message queue.
+        if self.max_history_size < 2 {
+            problems.push(format!(
+                "max_history_size must be at least 2, but is {}",
+                self.max_history_size
+            ));
+        }
+        if self.max_image_size == 0 {
+            problems.push("max_image_size must be larger than 0".to_string());
+        }
+        if !(1..=100).contains(&self.image_jpeg_quality) {
+            problems.push(format!(
+                "image_jpeg_quality must be between 1 and 100, but is {}",
+                self.image_jpeg_quality
+            ));
+        }
+        // The OpenAI api only accepts names made of these characters.
+        if let Some(name) = &self.assistant_name
+            && (name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        {
+            problems.push(format!(
+                "assistant_name '{name}' must only contain letters, digits, '_' and '-'"
+            ));
+        }
+        if let Some(username) = &self.webhook_username {
+            if !(1..=80).contains(&username.chars().count()) {
+                problems.push("webhook_username must be 1 to 80 characters long".to_string());
+            }
+            // Only the responses are posted through the webhook.
+            if self.streaming || self.placeholder_message.is_some() {
+                problems.push(
+                    "webhook_username can't be used with streaming or placeholder_message"
+                        .to_string(),
+                );
+            }
+        }
+        if !(-12..=14).contains(&self.budget_utc_offset) {
+            problems.push(format!(
+                "budget_utc_offset must be between -12 and 14, but is {}",
+                self.budget_utc_offset
+            ));
+        }
+        if let Err(err) = std::fs::File::open(&self.prompt_path) {
+            problems.push(format!(
+                "prompt_path '{}' can't be read: {err}",
+                self.prompt_path.display()
+            ));
+        }
+        if self.llm_api_key.trim().is_empty() {
+            problems.push("llm_api_key must not be empty".to_string());
+        }
+        if let Err(problem) = self.llm_headers() {
+            problems.push(problem);
+        }
+        if let Some(user_agent) = &self.user_agent
+            && let Err(err) = HeaderValue::from_str(user_agent)
+        {
+            problems.push(format!("user_agent is invalid: {err}"));
+        }
+        if self.backend == Backend::Anthropic {
+            for (enabled, setting) in [
+                (self.streaming, "streaming"),
+                (!self.tools.is_empty(), "tools"),
+                (self.summarize_on_truncate, "summarize_on_truncate"),
+                (self.moderation, "moderation"),
+                (self.retrieval, "retrieval"),
+                (
+                    self.response_format != ResponseFormat::Text,
+                    "response_format",
+                ),
+            ] {
+                if enabled {
+                    problems.push(format!(
+                        "{setting} isn't supported with the anthropic backend"
+                    ));
+                }
+            }
+        }
+        if self.image_support
+            && TEXT_ONLY_MODELS
+                .iter()
+                .any(|model| self.model_name.starts_with(model))
+        {
+            problems.push(format!(
+                "model '{}' doesn't accept images, so image_support must be false",
+                self.model_name
+            ));
         }
 
-        let current_prompt =
-            ChatCompletionRequestMessage::System(prompt_receiver.borrow().as_ref().into());
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
 
-        for msg in &new_messages {
-            let msg =
-                ChatCompletionRequestMessage::User(msg.as_chat_completion_message(&config).await);
+    /// Log warnings for settings that are valid, but likely to be a mistake.
+    pub fn warn_unusual_settings(&self) {
+        if let Some(max_response_tokens) = self.max_response_tokens
+            && max_response_tokens > MAX_RESPONSE_TOKENS_CEILING
+        {
+            warn!(
+                "Channel '{}' allows responses of up to {max_response_tokens} tokens. Responses this long can be expensive and will be split over many discord messages.",
+                self.channel_id
+            );
+        }
 
-            history.push_back(msg);
+        if self.max_response_chars > MAX_MESSAGE_CHARS {
+            warn!(
+                "Channel '{}' allows messages of up to {} characters, but discord only allows {MAX_MESSAGE_CHARS}. Messages are limited to {MAX_MESSAGE_CHARS} characters instead.",
+                self.channel_id, self.max_response_chars
+            );
         }
-        new_messages.clear();
 
-        if history.len() > max_history_size {
-            // Downsize the history buffer by removing some elements from the front until it is back
-            // to `min_history_size`. This is to ensure all messages fit in the context window while
-            // allowing the LLM cache to be re-used for the next messages.
-            let remove_from_front = history
-                .len()
-                .saturating_sub(config.min_history_size as usize);
-            // TODO: count history in tokens rather amount of messages.
-            history.drain(0..remove_from_front);
+        if self.stop.len() > MAX_STOP_SEQUENCES {
+            warn!(
+                "Channel '{}' has {} stop sequences, but the OpenAI api only accepts up to {MAX_STOP_SEQUENCES}.",
+                self.channel_id,
+                self.stop.len()
+            );
+        }
 
-            debug!("Downsized history to {}", history.len());
+        if self.response_format == ResponseFormat::JsonObject && self.streaming {
+            warn!(
+                "Channel '{}' requires JSON responses while streaming them. Streamed responses are posted before they can be checked, so invalid JSON is not retried.",
+                self.channel_id
+            );
         }
 
-        let messages: Vec<_> = [current_prompt]
-            .into_iter()
-            .chain(history.iter().cloned())
-            .collect();
+        if self.moderation && self.streaming {
+            warn!(
+                "Channel '{}' moderates responses while streaming them. Responses are visible while they are generated, before they are moderated.",
+                self.channel_id
+            );
+        }
 
-        let response = generate_response(&llm_client, &config.model_name, messages).await;
-        last_response_time = Instant::now();
+        if self.stateless
+            && (self.seed_history_from_channel > 0 || self.summarize_on_truncate || self.retrieval)
+        {
+            warn!(
+                "Channel '{}' is stateless, so seed_history_from_channel, summarize_on_truncate and retrieval have no effect.",
+                self.channel_id
+            );
+        }
+    }
+}
 
-        // Delete the previous error message. This should happen both if there is a new error
-        // message or there is another error.
-        if let Some(prev_err_msg_id) = last_error_response {
-            let http2 = http.clone();
-            tokio::spawn(async move {
-                if let Err(err) = http2
-                    .delete_message(config.channel_id, prev_err_msg_id)
-                    .await
-                {
-                    error!("Failed to delete previous error message: {err}");
-                }
-            });
+/// A model used when the models before it fail to generate a response.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "FallbackModelConfig")]
+struct FallbackModel {
+    model_name: String,
+    /// The API key for the model. If not set, `llm_api_key` is used.
+    llm_api_key: Option<String>,
+    /// The API to query for the model. If not set, `llm_api_base` is used.
+    llm_api_base: Option<String>,
+}
+
+/// Fallback models can be configured with only their name, or with their own api.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FallbackModelConfig {
+    Name(String),
+    Model {
+        model_name: String,
+        llm_api_key: Option<String>,
+        llm_api_base: Option<String>,
+    },
+}
 
-            last_error_response = None;
+impl TryFrom<FallbackModelConfig> for FallbackModel {
+    type Error = String;
+
+    /// Environment variables are expanded here instead of while deserializing, as the errors of
+    /// untagged enums don't say what went wrong.
+    fn try_from(config: FallbackModelConfig) -> Result<Self, Self::Error> {
+        match config {
+            FallbackModelConfig::Name(model_name) => Ok(Self {
+                model_name,
+                llm_api_key: None,
+                llm_api_base: None,
+            }),
+            FallbackModelConfig::Model {
+                model_name,
+                llm_api_key,
+                llm_api_base,
+            } => Ok(Self {
+                model_name,
+                llm_api_key: llm_api_key.as_deref().map(env_vars::expand).transpose()?,
+                llm_api_base: llm_api_base.as_deref().map(env_vars::expand).transpose()?,
+            }),
         }
+    }
+}
 
-        let mut response_content = match response {
-            Ok(v) => v,
-            Err(err) => {
-                error!("Error creating response: {err:?}");
+/// Response token limits above this are probably a mistake.
+const MAX_RESPONSE_TOKENS_CEILING: u32 = 16_384;
 
-                // Log the error in the channel.
-                let err_msg = send_error_msg(
-                    &http,
-                    config.channel_id,
-                    &format!("Something went wrong while generating a response\n```\n{err}\n```"),
-                )
-                .await;
+/// The most stop sequences the OpenAI api accepts in a request.
+const MAX_STOP_SEQUENCES: usize = 4;
 
-                if let Some(err_msg) = err_msg {
-                    last_error_response = Some(err_msg.id);
-                };
-                continue;
-            }
-        };
-        // Take only the first 2000 characters to stay within the discord character limit.
-        response_content.truncate(
-            response_content
-                .char_indices()
-                .take(2000)
-                .map(|v| v.0 + v.1.len_utf8())
-                .last()
-                .unwrap_or(0),
-        );
+/// Models that are known to not accept images, matched by the start of the model name.
+const TEXT_ONLY_MODELS: &[&str] = &[
+    "gpt-3.5",
+    "o1-mini",
+    "o3-mini",
+    "deepseek-chat",
+    "deepseek-reasoner",
+];
+
+/// The kind of api used to generate responses.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// The OpenAI chat completions api, which most providers are compatible with.
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Anthropic's native Messages api.
+    Anthropic,
+}
+
+/// The role used to send the channel prompt to the LLM.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptRole {
+    #[default]
+    System,
+    /// Newer OpenAI models give developer messages more weight than system messages.
+    Developer,
+}
 
-        if response_content.contains("<empty/>") {
-            debug!("Model chose to not respond");
-            continue;
+impl PromptRole {
+    /// Create a message with this role.
+    fn message(&self, content: &str) -> ChatCompletionRequestMessage {
+        match self {
+            PromptRole::System => ChatCompletionRequestMessage::System(content.into()),
+            PromptRole::Developer => ChatCompletionRequestMessage::Developer(content.into()),
         }
+    }
+}
 
-        history.push_back(ChatCompletionRequestMessage::Assistant(
-            response_content.as_str().into(),
-        ));
+/// What to do with the history when the model chooses not to respond.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoResponseAction {
+    /// Keep the messages that weren't responded to as context.
+    #[default]
+    KeepMessages,
+    /// Keep the messages, followed by the no response marker as the model's response. Some models
+    /// get confused by many user messages without responses in between.
+    AddMarker,
+    /// Remove the messages that weren't responded to from the history.
+    DropMessages,
+}
 
-        if let Err(err) = http
-            .create_message(config.channel_id)
-            .content(&response_content)
-            .await
-        {
-            error!("Failed to send response message: {err}");
-            continue;
+/// The format the LLM must respond in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    /// Every response must be a JSON object. Responses that aren't valid JSON are generated again
+    /// once.
+    JsonObject,
+}
+
+impl ResponseFormat {
+    /// The format to send in the request. Nothing is sent for text, as that is the default and
+    /// not every API supports the parameter.
+    fn api_format(&self) -> Option<async_openai::types::ResponseFormat> {
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => Some(async_openai::types::ResponseFormat::JsonObject),
         }
     }
 
-    // Don't clutter the channel with lots of error messages.
-    if let Some(msg_id) = last_error_response {
-        _ = http.delete_message(config.channel_id, msg_id).await;
+    /// Check if the response is in this format.
+    fn is_valid(&self, response: &str) -> bool {
+        match self {
+            ResponseFormat::Text => true,
+            ResponseFormat::JsonObject => {
+                serde_json::from_str::<serde_json::Value>(response).is_ok()
+            }
+        }
     }
 }
 
-/// Sent by the model in response to a chat history.
-///
-/// A custom type is used here as some (gemini *caugh caugh*) APIs dont return all fields.
-#[derive(Debug, Deserialize)]
-struct ChatCompletionResponse {
-    choices: Vec<ChatChoice>,
+fn default_ignore_bots() -> bool {
+    true
 }
 
-/// Send the chat history to the LLM api and generate a response based on this history.
-async fn generate_response(
-    client: &AIClient<OpenAIConfig>,
-    model_name: &str,
-    history: Vec<ChatCompletionRequestMessage>,
-) -> anyhow::Result<String> {
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(model_name)
-        .max_tokens(400u32)
-        .messages(history)
-        .build()
-        .context("Failed to build request")?;
-
-    let response: ChatCompletionResponse = client
-        .chat()
-        .create_byot(request)
-        .await
-        .context("LLM api returned an error")?;
-
-    let response_content = match response.choices.first() {
-        Some(ChatChoice {
-            message:
-                ChatCompletionResponseMessage {
-                    content: Some(content),
-                    ..
-                },
-            ..
-        }) => content.as_str(),
-        _ => {
-            anyhow::bail!("LLM response did not include message content");
-        }
-    };
+fn default_max_history_size() -> u32 {
+    40
+}
+
+fn default_min_history_size() -> u32 {
+    30
+}
+
+fn default_max_context_tokens() -> u32 {
+    8000
+}
+
+fn default_max_response_tokens() -> Option<u32> {
+    Some(400)
+}
+
+fn deserialize_max_response_tokens<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let max_response_tokens = u32::deserialize(deserializer)?;
+    Ok((max_response_tokens != 0).then_some(max_response_tokens))
+}
+
+fn default_max_image_size() -> u32 {
+    800
+}
+
+fn default_image_jpeg_quality() -> u8 {
+    75
+}
+
+fn default_image_detail() -> ImageDetail {
+    // Images can be very expensive in terms of tokens.
+    ImageDetail::Low
+}
+
+fn default_image_cache_mb() -> usize {
+    16
+}
+
+fn default_max_messages_per_response() -> usize {
+    3
+}
+
+fn default_max_response_chars() -> usize {
+    MAX_MESSAGE_CHARS
+}
+
+fn default_no_response_marker() -> String {
+    "<empty/>".to_string()
+}
+
+fn default_mention_fallback_response() -> String {
+    "I'm not sure how to help with that.".to_string()
+}
+
+fn default_response_cooldown_ms() -> u64 {
+    1500
+}
+
+fn default_regenerate_emoji() -> String {
+    "🔁".to_string()
+}
+
+fn default_continue_emoji() -> String {
+    "⏩".to_string()
+}
+
+fn default_feedback_emojis() -> Vec<String> {
+    vec!["👍".to_string(), "👎".to_string()]
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_backoff_max_elapsed_secs() -> u64 {
+    5
+}
+
+fn default_backoff_initial_interval_ms() -> u64 {
+    backoff::default::INITIAL_INTERVAL_MILLIS
+}
+
+fn default_backoff_multiplier() -> f64 {
+    backoff::default::MULTIPLIER
+}
+
+fn default_code_block_kept_lines() -> usize {
+    10
+}
+
+fn default_retrieval_top_k() -> usize {
+    5
+}
+
+fn default_embedding_model() -> String {
+    "text-embedding-3-small".to_string()
+}
+
+fn default_moderation_refusal() -> String {
+    "I can't help with that.".to_string()
+}
 
-    Ok(response_content.to_string())
+/// The maximum amount of characters in a discord message.
+const MAX_MESSAGE_CHARS: usize = 2000;
+
+/// The minimum value of `max_response_chars`.
+const MIN_MESSAGE_CHARS: usize = 100;
+
+/// The longest a download of an attachment or image may take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum amount of times the LLM may call tools before giving a response.
+const MAX_TOOL_ITERATIONS: usize = 5;
+
+/// The longest the api may ask us to wait before retrying a rate limited request. Longer delays are
+/// handled by extending the response cooldown instead.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// The endpoint chat completions are created with, as it is shown in the raw log.
+pub(super) const CHAT_ENDPOINT: &str = "chat/completions";
+
+/// The temperature APIs use when none is set.
+const DEFAULT_TEMPERATURE: f32 = 1.0;
+
+/// How much the temperature is increased when generating a blank response again, so the LLM is
+/// less likely to generate the same blank response.
+const BLANK_RETRY_TEMPERATURE_INCREASE: f32 = 0.2;
+
+/// The highest temperature the OpenAI API accepts.
+const MAX_TEMPERATURE: f32 = 2.0;
+
+/// Added to the end of responses that were cut off by the maximum amount of response tokens, so
+/// users aren't misled into thinking they are complete.
+const TRUNCATION_NOTE: &str = "\n-# … (response truncated, reply \"continue\" for the rest)";
+
+/// Sent once a day, when the daily budget of the channel is reached.
+const DAILY_LIMIT_NOTICE: &str =
+    "The daily limit of this channel has been reached, I'll be back to respond tomorrow.";
+
+/// Sent after the history when continuing a truncated response.
+const CONTINUE_INSTRUCTION: &str = "Your last response was cut off. Continue it exactly where it \
+    stopped, without repeating any of it or commenting on the interruption.";
+
+/// How often the typing indicator is re-triggered while generating a response.
+const TYPING_INTERVAL: Duration = Duration::from_secs(8);
+
+/// Serve every AI channel, each in its own task.
+///
+/// The channels finish their current response and stop once `shutdown` is set to true, or
+/// immediately when the returned [`JoinSet`] is dropped. If `max_concurrent_requests` is set, at
+/// most that many requests are sent to the LLM apis at once, across every channel.
+pub fn serve_all(
+    configs: Vec<Configuration>,
+    events: &broadcast::Receiver<Arc<Event>>,
+    http: Arc<Client>,
+    bot_id: Id<UserMarker>,
+    shutdown: watch::Receiver<bool>,
+    max_concurrent_requests: Option<NonZeroUsize>,
+    presence: Arc<Presence>,
+) -> JoinSet<()> {
+    let request_limit = max_concurrent_requests.map(|max| Arc::new(Semaphore::new(max.get())));
+    let mut channels = JoinSet::new();
+    for config in configs {
+        // Identify the channel in every log line of the task.
+        let span = info_span!(
+            "ai_channel",
+            channel_id = %config.channel_id,
+            model = %config.model_name
+        );
+        channels.spawn(
+            serve(
+                config,
+                events.resubscribe(),
+                http.clone(),
+                bot_id,
+                shutdown.clone(),
+                request_limit.clone(),
+                presence.clone(),
+            )
+            .instrument(span),
+        );
+    }
+    channels
+}
+
+/// Create the HTTP client used for the LLM api, which sends the configured headers.
+fn build_http_client(config: &Configuration) -> reqwest::Client {
+    // Invalid headers are reported when the configuration is validated at startup.
+    let headers = config.llm_headers().unwrap_or_default();
+    let mut builder = reqwest::Client::builder().default_headers(headers);
+    if let Some(user_agent) = &config.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    builder.build().unwrap_or_else(|err| {
+        error!("Failed to build the HTTP client for the LLM api: {err}");
+        reqwest::Client::new()
+    })
+}
+
+/// Create the HTTP client used to download attachments and images, which sends the configured
+/// user agent. Downloads time out, so a stalled one doesn't hold up the response.
+fn build_download_client(config: &Configuration) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().timeout(DOWNLOAD_TIMEOUT);
+    if let Some(user_agent) = &config.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    builder.build().unwrap_or_else(|err| {
+        error!("Failed to build the HTTP client for downloads: {err}");
+        reqwest::Client::new()
+    })
+}
+
+/// Create the client used to send requests to the LLM api.
+fn build_llm_client(
+    config: &Configuration,
+    http: reqwest::Client,
+    api_key: &str,
+    api_base: Option<&str>,
+) -> AIClient<OpenAIConfig> {
+    let mut llm_config = OpenAIConfig::new().with_api_key(api_key);
+    if let Some(api_base) = api_base {
+        llm_config = llm_config.with_api_base(api_base);
+    }
+    AIClient::with_config(llm_config)
+        .with_http_client(http)
+        .with_backoff(
+            backoff::ExponentialBackoffBuilder::new()
+                .with_initial_interval(Duration::from_millis(config.backoff_initial_interval_ms))
+                .with_multiplier(config.backoff_multiplier)
+                .with_max_elapsed_time(Some(Duration::from_secs(config.backoff_max_elapsed_secs)))
+                .build(),
+        )
+}
+
+/// A model and the client used to query it.
+struct LlmModel {
+    client: AIClient<OpenAIConfig>,
+    name: String,
+    /// Limits the requests sent at once, shared by every channel.
+    request_limit: Option<Arc<Semaphore>>,
+    /// How responses are generated with the api of the model.
+    backend: Arc<dyn LlmBackend>,
+}
+
+impl LlmModel {
+    /// Wait until a request may be sent, if the concurrent requests are limited.
+    ///
+    /// The request must be sent while the returned permit is held.
+    async fn acquire_permit(&self) -> Option<SemaphorePermit<'_>> {
+        acquire_permit(self.request_limit.as_deref()).await
+    }
+}
+
+/// Wait until a request may be sent, if the concurrent requests are limited. This is also used for
+/// the requests that aren't sent by a [`LlmModel`], such as summaries and embeddings.
+///
+/// The request must be sent while the returned permit is held.
+async fn acquire_permit(request_limit: Option<&Semaphore>) -> Option<SemaphorePermit<'_>> {
+    let request_limit = request_limit?;
+    if request_limit.available_permits() == 0 {
+        debug!("Waiting for another request to the LLM api to finish");
+    }
+    // The semaphore is never closed.
+    request_limit.acquire().await.ok()
+}
+
+/// Create the models used to generate responses, starting with the primary model followed by the
+/// fallback models.
+fn llm_models(config: &Configuration, request_limit: Option<Arc<Semaphore>>) -> Vec<LlmModel> {
+    let http = build_http_client(config);
+    let model = |name: &str, api_key: &str, api_base: Option<&str>| LlmModel {
+        client: build_llm_client(config, http.clone(), api_key, api_base),
+        name: name.to_string(),
+        request_limit: request_limit.clone(),
+        backend: match config.backend {
+            Backend::OpenAI => Arc::new(OpenAI),
+            Backend::Anthropic => Arc::new(anthropic::Anthropic {
+                http: http.clone(),
+                api_base: api_base.unwrap_or(anthropic::DEFAULT_API_BASE).to_string(),
+                api_key: api_key.to_string(),
+            }),
+        },
+    };
+
+    let primary = model(
+        &config.model_name,
+        &config.llm_api_key,
+        config.llm_api_base.as_deref(),
+    );
+    let fallbacks = config.fallback_models.iter().map(|fallback| {
+        model(
+            &fallback.model_name,
+            fallback.llm_api_key.as_ref().unwrap_or(&config.llm_api_key),
+            fallback
+                .llm_api_base
+                .as_deref()
+                .or(config.llm_api_base.as_deref()),
+        )
+    });
+
+    [primary].into_iter().chain(fallbacks).collect()
+}
+
+/// Runs the main AI channel logic, until `shutdown` is set to true.
+pub async fn serve(
+    config: Configuration,
+    events: broadcast::Receiver<Arc<Event>>,
+    http: Arc<Client>,
+    bot_id: Id<UserMarker>,
+    mut shutdown: watch::Receiver<bool>,
+    request_limit: Option<Arc<Semaphore>>,
+    presence: Arc<Presence>,
+) {
+    let health = ChannelHealth::register(config.channel_id);
+    raw_log::add_secrets(config.api_keys());
+    let (prompt_sender, mut prompt_receiver) = match load_prompt(config.get_prompt_path()).await {
+        Ok(var) => var,
+        Err(err) => {
+            tracing::error!("Unable to read channel prompt: {err}");
+            tracing::error!(
+                "Channel with id '{}' will not be activated",
+                config.get_channel_id()
+            );
+            return;
+        }
+    };
+    health.prompt_loaded();
+
+    // Kept to reload the prompt when asked to, in case the file watcher doesn't work.
+    let prompt_reloader = prompt_sender.clone();
+    if let Err(err) = monitor_prompt(config.get_prompt_path(), prompt_sender) {
+        tracing::error!(
+            "Unable to watch prompt file at '{}' for channel '{}'. The channel will be active, but the prompt wont be updated unless the program is restarted.",
+            config.get_prompt_path().display(),
+            config.get_channel_id()
+        );
+        tracing::error!("{err}");
+    };
+
+    let mut blocklist_receiver = match &config.blocklist_path {
+        Some(path) => match load_file(path).await {
+            Ok((sender, receiver)) => {
+                if let Err(err) = monitor_file(path, sender) {
+                    tracing::error!(
+                        "Unable to watch blocklist file at '{}' for channel '{}'. The blocklist wont be updated unless the program is restarted.",
+                        path.display(),
+                        config.get_channel_id()
+                    );
+                    tracing::error!("{err}");
+                }
+                Some(receiver)
+            }
+            Err(err) => {
+                // Running without the blocklist could let through responses it is meant to stop.
+                tracing::error!("Unable to read blocklist: {err}");
+                tracing::error!(
+                    "Channel with id '{}' will not be activated",
+                    config.get_channel_id()
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+    let prompt_append_receiver = match &config.prompt_append_path {
+        Some(path) => match load_file(path).await {
+            Ok((sender, receiver)) => {
+                if let Err(err) = monitor_file(path, sender) {
+                    tracing::error!(
+                        "Unable to watch prompt append file at '{}' for channel '{}'. It wont be updated unless the program is restarted.",
+                        path.display(),
+                        config.get_channel_id()
+                    );
+                    tracing::error!("{err}");
+                }
+                Some(receiver)
+            }
+            Err(err) => {
+                tracing::error!("Unable to read prompt append: {err}");
+                tracing::error!(
+                    "Channel with id '{}' will not be activated",
+                    config.get_channel_id()
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut blocklist = match &mut blocklist_receiver {
+        Some(receiver) => Blocklist::parse(&receiver.borrow_and_update()),
+        None => Blocklist::default(),
+    };
+
+    let models = llm_models(&config, request_limit);
+    // The primary model's api is also used for summaries, moderation and embeddings.
+    let primary_model = &models[0];
+    let trimmer = history_trimmer(&config, primary_model);
+    let mut retrieval = if config.retrieval {
+        Some(Retrieval::load(&config, primary_model).await)
+    } else {
+        None
+    };
+    let allowed_mentions = config.allowed_mentions();
+
+    let prompt_variables = match PromptVariables::fetch(&http, config.channel_id).await {
+        Ok(variables) => variables,
+        Err(err) => {
+            warn!(
+                "Unable to look up the names used in the prompt of channel '{}': {err:?}",
+                config.channel_id
+            );
+            PromptVariables::default()
+        }
+    };
+
+    let tools = ToolRegistry::new(&config.tools);
+    let config = Arc::new(config);
+
+    let max_history_size = config.max_history_size as usize;
+    let (message_tx, mut message_rx) = mpsc::channel(max_history_size / 2);
+
+    let (status_tx, status_rx) = watch::channel(commands::Status::new());
+
+    let (cooldown_tx, cooldown_rx) = watch::channel(Instant::now());
+    let (webhook_tx, webhook_rx) = watch::channel(None);
+
+    // Spawn a task to handle incoming message events and queue them in the channel above.
+    tokio::spawn(
+        queue_messages(
+            events,
+            message_tx,
+            config.clone(),
+            http.clone(),
+            bot_id,
+            ServeState {
+                status: status_rx,
+                cooldown_until: cooldown_rx,
+                webhook_id: webhook_rx,
+            },
+        )
+        .in_current_span(),
+    );
+
+    let mut last_response_time = Instant::now();
+    // The tokens used by this channel since the bot started.
+    let mut total_usage = TokenUsage::default();
+    // The tokens attributed to each user since the bot started, see `cost_attribution`.
+    let mut user_usage: HashMap<Id<UserMarker>, TokenUsage> = HashMap::new();
+    let mut cooldown = Duration::from_millis(config.response_cooldown_ms);
+    let mut budget = DailyBudget::load(&config).await;
+    let mut webhook_poster = WebhookPoster::new(&config, webhook_tx);
+    // Looked up now, so feedback on responses from before a restart is recognized.
+    if let Some(poster) = &mut webhook_poster
+        && let Err(err) = poster.connect(&http).await
+    {
+        error!("Failed to find the webhook of the channel: {err:?}");
+    }
+    // The channel or thread and the id of the last error message sent.
+    let mut last_error_response = None;
+    // The history of the channel and each of its threads.
+    let mut histories: HashMap<Id<ChannelMarker>, VecDeque<HistoryEntry>> = HashMap::new();
+    // The time the last message added to each history was sent, for relative timestamps.
+    let mut last_sent_at: HashMap<Id<ChannelMarker>, Timestamp> = HashMap::new();
+    let mut image_cache = ImageCache::new(config.image_cache_mb * 1024 * 1024);
+    let download_http = build_download_client(&config);
+    // The last response sent in the channel and each of its threads.
+    let mut last_responses: HashMap<Id<ChannelMarker>, LastResponse> = HashMap::new();
+
+    // Start with the latest messages in the channel, so restarting doesn't lose the context of
+    // ongoing conversations.
+    if config.seed_history_from_channel > 0 && !config.stateless {
+        match fetch_history(&config, &http, bot_id, &download_http, &mut image_cache).await {
+            Ok(history) => {
+                let history = histories.entry(config.channel_id).or_insert(history);
+                let prompt = config.prompt_role.message(
+                    &prompt_variables.render(&prompt_receiver.borrow(), SystemTime::now()),
+                );
+                let removed = trimmer.trim(history, &prompt).await;
+                if let Some(retrieval) = &mut retrieval {
+                    retrieval.add(config.channel_id, &removed).await;
+                }
+                info!(
+                    "Started with {} messages from channel {}",
+                    history.len(),
+                    config.channel_id
+                );
+            }
+            Err(err) => error!("Failed to fetch the history of the channel: {err:?}"),
+        }
+    }
+
+    health.running();
+
+    // Batch new messages together to avoid generating a separate response to each one.
+    let mut new_events = Vec::new();
+    loop {
+        status_tx.send_replace(status(&config, &histories, &last_responses));
+
+        // Wait to avoid getting rate limited by the LLM endpoint.
+        select! {
+            _ = sleep_until(last_response_time + cooldown) => {},
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+        cooldown = Duration::from_millis(config.response_cooldown_ms);
+
+        let recv_amt = select! {
+            recv_amt = message_rx.recv_many(&mut new_events, max_history_size) => recv_amt,
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+
+        if recv_amt == 0 {
+            // The message ingestion channel has closed, gracefully shut down this task.
+            break;
+        }
+
+        // Give users a moment to send follow-up messages, so they can be responded to together.
+        if config.batch_window_ms > 0 {
+            sleep(Duration::from_millis(config.batch_window_ms)).await;
+            while new_events.len() < max_history_size {
+                let Ok(event) = message_rx.try_recv() else {
+                    break;
+                };
+                new_events.push(event);
+            }
+        }
+
+        if let Some(receiver) = &mut blocklist_receiver
+            && receiver.has_changed().unwrap_or(false)
+        {
+            blocklist = Blocklist::parse(&receiver.borrow_and_update());
+            debug!("Reloaded blocklist");
+        }
+
+        let mut new_messages: Vec<UserMessage> = Vec::new();
+        // The channels to regenerate the last response in, and the message it replied to.
+        let mut regenerate = HashMap::new();
+        // The channels to continue the truncated last response in.
+        let mut continue_requests = HashSet::new();
+        for event in new_events.drain(..) {
+            match event {
+                QueuedEvent::Message(msg)
+                    if config.blocklist_user_messages && blocklist.is_match(&msg.content) =>
+                {
+                    debug!("Ignoring message that matches the blocklist");
+                    msg.remove_reactions(&http, &config);
+                }
+                // The request to continue is left out of the history, so the continuation is
+                // part of the same assistant turn.
+                QueuedEvent::Message(msg)
+                    if msg.triggers_response
+                        && is_continue_message(&msg.content)
+                        && last_responses
+                            .get(&msg.channel_id)
+                            .is_some_and(|last| last.truncated) =>
+                {
+                    continue_requests.insert(msg.channel_id);
+                    msg.remove_reactions(&http, &config);
+                }
+                QueuedEvent::Message(msg) => new_messages.push(msg),
+                // Blocked content must not be edited into the history either.
+                QueuedEvent::Edit(edited)
+                    if config.blocklist_user_messages && blocklist.is_match(&edited.content) =>
+                {
+                    debug!("Removing message edited to match the blocklist");
+                    new_messages.retain(|msg| {
+                        let blocked = msg.message_id == edited.message_id;
+                        if blocked {
+                            msg.remove_reactions(&http, &config);
+                        }
+                        !blocked
+                    });
+                    if let Some(history) = histories.get_mut(&edited.channel_id) {
+                        history.retain(|entry| entry.id != Some(edited.message_id));
+                    }
+                }
+                QueuedEvent::Edit(edited) => {
+                    // The edited message may not have been added to the history yet.
+                    if let Some(msg) = new_messages
+                        .iter_mut()
+                        .find(|msg| msg.message_id == edited.message_id)
+                    {
+                        *msg = UserMessage {
+                            triggers_response: msg.triggers_response,
+                            cooldown_reacted: msg.cooldown_reacted,
+                            ..edited
+                        };
+                    } else if let Some(entry) =
+                        histories.get_mut(&edited.channel_id).and_then(|history| {
+                            history
+                                .iter_mut()
+                                .find(|entry| entry.id == Some(edited.message_id))
+                        })
+                    {
+                        debug!("Updating edited message in history");
+                        entry.msg = ChatCompletionRequestMessage::User(
+                            // The previous message isn't known here, so relative timestamps
+                            // fall back to the absolute time.
+                            edited
+                                .as_chat_completion_message(
+                                    &config,
+                                    &download_http,
+                                    &mut image_cache,
+                                    None,
+                                )
+                                .await,
+                        );
+                    }
+                }
+                QueuedEvent::Delete { channel_id, ids } => {
+                    new_messages.retain(|msg| !ids.contains(&msg.message_id));
+                    if let Some(history) = histories.get_mut(&channel_id) {
+                        let len = history.len();
+                        history.retain(|entry| entry.id.is_none_or(|id| !ids.contains(&id)));
+                        if history.len() != len {
+                            debug!(
+                                "Removed {} deleted messages from history",
+                                len - history.len()
+                            );
+                        }
+                    }
+                }
+                QueuedEvent::Clear {
+                    channel_id,
+                    reload_prompt,
+                } => {
+                    info!("Clearing the history of '{channel_id}'");
+                    new_messages.retain(|msg| {
+                        let cleared = msg.channel_id == channel_id;
+                        if cleared {
+                            msg.remove_reactions(&http, &config);
+                        }
+                        !cleared
+                    });
+                    histories.remove(&channel_id);
+                    if let Some(retrieval) = &mut retrieval {
+                        retrieval.clear(channel_id).await;
+                    }
+                    last_sent_at.remove(&channel_id);
+                    last_responses.remove(&channel_id);
+                    regenerate.remove(&channel_id);
+                    continue_requests.remove(&channel_id);
+
+                    if reload_prompt {
+                        match tokio::fs::read_to_string(config.get_prompt_path()).await {
+                            Ok(prompt) if prompt.trim().is_empty() => {
+                                warn!("Prompt file is empty, keeping the previous prompt");
+                            }
+                            Ok(prompt) => {
+                                prompt_reloader.send_replace(prompt.into_boxed_str());
+                            }
+                            Err(err) => error!("Unable to reload channel prompt: {err}"),
+                        }
+                    }
+                }
+                QueuedEvent::Regenerate {
+                    channel_id,
+                    message_id,
+                } => {
+                    // Only the last response can be regenerated, as later messages may depend on
+                    // older ones.
+                    let is_last_response = last_responses
+                        .get(&channel_id)
+                        .is_some_and(|last| last.message_ids.contains(&message_id));
+                    if !is_last_response {
+                        continue;
+                    }
+                    let Some(last) = last_responses.remove(&channel_id) else {
+                        continue;
+                    };
+
+                    debug!("Regenerating last response in '{channel_id}'");
+                    if let Some(history) = histories.get_mut(&channel_id) {
+                        history.retain(|entry| {
+                            entry.id.is_none_or(|id| !last.message_ids.contains(&id))
+                        });
+                    }
+                    for message_id in &last.message_ids {
+                        if let Err(err) = http.delete_message(channel_id, *message_id).await {
+                            error!("Failed to delete response to regenerate: {err}");
+                        }
+                    }
+                    regenerate.insert(channel_id, last.reply_to);
+                    continue_requests.remove(&channel_id);
+                }
+                QueuedEvent::Feedback(mut feedback) => {
+                    debug!("Received {} feedback on a response", feedback.emoji);
+                    metrics::record_feedback(config.channel_id, &feedback.emoji);
+                    let Some(path) = &config.feedback_log_path else {
+                        continue;
+                    };
+                    // Responses that were trimmed from the history are logged without content.
+                    feedback.response =
+                        histories
+                            .get(&feedback.channel_id)
+                            .and_then(|history| {
+                                history
+                                    .iter()
+                                    .find(|entry| entry.id == Some(feedback.message_id))
+                            })
+                            .and_then(|entry| match &entry.msg {
+                                ChatCompletionRequestMessage::Assistant(
+                                    ChatCompletionRequestAssistantMessage {
+                                        content:
+                                            Some(ChatCompletionRequestAssistantMessageContent::Text(
+                                                text,
+                                            )),
+                                        ..
+                                    },
+                                ) => Some(text.clone()),
+                                _ => None,
+                            });
+                    feedback.append_to(path);
+                }
+                QueuedEvent::Continue {
+                    channel_id,
+                    message_id,
+                } => {
+                    let is_truncated_response =
+                        last_responses.get(&channel_id).is_some_and(|last| {
+                            last.truncated && last.message_ids.contains(&message_id)
+                        });
+                    if is_truncated_response {
+                        continue_requests.insert(channel_id);
+                    }
+                }
+            }
+        }
+
+        // Responses generated under the old prompt can confuse the model, so start over.
+        if prompt_receiver.has_changed().unwrap_or(false) {
+            prompt_receiver.mark_unchanged();
+            if config.clear_history_on_prompt_change && !histories.is_empty() {
+                info!(
+                    "Prompt changed, clearing the history of channel '{}'",
+                    config.channel_id
+                );
+                histories.clear();
+                if let Some(retrieval) = &mut retrieval {
+                    retrieval.clear_all().await;
+                }
+                last_sent_at.clear();
+                last_responses.clear();
+                regenerate.clear();
+                continue_requests.clear();
+            }
+        }
+
+        let mut conversations = group_by_channel(new_messages);
+        for channel_id in regenerate.keys().chain(&continue_requests) {
+            if !conversations.iter().any(|(id, _)| id == channel_id) {
+                conversations.push((*channel_id, Vec::new()));
+            }
+        }
+
+        for (channel_id, batch) in conversations {
+            let history = histories.entry(channel_id).or_default();
+            let current_prompt = config
+                .prompt_role
+                .message(&prompt_variables.render(&prompt_receiver.borrow(), SystemTime::now()));
+
+            let regenerate_reply_to = regenerate.get(&channel_id).copied();
+            // New messages mean the conversation has moved on, so it is only continued without them.
+            let continuing = batch.is_empty() && continue_requests.contains(&channel_id);
+
+            // The latest message in the batch is the one the response is replying to.
+            let trigger_message = batch
+                .iter()
+                .rfind(|msg| msg.triggers_response)
+                .map(|msg| msg.message_id)
+                .or(regenerate_reply_to.flatten())
+                .filter(|_| config.reply_to_trigger);
+
+            // Messages sent by users on cooldown are only kept as context.
+            let should_respond = regenerate_reply_to.is_some()
+                || continuing
+                || batch.iter().any(|msg| msg.triggers_response);
+
+            // The previous batch is only kept until now, so its response can be regenerated or
+            // continued.
+            if config.stateless && !batch.is_empty() {
+                history.clear();
+            }
+
+            for msg in &batch {
+                // Messages sent while the scrollback was fetched can already be in the history.
+                if config.seed_history_from_channel > 0
+                    && history.iter().any(|entry| entry.id == Some(msg.message_id))
+                {
+                    continue;
+                }
+                let previous_sent_at = last_sent_at.insert(channel_id, msg.sent_at);
+                history.push_back(HistoryEntry {
+                    id: Some(msg.message_id),
+                    msg: ChatCompletionRequestMessage::User(
+                        msg.as_chat_completion_message(
+                            &config,
+                            &download_http,
+                            &mut image_cache,
+                            previous_sent_at,
+                        )
+                        .await,
+                    ),
+                });
+            }
+            metrics::record_messages(config.channel_id, batch.len());
+
+            if !config.stateless {
+                let removed = trimmer.trim(history, &current_prompt).await;
+                if let Some(retrieval) = &mut retrieval {
+                    retrieval.add(channel_id, &removed).await;
+                }
+            }
+
+            if !should_respond {
+                continue;
+            }
+
+            // Another thread may have just been responded to in the same batch.
+            select! {
+                _ = sleep_until(last_response_time + cooldown) => {},
+                // Don't start new responses while shutting down.
+                _ = shutdown_requested(&mut shutdown) => break,
+            }
+            cooldown = Duration::from_millis(config.response_cooldown_ms);
+
+            // The cooldown has passed, so the messages are no longer waiting for it.
+            let waited: Vec<_> = batch
+                .iter()
+                .filter(|msg| msg.cooldown_reacted)
+                .map(|msg| (msg.channel_id, msg.message_id))
+                .collect();
+            reactions::remove_reactions(&http, config.cooldown_emoji.clone(), waited);
+
+            let seen = SeenMessages {
+                channel_id,
+                message_ids: batch
+                    .iter()
+                    .filter(|msg| msg.triggers_response)
+                    .map(|msg| msg.message_id)
+                    .collect(),
+            };
+
+            // The messages are kept as context for when the budget resets.
+            if budget.is_exceeded(SystemTime::now()) {
+                debug!("Daily budget reached, not responding");
+                if budget.take_notice().await {
+                    warn!("Daily budget of channel '{}' reached", config.channel_id);
+                    _ = send_error_msg(&http, channel_id, DAILY_LIMIT_NOTICE).await;
+                }
+                seen.finish(&http, &config, false);
+                continue;
+            }
+
+            // The removed messages most relevant to the latest message, which don't change if the
+            // request is sent again.
+            let recalled = match &retrieval {
+                Some(retrieval) => match latest_query(history.iter().map(|entry| &entry.msg)) {
+                    Some(query) => retrieval.recall(channel_id, &query).await,
+                    None => None,
+                },
+                None => None,
+            };
+
+            let build_messages = |history: &VecDeque<HistoryEntry>| {
+                let mut messages = vec![current_prompt.clone()];
+                if let Some(append) = prompt_append(&config, prompt_append_receiver.as_ref()) {
+                    let append = prompt_variables.render(&append, SystemTime::now());
+                    messages.push(config.prompt_role.message(&append));
+                }
+                messages.extend(recalled.clone());
+                let leading = messages.len();
+
+                messages.extend(history.iter().map(|entry| entry.msg.clone()));
+                let history_end = messages.len();
+                if continuing {
+                    messages.push(config.prompt_role.message(CONTINUE_INSTRUCTION));
+                }
+                messages.extend(config.language_instruction());
+
+                // A summary may have been added after trimming the history, and the other messages
+                // count towards the budget too.
+                let trailing = messages.len() - history_end;
+                trimmer.cap(&mut messages, leading, trailing);
+                messages
+            };
+            let mut messages = build_messages(history);
+
+            // Post the placeholder straight away, the response replaces it once it is generated.
+            let placeholder = match &config.placeholder_message {
+                Some(placeholder) if !config.streaming => {
+                    let mut create_message = http
+                        .create_message(channel_id)
+                        .allowed_mentions(Some(&allowed_mentions))
+                        .content(placeholder);
+                    if let Some(message_id) = trigger_message {
+                        create_message = create_message.reply(message_id).fail_if_not_exists(false);
+                    }
+                    match create_message.await {
+                        Ok(response) => response.model().await.ok().map(|msg| msg.id),
+                        Err(err) => {
+                            error!("Failed to send placeholder message: {err}");
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            // The message the response has already been streamed into, if any. The placeholder is
+            // treated like a streamed message, so it is replaced by the response.
+            let mut streamed_message = placeholder;
+            let echoed_messages = config.debug_echo.then(|| messages.clone());
+            let generation_start = Instant::now();
+            let generation_span = info_span!(
+                "generate_response",
+                conversation_id = %channel_id,
+                latency_ms = field::Empty
+            );
+            let mut retried_context_length = false;
+            let generating = presence.generating();
+            let response = loop {
+                let response = if config.streaming {
+                    let streamed = match build_request(&config, messages.clone()) {
+                        Ok(request) => {
+                            with_typing_indicator(
+                                config.show_typing,
+                                &http,
+                                channel_id,
+                                stream_with_fallbacks(
+                                    &models,
+                                    &config,
+                                    request,
+                                    &http,
+                                    channel_id,
+                                    trigger_message,
+                                ),
+                            )
+                            .instrument(generation_span.clone())
+                            .await
+                        }
+                        Err(err) => stream::StreamedResponse {
+                            content: String::new(),
+                            message_id: None,
+                            usage: None,
+                            truncated: false,
+                            error: Some(err),
+                        },
+                    };
+                    streamed_message = streamed.message_id;
+
+                    match streamed.error {
+                        Some(err) => {
+                            // Keep whatever has already been posted in the history, so the model knows
+                            // what the users have seen.
+                            if streamed_message.is_some() {
+                                history.push_back(HistoryEntry {
+                                    id: streamed_message,
+                                    msg: config.assistant_message(&streamed.content),
+                                });
+                            }
+                            Err(err)
+                        }
+                        None => Ok(GeneratedResponse {
+                            content: streamed.content,
+                            usage: streamed.usage,
+                            truncated: streamed.truncated,
+                        }),
+                    }
+                } else {
+                    with_typing_indicator(
+                        config.show_typing,
+                        &http,
+                        channel_id,
+                        generate_in_format(&models, &config, &tools, messages.clone()),
+                    )
+                    .instrument(generation_span.clone())
+                    .await
+                };
+
+                // A history that doesn't fit in the context window of the model would make every
+                // following response fail too, so the oldest half of it is dropped.
+                let nothing_posted = !config.streaming || streamed_message.is_none();
+                match &response {
+                    Err(err)
+                        if !retried_context_length
+                            && nothing_posted
+                            && history.len() > 1
+                            && context_length::is_exceeded(err) =>
+                    {
+                        let removed = history.len() / 2;
+                        let removed_messages: Vec<_> =
+                            history.drain(..removed).map(|entry| entry.msg).collect();
+                        if let Some(retrieval) = &mut retrieval {
+                            retrieval.add(channel_id, &removed_messages).await;
+                        }
+                        warn!(
+                            "The history doesn't fit in the context window of the model, retrying without the oldest {removed} messages"
+                        );
+                        messages = build_messages(history);
+                        retried_context_length = true;
+                    }
+                    _ => break response,
+                }
+            };
+            drop(generating);
+            last_response_time = Instant::now();
+            // Messages sent from now on wait for the cooldown.
+            cooldown_tx.send_replace(last_response_time + cooldown);
+            generation_span.record(
+                "latency_ms",
+                (last_response_time - generation_start).as_millis() as u64,
+            );
+            generation_span.in_scope(|| debug!("Finished generating the response"));
+            metrics::record_response(
+                config.channel_id,
+                last_response_time - generation_start,
+                response.is_ok(),
+            );
+
+            let usage = response.as_ref().ok().and_then(|response| response.usage);
+            budget.record(SystemTime::now(), usage).await;
+            if let Some(usage) = &usage {
+                metrics::record_tokens(
+                    config.channel_id,
+                    usage.prompt_tokens,
+                    usage.completion_tokens,
+                );
+                total_usage += *usage;
+                log_usage(&config, usage, &total_usage);
+
+                let triggers: Vec<_> = batch
+                    .iter()
+                    .filter(|msg| msg.triggers_response)
+                    .map(|msg| (msg.sender_id, msg.sender_name.as_str()))
+                    .collect();
+                for (user_id, user_name, share) in
+                    usage::attribute(config.cost_attribution, usage, &triggers)
+                {
+                    let user_total = user_usage.entry(user_id).or_default();
+                    *user_total += share;
+                    log_user_usage(&config, channel_id, user_id, user_name, &share, user_total);
+                }
+            }
+
+            // Delete the previous error message. This should happen both if there is a new error
+            // message or there is another error.
+            if let Some((prev_err_channel_id, prev_err_msg_id)) = last_error_response {
+                let http2 = http.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = http2
+                        .delete_message(prev_err_channel_id, prev_err_msg_id)
+                        .await
+                    {
+                        error!("Failed to delete previous error message: {err}");
+                    }
+                });
+
+                last_error_response = None;
+            }
+
+            let (response_content, truncated) = match response {
+                Ok(v) => (v.content, v.truncated),
+                Err(err) => {
+                    error!("Error creating response: {err:?}");
+                    if let Some(path) = &config.error_log_path {
+                        FailedGeneration::new(
+                            channel_id,
+                            models.iter().map(|model| model.name.clone()).collect(),
+                            &err,
+                            batch.last().map(|msg| msg.content.clone()),
+                        )
+                        .append_to(path);
+                    }
+
+                    // Respect the rate limit for the next response.
+                    if let Some(rate_limited) = err.downcast_ref::<RateLimited>() {
+                        cooldown = cooldown.max(rate_limited.retry_after);
+                        cooldown_tx.send_replace(last_response_time + cooldown);
+                    }
+
+                    // Log the error in the channel, in place of the placeholder if there is one. A
+                    // filtered response isn't a failure of the bot, so no error is shown for it.
+                    let err_text = if err.downcast_ref::<ContentFiltered>().is_some() {
+                        "That response was filtered by the LLM provider.".to_string()
+                    } else {
+                        format!("Something went wrong while generating a response\n```\n{err}\n```")
+                    };
+                    let err_msg = match placeholder {
+                        Some(message_id) => {
+                            edit_to_error_msg(&http, channel_id, message_id, &err_text).await
+                        }
+                        None => send_error_msg(&http, channel_id, &err_text).await,
+                    };
+
+                    if let Some(err_msg) = err_msg {
+                        last_error_response = Some((channel_id, err_msg.id));
+                    };
+                    seen.finish(&http, &config, false);
+                    continue;
+                }
+            };
+
+            let mentioned = batch
+                .iter()
+                .any(|msg| msg.triggers_response && msg.mentions_bot);
+            let response_content = match config.forced_reply(&response_content, mentioned) {
+                Some(fallback) => {
+                    debug!("Model chose to not respond to a mention, sending the fallback");
+                    fallback.to_string()
+                }
+                None => response_content,
+            };
+
+            if config.is_no_response(&response_content) {
+                debug!("Model chose to not respond");
+                if let Some(message_id) = streamed_message {
+                    _ = http.delete_message(channel_id, message_id).await;
+                }
+                match config.no_response_action {
+                    NoResponseAction::KeepMessages => {}
+                    NoResponseAction::AddMarker => history
+                        .push_back(config.assistant_message(&config.no_response_marker).into()),
+                    NoResponseAction::DropMessages => {
+                        history.retain(|entry| {
+                            entry
+                                .id
+                                .is_none_or(|id| !batch.iter().any(|msg| msg.message_id == id))
+                        });
+                    }
+                }
+                seen.finish(&http, &config, false);
+                continue;
+            }
+
+            if response_content.trim().is_empty() {
+                warn!("Response was blank, not sending it");
+                if let Some(message_id) = streamed_message {
+                    _ = http.delete_message(channel_id, message_id).await;
+                }
+                seen.finish(&http, &config, false);
+                continue;
+            }
+
+            // Flagged responses are replaced by the refusal, and are kept out of the history so
+            // the model doesn't build on them.
+            let refused = if config.moderation {
+                match moderation::is_flagged(
+                    primary_model,
+                    &response_content,
+                    config.request_timeout(),
+                )
+                .await
+                {
+                    Ok(flagged) => {
+                        if flagged {
+                            warn!("Response was flagged by moderation: {response_content}");
+                        }
+                        flagged
+                    }
+                    Err(err) => {
+                        error!("Failed to moderate response: {err:?}");
+                        config.moderation_fail_closed
+                    }
+                }
+            } else {
+                false
+            };
+            let response_content = if refused {
+                config.moderation_refusal.clone()
+            } else {
+                response_content
+            };
+
+            let response_content = if blocklist.is_match(&response_content) {
+                match config.blocklist_action {
+                    BlocklistAction::Suppress => {
+                        warn!("Response matched the blocklist, not sending it: {response_content}");
+                        if let Some(message_id) = streamed_message {
+                            _ = http.delete_message(channel_id, message_id).await;
+                        }
+                        seen.finish(&http, &config, false);
+                        continue;
+                    }
+                    BlocklistAction::Mask => {
+                        warn!("Response matched the blocklist, masking it: {response_content}");
+                        blocklist.mask(&response_content)
+                    }
+                }
+            } else {
+                response_content
+            };
+
+            // Structured responses are sent as a single embed, other responses are split to stay
+            // within the discord character limit.
+            // Truncated responses are sent as text, so the note saying so can be added.
+            let embed = (config.use_embeds && !truncated)
+                .then(|| embed::structured_embed(&response_content))
+                .flatten();
+            let note_chars = if truncated {
+                TRUNCATION_NOTE.chars().count()
+            } else {
+                0
+            };
+            let mut chunks = match embed {
+                Some(_) => vec![response_content.clone()],
+                None => split_message(
+                    &response_content,
+                    config.max_message_chars().saturating_sub(note_chars).max(1),
+                ),
+            };
+            if chunks.len() > config.max_messages_per_response {
+                warn!(
+                    "Response was split into {} messages, only sending the first {}",
+                    chunks.len(),
+                    config.max_messages_per_response
+                );
+                chunks.truncate(config.max_messages_per_response);
+            }
+
+            if chunks.is_empty() {
+                if let Some(message_id) = streamed_message {
+                    _ = http.delete_message(channel_id, message_id).await;
+                }
+                seen.finish(&http, &config, false);
+                continue;
+            }
+
+            if truncated {
+                warn!("Response was cut off by the maximum amount of response tokens");
+                if let Some(last) = chunks.last_mut() {
+                    last.push_str(TRUNCATION_NOTE);
+                }
+            }
+
+            let mut reply_to = trigger_message;
+            let mut sent_ids = Vec::new();
+            for chunk in &chunks {
+                // The first chunk replaces the content of the streamed message.
+                let content = embed.is_none().then_some(chunk.as_str());
+                let res = match (streamed_message.take(), &mut webhook_poster) {
+                    (Some(message_id), _) => http
+                        .update_message(channel_id, message_id)
+                        .allowed_mentions(Some(&allowed_mentions))
+                        .content(content)
+                        .embeds(Some(embed.as_slice()))
+                        .await
+                        .map(|_| Some(message_id))
+                        .map_err(anyhow::Error::from),
+                    (None, Some(poster)) => poster
+                        .send(
+                            &http,
+                            channel_id,
+                            content,
+                            embed.as_slice(),
+                            &allowed_mentions,
+                        )
+                        .await
+                        .map(Some),
+                    (None, None) => {
+                        let mut create_message = http
+                            .create_message(channel_id)
+                            .allowed_mentions(Some(&allowed_mentions))
+                            .embeds(embed.as_slice());
+                        if let Some(content) = content {
+                            create_message = create_message.content(content);
+                        }
+                        // Only the first message of the response is sent as a reply.
+                        if let Some(message_id) = reply_to.take() {
+                            create_message =
+                                create_message.reply(message_id).fail_if_not_exists(false);
+                        }
+                        match create_message.await {
+                            Ok(response) => Ok(response.model().await.ok().map(|msg| msg.id)),
+                            Err(err) => Err(err.into()),
+                        }
+                    }
+                };
+                reply_to = None;
+
+                // Each sent message is its own history entry, so it can be removed if a
+                // moderator deletes it.
+                match res {
+                    Ok(id) => {
+                        sent_ids.extend(id);
+                        if !refused {
+                            // The note isn't part of what the model said.
+                            let chunk = chunk.strip_suffix(TRUNCATION_NOTE).unwrap_or(chunk);
+                            history.push_back(HistoryEntry {
+                                id,
+                                msg: config.assistant_message(chunk),
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        error!("Failed to send response message: {err:#}");
+                        break;
+                    }
+                }
+            }
+
+            seen.finish(&http, &config, !sent_ids.is_empty());
+            if let Some(messages) = echoed_messages {
+                debug_echo::echo_messages(&http, &config, channel_id, &messages);
+            }
+            // A continuation is part of the response it continues, so both are regenerated together.
+            let continued = last_responses.remove(&channel_id).filter(|_| continuing);
+            let (mut message_ids, reply_to) = continued
+                .map(|last| (last.message_ids, last.reply_to))
+                .unwrap_or((Vec::new(), trigger_message));
+            message_ids.extend(sent_ids);
+            last_responses.insert(
+                channel_id,
+                LastResponse {
+                    message_ids,
+                    reply_to,
+                    sent_at: Instant::now(),
+                    truncated,
+                },
+            );
+        }
+    }
+
+    // Don't clutter the channel with lots of error messages.
+    if let Some((channel_id, msg_id)) = last_error_response {
+        _ = http.delete_message(channel_id, msg_id).await;
+    }
+}
+
+/// Fetch the latest messages in the channel to start the history with.
+async fn fetch_history(
+    config: &Configuration,
+    http: &Client,
+    bot_id: Id<UserMarker>,
+    download_http: &reqwest::Client,
+    image_cache: &mut ImageCache,
+) -> anyhow::Result<VecDeque<HistoryEntry>> {
+    let scrollback = user_message::fetch_scrollback(
+        http,
+        config,
+        bot_id,
+        download_http,
+        image_cache,
+        config.seed_history_from_channel.into(),
+    )
+    .await?;
+
+    Ok(scrollback
+        .into_iter()
+        .map(|(id, msg)| HistoryEntry { id: Some(id), msg })
+        .collect())
+}
+
+/// The text sent after the main prompt, from the prompt append file followed by the environment
+/// variable. Returns `None` if neither is set or both are empty.
+fn prompt_append(
+    config: &Configuration,
+    file: Option<&watch::Receiver<Box<str>>>,
+) -> Option<String> {
+    let file = file.map(|receiver| receiver.borrow().trim().to_string());
+    let env = config
+        .prompt_append_env
+        .as_ref()
+        .and_then(|name| std::env::var(name).ok())
+        .map(|value| value.trim().to_string());
+
+    let append = [file, env]
+        .into_iter()
+        .flatten()
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!append.is_empty()).then_some(append)
+}
+
+/// Wait until the bot is shutting down, which is also assumed if the sender is gone.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    _ = shutdown.wait_for(|&shutdown| shutdown).await;
+}
+
+/// The messages of a response, so it can be regenerated.
+struct LastResponse {
+    message_ids: Vec<Id<MessageMarker>>,
+    /// The message the response replied to.
+    reply_to: Option<Id<MessageMarker>>,
+    sent_at: Instant,
+    /// If the response was cut off, so it can be continued.
+    truncated: bool,
+}
+
+/// Check if the message asks for the truncated last response to be continued.
+fn is_continue_message(content: &str) -> bool {
+    content
+        .trim()
+        .trim_end_matches(['.', '!'])
+        .eq_ignore_ascii_case("continue")
+}
+
+/// Select how the history is trimmed from the configuration.
+fn history_trimmer(config: &Configuration, model: &LlmModel) -> Box<dyn HistoryTrimmer> {
+    let trimmer: Box<dyn HistoryTrimmer> = match config.tokenizer {
+        Some(tokenizer) => Box::new(TokenBudget {
+            tokenizer,
+            max_tokens: config.max_context_tokens as usize,
+        }),
+        None => Box::new(MessageCount {
+            max: config.max_history_size as usize,
+            min: config.min_history_size as usize,
+        }),
+    };
+
+    if !config.summarize_on_truncate {
+        return trimmer;
+    }
+    Box::new(Summarizing {
+        inner: trimmer,
+        client: model.client.clone(),
+        request_limit: model.request_limit.clone(),
+        model_name: config
+            .summary_model
+            .clone()
+            .unwrap_or_else(|| config.model_name.clone()),
+        timeout: config.request_timeout(),
+    })
+}
+
+/// A message in the history of a channel.
+struct HistoryEntry {
+    /// The discord message the entry was created from, if there is one.
+    id: Option<Id<MessageMarker>>,
+    msg: ChatCompletionRequestMessage,
+}
+
+impl From<ChatCompletionRequestMessage> for HistoryEntry {
+    fn from(msg: ChatCompletionRequestMessage) -> Self {
+        Self { id: None, msg }
+    }
+}
+
+/// Take a snapshot of the state of each conversation.
+fn status(
+    config: &Configuration,
+    histories: &HashMap<Id<ChannelMarker>, VecDeque<HistoryEntry>>,
+    last_responses: &HashMap<Id<ChannelMarker>, LastResponse>,
+) -> commands::Status {
+    let tokenizer = config.tokenizer.unwrap_or(Tokenizer::CharEstimate);
+    histories
+        .iter()
+        .map(|(channel_id, history)| {
+            let status = commands::ConversationStatus {
+                history_len: history.len(),
+                history_tokens: tokenizer
+                    .history_token_count(history.iter().map(|entry| &entry.msg)),
+                last_response: last_responses.get(channel_id).map(|last| last.sent_at),
+            };
+            (*channel_id, status)
+        })
+        .collect()
+}
+
+/// Group the messages by the channel or thread they were sent in, keeping them in order.
+fn group_by_channel(
+    messages: impl IntoIterator<Item = UserMessage>,
+) -> Vec<(Id<ChannelMarker>, Vec<UserMessage>)> {
+    let mut groups: Vec<(Id<ChannelMarker>, Vec<UserMessage>)> = Vec::new();
+    for msg in messages {
+        match groups.iter_mut().find(|(id, _)| *id == msg.channel_id) {
+            Some((_, group)) => group.push(msg),
+            None => groups.push((msg.channel_id, vec![msg])),
+        }
+    }
+    groups
+}
+
+/// Show the typing indicator in the channel while the future is running, if enabled.
+async fn with_typing_indicator<F: Future>(
+    enabled: bool,
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+    future: F,
+) -> F::Output {
+    if !enabled {
+        return future.await;
+    }
+
+    let typing = async {
+        loop {
+            if let Err(err) = http.create_typing_trigger(channel_id).await {
+                debug!("Failed to trigger typing indicator: {err}");
+            }
+            // The typing indicator lasts for ~10 seconds, so re-trigger it a bit before then.
+            sleep(TYPING_INTERVAL).await;
+        }
+    };
+
+    select! {
+        output = future => output,
+        _ = typing => unreachable!("typing indicator loop never returns"),
+    }
+}
+
+/// Log the tokens used by a response, and by the channel in total.
+fn log_usage(config: &Configuration, usage: &TokenUsage, total_usage: &TokenUsage) {
+    let cost = match config.prices() {
+        None => String::new(),
+        Some((prompt, completion)) => format!(
+            " (~${:.4}, ~${:.4} in total)",
+            usage.cost(prompt, completion),
+            total_usage.cost(prompt, completion)
+        ),
+    };
+
+    info!(
+        "Response used {} prompt and {} completion tokens, {} tokens used in total{cost}",
+        usage.prompt_tokens, usage.completion_tokens, total_usage.total_tokens
+    );
+}
+
+/// Log the tokens of a response attributed to a user, and to the user in total, also writing them
+/// to `usage_log_path` if it is set.
+fn log_user_usage(
+    config: &Configuration,
+    channel_id: Id<ChannelMarker>,
+    user_id: Id<UserMarker>,
+    user_name: &str,
+    usage: &TokenUsage,
+    user_total: &TokenUsage,
+) {
+    let prices = config.prices();
+    let cost = prices.map(|(prompt, completion)| usage.cost(prompt, completion));
+    let user_total_cost = prices.map(|(prompt, completion)| user_total.cost(prompt, completion));
+    info!(
+        "Attributed {} tokens to '{user_name}' ({user_id}), {} tokens attributed to them in total{}",
+        usage.total_tokens,
+        user_total.total_tokens,
+        user_total_cost.map_or(String::new(), |cost| format!(" (~${cost:.4})"))
+    );
+
+    if let Some(path) = &config.usage_log_path {
+        UserUsage {
+            timestamp: error_log::now_iso_8601(),
+            channel_id,
+            user_id,
+            user_name: user_name.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost,
+            user_total_tokens: user_total.total_tokens,
+            user_total_cost,
+        }
+        .append_to(path);
+    }
+}
+
+/// Sent by the model in response to a chat history.
+///
+/// A custom type is used here as some (gemini *caugh caugh*) APIs dont return all fields.
+#[derive(Debug, Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<ResponseChoice>,
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseChoice {
+    message: ResponseMessage,
+    /// Why the model stopped generating, e.g. `stop`, `length` or `content_filter`.
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    #[serde(default, deserialize_with = "deserialize_content")]
+    content: Option<String>,
+    tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
+    /// The thinking of reasoning models, which APIs return under different names. These are not
+    /// always strings, so anything else is ignored.
+    reasoning_content: Option<serde_json::Value>,
+    reasoning: Option<serde_json::Value>,
+}
+
+/// The content of a response, which some APIs return as an array of parts.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ResponseContent {
+    Text(String),
+    Parts(Vec<ResponseContentPart>),
+}
+
+#[derive(Deserialize)]
+struct ResponseContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+/// Deserialize the content of a response, joining the text parts if it is an array. Parts that
+/// aren't text are ignored.
+fn deserialize_content<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(
+        Option::<ResponseContent>::deserialize(deserializer)?.map(|content| match content {
+            ResponseContent::Text(text) => text,
+            ResponseContent::Parts(parts) => parts
+                .into_iter()
+                .filter(|part| part.kind == "text")
+                .filter_map(|part| part.text)
+                .collect(),
+        }),
+    )
+}
+
+impl ResponseMessage {
+    fn reasoning(&self) -> Option<&str> {
+        [&self.reasoning_content, &self.reasoning]
+            .into_iter()
+            .find_map(|reasoning| reasoning.as_ref()?.as_str())
+    }
+}
+
+/// The LLM api blocked the response with its content filter.
+#[derive(Debug)]
+struct ContentFiltered;
+
+impl std::fmt::Display for ContentFiltered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the response was blocked by the content filter of the LLM api"
+        )
+    }
+}
+
+impl std::error::Error for ContentFiltered {}
+
+/// What the LLM chose to do in a response.
+#[derive(Debug)]
+enum ResponseAction {
+    /// Call tools, after which the LLM is asked to respond again.
+    CallTools {
+        content: Option<String>,
+        tool_calls: Vec<ChatCompletionMessageToolCall>,
+    },
+    /// Respond with the content, which has had any reasoning removed.
+    Respond {
+        content: String,
+        /// If the response was cut off by the maximum amount of response tokens.
+        truncated: bool,
+    },
+}
+
+/// Get what the LLM chose to do from the first choice of the response.
+fn parse_response(response: ChatCompletionResponse) -> anyhow::Result<ResponseAction> {
+    // Some providers leave out the choices entirely when they are blocked by a content filter.
+    let Some(ResponseChoice {
+        message,
+        finish_reason,
+    }) = response.choices.into_iter().next()
+    else {
+        anyhow::bail!("LLM response did not include any choices, it may have been filtered");
+    };
+    if finish_reason.as_deref() == Some("content_filter") {
+        return Err(ContentFiltered.into());
+    }
+    // The reasoning is only useful for troubleshooting, it isn't posted or kept in the history.
+    if let Some(reasoning) = message.reasoning() {
+        debug!("Model reasoning: {reasoning}");
+    }
+
+    match message {
+        ResponseMessage {
+            tool_calls: Some(tool_calls),
+            content,
+            ..
+        } if !tool_calls.is_empty() => Ok(ResponseAction::CallTools {
+            content,
+            tool_calls,
+        }),
+        ResponseMessage {
+            content: Some(content),
+            ..
+        } => {
+            let (reasoning, answer) = reasoning::split_inline_reasoning(&content);
+            if let Some(reasoning) = reasoning {
+                debug!("Model reasoning: {reasoning}");
+            }
+            Ok(ResponseAction::Respond {
+                content: answer.to_string(),
+                truncated: finish_reason.as_deref() == Some("length"),
+            })
+        }
+        _ => anyhow::bail!("LLM response did not include message content"),
+    }
+}
+
+/// A response generated by the LLM.
+struct GeneratedResponse {
+    content: String,
+    /// The tokens used to generate the response, if the api reported them.
+    usage: Option<TokenUsage>,
+    /// If the response was cut off by the maximum amount of response tokens.
+    truncated: bool,
+}
+
+impl GeneratedResponse {
+    /// Add the usage of an earlier attempt at generating the response.
+    fn with_earlier_usage(mut self, usage: Option<TokenUsage>) -> Self {
+        if let Some(usage) = usage {
+            *self.usage.get_or_insert_default() += usage;
+        }
+        self
+    }
+}
+
+/// Build the request used to generate a response to the chat history.
+fn build_request(
+    config: &Configuration,
+    mut history: Vec<ChatCompletionRequestMessage>,
+) -> anyhow::Result<CreateChatCompletionRequest> {
+    if let Some(max_images) = config.max_images_per_request {
+        user_message::omit_old_images(&mut history, max_images);
+    }
+
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(&config.model_name).messages(history);
+    if let Some(max_response_tokens) = config.max_response_tokens {
+        args.max_tokens(max_response_tokens);
+    }
+
+    let mut request = args.build().context("Failed to build request")?;
+
+    // Only send the sampling parameters that are set, as some APIs reject parameters they don't
+    // support.
+    request.temperature = config.temperature;
+    request.top_p = config.top_p;
+    request.frequency_penalty = config.frequency_penalty;
+    request.presence_penalty = config.presence_penalty;
+    request.seed = config.seed;
+    request.response_format = config.response_format.api_format();
+    if !config.stop.is_empty() {
+        request.stop = Some(Stop::StringArray(config.stop.clone()));
+    }
+
+    Ok(request)
+}
+
+/// Serialize the request into the body sent to the LLM api, adding the parts that aren't
+/// supported by [`CreateChatCompletionRequest`].
+fn request_body(
+    config: &Configuration,
+    request: &CreateChatCompletionRequest,
+) -> anyhow::Result<serde_json::Value> {
+    let mut body = serde_json::to_value(request).context("Failed to serialize request")?;
+    if let Some(cache_control) = &config.extra_cache_control {
+        cache_control::add_cache_control(&mut body, cache_control);
+    }
+    if let Some(body) = body.as_object_mut() {
+        body.extend(config.extra_params.clone());
+    }
+    Ok(body)
+}
+
+/// Send the request to the LLM api.
+///
+/// If the api responds that the rate limit was reached, the request is retried once after the
+/// delay the api asked for, as long as that delay is short enough.
+async fn create_chat_completion(
+    client: &AIClient<OpenAIConfig>,
+    request: impl Serialize + Clone,
+    timeout: Duration,
+) -> anyhow::Result<ChatCompletionResponse> {
+    raw_log::log_request(CHAT_ENDPOINT, &request);
+    let response = tokio::time::timeout(
+        timeout,
+        client
+            .chat()
+            .create_byot::<_, serde_json::Value>(request.clone()),
+    )
+    .await
+    .map_err(|_| timed_out(timeout))?;
+    let err = match response {
+        Ok(response) => return raw_log::parse(CHAT_ENDPOINT, response),
+        Err(err) => err,
+    };
+
+    let rate_limited = match RateLimited::from_error(err) {
+        Ok(rate_limited) if rate_limited.retry_after <= MAX_RATE_LIMIT_WAIT => rate_limited,
+        Ok(rate_limited) => return Err(rate_limited.into()),
+        Err(err) => return Err(anyhow::Error::new(err).context("LLM api returned an error")),
+    };
+
+    warn!(
+        "Rate limited by the LLM api, retrying in {:.1}s",
+        rate_limited.retry_after.as_secs_f32()
+    );
+    sleep(rate_limited.retry_after).await;
+
+    raw_log::log_request(CHAT_ENDPOINT, &request);
+    let response = tokio::time::timeout(
+        timeout,
+        client.chat().create_byot::<_, serde_json::Value>(request),
+    )
+    .await
+    .map_err(|_| timed_out(timeout))?;
+    match response {
+        Ok(response) => raw_log::parse(CHAT_ENDPOINT, response),
+        Err(err) => match RateLimited::from_error(err) {
+            Ok(rate_limited) => Err(rate_limited.into()),
+            Err(err) => Err(anyhow::Error::new(err).context("LLM api returned an error")),
+        },
+    }
+}
+
+/// Generate a response with the first model that succeeds, trying the fallback models in order.
+async fn generate_with_fallbacks(
+    models: &[LlmModel],
+    config: &Configuration,
+    tools: &ToolRegistry,
+    history: Vec<ChatCompletionRequestMessage>,
+    temperature: Option<f32>,
+) -> anyhow::Result<GeneratedResponse> {
+    let mut last_err = None;
+    for (index, model) in models.iter().enumerate() {
+        match generate_response(model, config, tools, history.clone(), temperature).await {
+            Ok(response) => {
+                if index == 0 {
+                    debug!("Response generated by '{}'", model.name);
+                } else {
+                    info!("Response generated by fallback model '{}'", model.name);
+                }
+                return Ok(response);
+            }
+            Err(err) => {
+                if let Some(next) = models.get(index + 1) {
+                    warn!(
+                        "Model '{}' failed, falling back to '{}': {err:#}",
+                        model.name, next.name
+                    );
+                }
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No LLM models are configured")))
+}
+
+/// Generate a response, generating it again once if it is blank or isn't in the configured format.
+///
+/// A response that is still blank is returned as it is, so it can be skipped.
+async fn generate_in_format(
+    models: &[LlmModel],
+    config: &Configuration,
+    tools: &ToolRegistry,
+    history: Vec<ChatCompletionRequestMessage>,
+) -> anyhow::Result<GeneratedResponse> {
+    let mut response =
+        generate_with_fallbacks(models, config, tools, history.clone(), None).await?;
+    if response.content.trim().is_empty() {
+        let temperature = (config.temperature.unwrap_or(DEFAULT_TEMPERATURE)
+            + BLANK_RETRY_TEMPERATURE_INCREASE)
+            .min(MAX_TEMPERATURE);
+        warn!("Response was blank, retrying with a temperature of {temperature}");
+        let retried =
+            generate_with_fallbacks(models, config, tools, history.clone(), Some(temperature))
+                .await?;
+        response = retried.with_earlier_usage(response.usage);
+    }
+    if response.content.trim().is_empty() || config.response_format.is_valid(&response.content) {
+        return Ok(response);
+    }
+
+    warn!(
+        "Response is not in the {:?} format, retrying",
+        config.response_format
+    );
+    let retried = generate_with_fallbacks(models, config, tools, history, None)
+        .await?
+        .with_earlier_usage(response.usage);
+    if !config.response_format.is_valid(&retried.content) {
+        anyhow::bail!(
+            "LLM did not respond in the {:?} format after retrying",
+            config.response_format
+        );
+    }
+    Ok(retried)
+}
+
+/// Stream the response from the first model that succeeds, trying the fallback models in order.
+///
+/// Once part of a response has been posted, the other models are no longer tried.
+async fn stream_with_fallbacks(
+    models: &[LlmModel],
+    config: &Configuration,
+    request: CreateChatCompletionRequest,
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+    reply_to: Option<Id<MessageMarker>>,
+) -> stream::StreamedResponse {
+    let mut streamed = stream::StreamedResponse {
+        content: String::new(),
+        message_id: None,
+        usage: None,
+        truncated: false,
+        error: Some(anyhow::anyhow!("No LLM models are configured")),
+    };
+
+    for (index, model) in models.iter().enumerate() {
+        let mut request = request.clone();
+        request.model = model.name.clone();
+        let _permit = model.acquire_permit().await;
+        streamed = stream_response(
+            &model.client,
+            request,
+            http,
+            channel_id,
+            reply_to,
+            config,
+            config.max_message_chars(),
+        )
+        .await;
+
+        match (&streamed.error, models.get(index + 1)) {
+            (Some(err), Some(next)) if streamed.message_id.is_none() => warn!(
+                "Model '{}' failed, falling back to '{}': {err:#}",
+                model.name, next.name
+            ),
+            _ => {
+                if streamed.error.is_none() && index > 0 {
+                    info!("Response generated by fallback model '{}'", model.name);
+                }
+                break;
+            }
+        }
+    }
+
+    streamed
+}
+
+/// The error used when the LLM api takes longer than the timeout to respond.
+fn timed_out(timeout: Duration) -> anyhow::Error {
+    anyhow::anyhow!("LLM api did not respond within {}s", timeout.as_secs())
+}
+
+/// Send the chat history to the LLM api and generate a response based on this history.
+///
+/// If `temperature` is set, it is used instead of the configured temperature.
+async fn generate_response(
+    model: &LlmModel,
+    config: &Configuration,
+    tools: &ToolRegistry,
+    history: Vec<ChatCompletionRequestMessage>,
+    temperature: Option<f32>,
+) -> anyhow::Result<GeneratedResponse> {
+    model
+        .backend
+        .generate(model, config, tools, history, temperature)
+        .await
+}
+
+/// A kind of api that responses can be generated with.
+#[async_trait]
+trait LlmBackend: Send + Sync {
+    /// Generate a response to the chat history with the model, which uses this backend.
+    ///
+    /// If `temperature` is set, it is used instead of the configured temperature.
+    async fn generate(
+        &self,
+        model: &LlmModel,
+        config: &Configuration,
+        tools: &ToolRegistry,
+        history: Vec<ChatCompletionRequestMessage>,
+        temperature: Option<f32>,
+    ) -> anyhow::Result<GeneratedResponse>;
+}
+
+/// Generates responses with the OpenAI chat completions api, calling the tools the model asks for.
+struct OpenAI;
+
+#[async_trait]
+impl LlmBackend for OpenAI {
+    async fn generate(
+        &self,
+        model: &LlmModel,
+        config: &Configuration,
+        tools: &ToolRegistry,
+        history: Vec<ChatCompletionRequestMessage>,
+        temperature: Option<f32>,
+    ) -> anyhow::Result<GeneratedResponse> {
+        generate_openai_response(model, config, tools, history, temperature).await
+    }
+}
+
+/// Generate a response with the OpenAI chat completions api.
+async fn generate_openai_response(
+    model: &LlmModel,
+    config: &Configuration,
+    tools: &ToolRegistry,
+    mut history: Vec<ChatCompletionRequestMessage>,
+    temperature: Option<f32>,
+) -> anyhow::Result<GeneratedResponse> {
+    let mut usage: Option<TokenUsage> = None;
+    for _ in 0..MAX_TOOL_ITERATIONS {
+        let mut request = build_request(config, history.clone())?;
+        request.model = model.name.clone();
+        if temperature.is_some() {
+            request.temperature = temperature;
+        }
+        if !tools.is_empty() {
+            request.tools = Some(tools.definitions());
+        }
+
+        let permit = model.acquire_permit().await;
+        let mut response = create_chat_completion(
+            &model.client,
+            request_body(config, &request)?,
+            config.request_timeout(),
+        )
+        .await?;
+        // Tool calls can take a while, so the permit isn't held while they run.
+        drop(permit);
+        if let Some(response_usage) = response.usage.take() {
+            *usage.get_or_insert_default() += response_usage;
+        }
+
+        match parse_response(response)? {
+            ResponseAction::CallTools {
+                content,
+                tool_calls,
+            } => {
+                // Give the results of the tool calls back to the LLM, so it can use them in the
+                // next response.
+                let mut results = Vec::with_capacity(tool_calls.len());
+                for call in &tool_calls {
+                    let result = tools
+                        .call(&call.function.name, &call.function.arguments)
+                        .await;
+                    results.push(ChatCompletionRequestMessage::Tool(
+                        ChatCompletionRequestToolMessage {
+                            content: result.into(),
+                            tool_call_id: call.id.clone(),
+                        },
+                    ));
+                }
+
+                history.push(ChatCompletionRequestMessage::Assistant(
+                    ChatCompletionRequestAssistantMessage {
+                        content: content.map(Into::into),
+                        tool_calls: Some(tool_calls),
+                        ..Default::default()
+                    },
+                ));
+                history.extend(results);
+            }
+            ResponseAction::Respond { content, truncated } => {
+                return Ok(GeneratedResponse {
+                    content,
+                    usage,
+                    truncated,
+                });
+            }
+        }
+    }
+
+    anyhow::bail!("LLM did not respond after {MAX_TOOL_ITERATIONS} rounds of tool calls")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::config::Config;
+
+    /// Create a channel configuration with the required fields set, along with any extra toml.
+    pub(super) fn test_config(extra: &str) -> Configuration {
+        let toml = format!(
+            "channel_id = 1\nllm_api_key = \"key\"\nmodel_name = \"model\"\nprompt_path = \"prompt.txt\"\n{extra}"
+        );
+
+        config::Config::builder()
+            .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+            .build()
+            .expect("Unable to build test config")
+            .try_deserialize()
+            .expect("Unable to deserialize test config")
+    }
+
+    /// Serialize the request built for the configuration into JSON.
+    fn request_json(config: &Configuration) -> serde_json::Value {
+        let request = build_request(
+            config,
+            vec![ChatCompletionRequestMessage::User("hi".into())],
+        )
+        .expect("Unable to build request");
+        serde_json::to_value(request).expect("Unable to serialize request")
+    }
+
+    /// Sampling parameters must not be sent when they aren't configured.
+    #[test]
+    fn sampling_params_omitted_by_default() {
+        let request = request_json(&test_config(""));
+
+        for field in [
+            "temperature",
+            "top_p",
+            "frequency_penalty",
+            "presence_penalty",
+            "seed",
+            "stop",
+        ] {
+            assert!(request.get(field).is_none(), "{field} should not be sent");
+        }
+    }
+
+    /// The response token limit defaults to 400 tokens and can be disabled with 0.
+    #[test]
+    fn max_response_tokens() {
+        assert_eq!(request_json(&test_config(""))["max_tokens"], 400);
+        assert_eq!(
+            request_json(&test_config("max_response_tokens = 1000"))["max_tokens"],
+            1000
+        );
+        assert!(
+            request_json(&test_config("max_response_tokens = 0"))
+                .get("max_tokens")
+                .is_none()
+        );
+    }
+
+    /// JSON mode must be requested from the api, and responses checked to be JSON.
+    #[test]
+    fn response_format() {
+        assert!(
+            request_json(&test_config(""))
+                .get("response_format")
+                .is_none()
+        );
+
+        let config = test_config("response_format = \"json_object\"");
+        assert_eq!(
+            request_json(&config)["response_format"],
+            serde_json::json!({ "type": "json_object" })
+        );
+        assert!(config.response_format.is_valid("{\"answer\": 42}"));
+        assert!(!config.response_format.is_valid("The answer is 42"));
+    }
+
+    /// Reasoning must be read from either of the names APIs use for it.
+    #[test]
+    fn response_reasoning() {
+        for key in ["reasoning_content", "reasoning"] {
+            let response: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+                "choices": [{
+                    "message": { "role": "assistant", "content": "answer", key: "thinking" }
+                }]
+            }))
+            .expect("Unable to parse response");
+
+            let message = &response.choices[0].message;
+            assert_eq!(message.reasoning(), Some("thinking"));
+            assert_eq!(message.content.as_deref(), Some("answer"));
+        }
+    }
+
+    /// Content returned as an array of parts must be joined, ignoring parts that aren't text.
+    #[test]
+    fn response_content_parts() {
+        let response: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": [
+                        { "type": "text", "text": "Hello" },
+                        { "type": "image_url", "image_url": { "url": "https://example.com" } },
+                        { "type": "text", "text": " world" }
+                    ]
+                }
+            }]
+        }))
+        .expect("Unable to parse response");
+
+        assert!(matches!(
+            parse_response(response).unwrap(),
+            ResponseAction::Respond { content, .. } if content == "Hello world"
+        ));
+    }
+
+    /// The message length must stay within discord's limit.
+    #[test]
+    fn max_message_chars() {
+        assert_eq!(test_config("").max_message_chars(), 2000);
+        assert_eq!(
+            test_config("max_response_chars = 1000").max_message_chars(),
+            1000
+        );
+        assert_eq!(
+            test_config("max_response_chars = 4000").max_message_chars(),
+            2000
+        );
+    }
+
+    /// The prompt must be sent with the configured role.
+    #[test]
+    fn prompt_role() {
+        for (toml, role) in [
+            ("", "system"),
+            ("prompt_role = \"system\"", "system"),
+            ("prompt_role = \"developer\"", "developer"),
+        ] {
+            let config = test_config(toml);
+            let request = build_request(&config, vec![config.prompt_role.message("prompt")])
+                .expect("Unable to build request");
+            let request = serde_json::to_value(request).expect("Unable to serialize request");
+
+            assert_eq!(request["messages"][0]["role"], role);
+            assert_eq!(request["messages"][0]["content"], "prompt");
+        }
+    }
+
+    /// The no response marker must only match when it is the whole response.
+    #[test]
+    fn no_response_marker() {
+        let config = test_config("");
+        assert!(config.is_no_response("<empty/>"));
+        assert!(config.is_no_response("  <empty/>\n"));
+        assert!(!config.is_no_response("I won't use <empty/> here"));
+
+        let config = test_config("no_response_marker = \"[skip]\"");
+        assert!(config.is_no_response("[skip]"));
+        assert!(!config.is_no_response("<empty/>"));
+
+        let config = test_config("response_format = \"json_object\"");
+        assert!(!config.is_no_response("<empty/>"));
+    }
+
+    /// The prompt append must use the latest contents of the file, and be left out when empty.
+    #[test]
+    fn prompt_append_from_file() {
+        let config = test_config("prompt_append_env = \"BOT_TEST_PROMPT_APPEND_THAT_IS_NOT_SET\"");
+        assert_eq!(prompt_append(&config, None), None);
+
+        let (sender, receiver) = watch::channel(Box::from("The locale is de-DE.\n"));
+        assert_eq!(
+            prompt_append(&config, Some(&receiver)).as_deref(),
+            Some("The locale is de-DE.")
+        );
+
+        sender.send_replace(Box::from("  "));
+        assert_eq!(prompt_append(&config, Some(&receiver)), None);
+    }
+
+    /// The configured headers must be sent, and invalid ones reported.
+    #[test]
+    fn llm_headers() {
+        let config = test_config("headers = { \"X-Title\" = \"Rust Central\" }");
+        let headers = config.llm_headers().expect("Headers should be valid");
+        assert_eq!(headers.get("x-title").unwrap(), "Rust Central");
+
+        let config = test_config("headers = { \"Not a header\" = \"value\" }");
+        assert!(config.llm_headers().is_err());
+    }
+
+    /// Every invalid setting must be reported at once.
+    #[test]
+    fn validate_reports_every_problem() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let prompt_path = dir.path().join("prompt.txt");
+        std::fs::write(&prompt_path, "prompt").expect("Unable to write prompt");
+        let mut config = test_config("");
+        config.prompt_path = prompt_path.into_boxed_path();
+        assert_eq!(config.validate(), Ok(()));
+
+        let mut config = test_config(
+            "max_history_size = 4\nmin_history_size = 8\nimage_support = true\nassistant_name = \"Mr Bot\"\nwebhook_username = \"Ferris\"\nstreaming = true\nmax_response_chars = 10\nmax_messages_per_response = 0",
+        );
+        config.llm_api_key = String::new();
+        config.model_name = "gpt-3.5-turbo".to_string();
+        let problems = config.validate().expect_err("Config should be invalid");
+        assert_eq!(problems.len(), 8, "{problems:?}");
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("prompt_path"))
+        );
+    }
+
+    /// Declining to respond is only overridden for mentions, when enabled.
+    #[test]
+    fn forced_reply_on_mention() {
+        let config = test_config("");
+        assert_eq!(config.forced_reply("<empty/>", true), None);
+
+        let config =
+            test_config("force_reply_on_mention = true\nmention_fallback_response = \"Hm?\"");
+        assert_eq!(config.forced_reply("<empty/>", true), Some("Hm?"));
+        assert_eq!(config.forced_reply("<empty/>", false), None);
+        assert_eq!(config.forced_reply("A response", true), None);
+    }
+
+    /// Fallback models can be set by name only, or with their own api.
+    #[test]
+    fn fallback_models() {
+        let config = test_config(
+            "llm_api_base = \"http://primary\"\nfallback_models = [\"second\", { model_name = \"third\", llm_api_base = \"http://third\" }]",
+        );
+        let models = llm_models(&config, None);
+
+        let names: Vec<_> = models.iter().map(|model| model.name.as_str()).collect();
+        assert_eq!(names, ["model", "second", "third"]);
+
+        let api_bases: Vec<_> = models
+            .iter()
+            .map(|model| model.client.config().api_base())
+            .collect();
+        assert_eq!(
+            api_bases,
+            ["http://primary", "http://primary", "http://third"]
+        );
+    }
+
+    /// Sampling parameters must be sent when they are configured.
+    #[test]
+    fn sampling_params_sent_when_set() {
+        let request = request_json(&test_config(
+            "temperature = 0.5\ntop_p = 0.25\nfrequency_penalty = 1.0\npresence_penalty = -1.0\nseed = 42\nstop = [\"===\"]",
+        ));
+
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["top_p"], 0.25);
+        assert_eq!(request["frequency_penalty"], 1.0);
+        assert_eq!(request["presence_penalty"], -1.0);
+        assert_eq!(request["seed"], 42);
+        assert_eq!(request["stop"], serde_json::json!(["==="]));
+    }
+
+    /// The configured cache control must be added to the request body.
+    #[test]
+    fn cache_control_added_to_body() {
+        let config = test_config("extra_cache_control = { type = \"ephemeral\" }");
+        let request = build_request(
+            &config,
+            vec![ChatCompletionRequestMessage::User("hi".into())],
+        )
+        .expect("Unable to build request");
+
+        let body = request_body(&config, &request).expect("Unable to build request body");
+        assert_eq!(
+            body["messages"][0]["content"][0]["cache_control"],
+            serde_json::json!({ "type": "ephemeral" })
+        );
+    }
+
+    /// Extra parameters must be added to the request body, overriding the parameters set by the
+    /// bot.
+    #[test]
+    fn extra_params_override_body() {
+        let config = test_config(
+            "temperature = 0.5\n[extra_params]\nreasoning_effort = \"low\"\ntemperature = 1.0",
+        );
+        let request = build_request(
+            &config,
+            vec![ChatCompletionRequestMessage::User("hi".into())],
+        )
+        .expect("Unable to build request");
+
+        let body = request_body(&config, &request).expect("Unable to build request body");
+        assert_eq!(body["reasoning_effort"], "low");
+        assert_eq!(body["temperature"], 1.0);
+        assert_eq!(body["model"], "model");
+    }
+
+    /// Responses must not ping anyone unless it is allowed.
+    #[test]
+    fn allowed_mentions() {
+        assert_eq!(
+            test_config("").allowed_mentions(),
+            AllowedMentions::default()
+        );
+        assert_eq!(
+            test_config("allowed_mentions = [\"users\"]")
+                .allowed_mentions()
+                .parse,
+            [MentionType::Users]
+        );
+
+        let mentions = test_config("ping_on_reply = true").allowed_mentions();
+        assert!(mentions.replied_user);
+        assert!(mentions.parse.is_empty());
+    }
+
+    /// Parse a response from the test data.
+    fn parse_fixture(json: &str) -> (Option<TokenUsage>, anyhow::Result<ResponseAction>) {
+        let mut response: ChatCompletionResponse =
+            serde_json::from_str(json).expect("Unable to deserialize response");
+        (response.usage.take(), parse_response(response))
+    }
+
+    /// A full OpenAI response must be parsed into its content and usage.
+    #[test]
+    fn parse_openai_response() {
+        let (usage, action) =
+            parse_fixture(include_str!("ai_channel/test_data/openai_response.json"));
+
+        assert!(matches!(
+            action.unwrap(),
+            ResponseAction::Respond { content, .. } if content == "Hello! How can I help with your Rust code today?"
+        ));
+        assert_eq!(
+            usage,
+            Some(TokenUsage {
+                prompt_tokens: 19,
+                completion_tokens: 12,
+                total_tokens: 31,
+            })
+        );
+    }
+
+    /// A response without the fields that gemini doesn't return must still be parsed.
+    #[test]
+    fn parse_minimal_response() {
+        let (usage, action) =
+            parse_fixture(include_str!("ai_channel/test_data/gemini_response.json"));
+
+        assert!(matches!(
+            action.unwrap(),
+            ResponseAction::Respond { content, .. } if content == "Hello! How can I help with your Rust code today?"
+        ));
+        assert_eq!(usage.map(|usage| usage.total_tokens), Some(31));
+    }
+
+    /// A response without content or tool calls must be an error.
+    #[test]
+    fn parse_response_without_content() {
+        let (usage, action) = parse_fixture(include_str!(
+            "ai_channel/test_data/no_content_response.json"
+        ));
+
+        assert_eq!(usage, None);
+        assert_eq!(
+            action.unwrap_err().to_string(),
+            "LLM response did not include message content"
+        );
+    }
+
+    /// Filtered responses must be told apart from other failures.
+    #[test]
+    fn parse_filtered_response() {
+        let (_, action) = parse_fixture(
+            r#"{ "choices": [{ "finish_reason": "content_filter", "message": { "content": null } }] }"#,
+        );
+        assert!(
+            action
+                .unwrap_err()
+                .downcast_ref::<ContentFiltered>()
+                .is_some()
+        );
+
+        let (_, action) = parse_fixture(r#"{ "choices": [] }"#);
+        assert_eq!(
+            action.unwrap_err().to_string(),
+            "LLM response did not include any choices, it may have been filtered"
+        );
+    }
+
+    /// Responses that ran out of tokens must be marked as truncated.
+    #[test]
+    fn parse_truncated_response() {
+        let (_, action) = parse_fixture(
+            r#"{ "choices": [{ "finish_reason": "length", "message": { "content": "It starts" } }] }"#,
+        );
+        assert!(matches!(
+            action.expect("Unable to parse response"),
+            ResponseAction::Respond { content, truncated: true } if content == "It starts"
+        ));
+    }
+
+    /// The bot's messages must carry the assistant name when it is set.
+    #[test]
+    fn assistant_message_name() {
+        let json = serde_json::to_value(test_config("").assistant_message("hi"))
+            .expect("Unable to serialize message");
+        assert_eq!(
+            json,
+            serde_json::json!({ "role": "assistant", "content": "hi" })
+        );
+
+        let config = test_config("assistant_name = \"Ferris\"");
+        let json = serde_json::to_value(config.assistant_message("hi"))
+            .expect("Unable to serialize message");
+        assert_eq!(json["name"], "Ferris");
+    }
+
+    /// The language instruction must only be sent when a language is set.
+    #[test]
+    fn language_instruction() {
+        assert!(test_config("").language_instruction().is_none());
+        assert!(
+            test_config("force_language = \" \"")
+                .language_instruction()
+                .is_none()
+        );
+
+        let instruction = test_config("force_language = \"German\"").language_instruction();
+        let json = serde_json::to_value(instruction).expect("Unable to serialize message");
+        assert_eq!(json["role"], "system");
+        assert_eq!(
+            json["content"],
+            "Always respond in German, regardless of the language of the messages."
+        );
+    }
+
+    /// Only messages that just say "continue" must continue the response.
+    #[test]
+    fn continue_messages() {
+        assert!(is_continue_message("continue"));
+        assert!(is_continue_message(" Continue! "));
+        assert!(!is_continue_message("continue with the next step"));
+        assert!(!is_continue_message(""));
+    }
 }
@@ -0,0 +1,350 @@
+use anyhow::Context;
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequest};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use tracing::debug;
+
+use super::{
+    GeneratedResponse, LlmBackend, LlmModel, cache_control, raw_log, reasoning, usage::TokenUsage,
+};
+use crate::tools::ToolRegistry;
+
+/// The api used when `llm_api_base` isn't set.
+pub const DEFAULT_API_BASE: &str = "https://api.anthropic.com/v1";
+
+/// The version of the Messages API the requests are written for.
+const API_VERSION: &str = "2023-06-01";
+
+/// The Messages API requires a maximum, so this is used when `max_response_tokens` isn't set.
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// The Messages API only accepts temperatures up to 1, unlike the OpenAI api.
+const MAX_TEMPERATURE: f64 = 1.0;
+
+/// Generates responses with Anthropic's native Messages API.
+pub struct Anthropic {
+    pub http: reqwest::Client,
+    pub api_base: String,
+    pub api_key: String,
+}
+
+#[async_trait]
+impl LlmBackend for Anthropic {
+    async fn generate(
+        &self,
+        model: &LlmModel,
+        config: &super::Configuration,
+        _tools: &ToolRegistry,
+        history: Vec<ChatCompletionRequestMessage>,
+        temperature: Option<f32>,
+    ) -> anyhow::Result<GeneratedResponse> {
+        let mut request = super::build_request(config, history)?;
+        request.model = model.name.clone();
+        if temperature.is_some() {
+            request.temperature = temperature;
+        }
+        let body = request_body(config, &request)?;
+
+        let timeout = config.request_timeout();
+        let _permit = model.acquire_permit().await;
+        raw_log::log_request("messages", &body);
+        let (status, text) = tokio::time::timeout(timeout, async {
+            let response = self
+                .http
+                .post(format!("{}/messages", self.api_base.trim_end_matches('/')))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", API_VERSION)
+                .json(&body)
+                .send()
+                .await?;
+            Ok::<_, reqwest::Error>((response.status(), response.text().await?))
+        })
+        .await
+        .map_err(|_| super::timed_out(timeout))?
+        .context("Failed to send the request to the LLM api")?;
+        raw_log::log_response("messages", &text);
+
+        if !status.is_success() {
+            anyhow::bail!("LLM api returned an error ({status}): {text}");
+        }
+        let response: MessagesResponse =
+            serde_json::from_str(&text).context("Failed to parse the LLM api response")?;
+        Ok(response.into_generated())
+    }
+}
+
+/// Convert the request into the body of a Messages API request.
+///
+/// The prompts are moved out of the messages into the system prompt, and the content parts are
+/// converted to Anthropic's format. Parameters the api doesn't support are left out.
+///
+/// With `extra_cache_control`, the channel prompt and the last message are marked as cacheable.
+fn request_body(
+    config: &super::Configuration,
+    request: &CreateChatCompletionRequest,
+) -> anyhow::Result<Value> {
+    let request = serde_json::to_value(request).context("Failed to serialize request")?;
+
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+    for message in request["messages"].as_array().into_iter().flatten() {
+        let content = &message["content"];
+        match message["role"].as_str() {
+            Some("system" | "developer") => system.extend(text_block(&text_content(content))),
+            Some(role @ ("user" | "assistant")) => {
+                let content = content_blocks(content);
+                if !content.is_empty() {
+                    messages.push(json!({ "role": role, "content": content }));
+                }
+            }
+            // Tools aren't supported with this api, so there are no tool results to send.
+            _ => {}
+        }
+    }
+
+    let mut body = Map::new();
+    body.insert("model".to_string(), request["model"].clone());
+    body.insert(
+        "max_tokens".to_string(),
+        request["max_tokens"]
+            .as_u64()
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+            .into(),
+    );
+    if let Some(cache_control) = &config.extra_cache_control {
+        // The channel prompt is always the first system message.
+        if let Some(Value::Object(prompt)) = system.first_mut() {
+            prompt.insert("cache_control".to_string(), cache_control.clone());
+        }
+        if let Some(message) = messages.last_mut() {
+            cache_control::mark_message(message, cache_control);
+        }
+    }
+    if !system.is_empty() {
+        body.insert("system".to_string(), system.into());
+    }
+    body.insert("messages".to_string(), messages.into());
+    if let Some(temperature) = request["temperature"].as_f64() {
+        body.insert(
+            "temperature".to_string(),
+            temperature.min(MAX_TEMPERATURE).into(),
+        );
+    }
+    if !request["top_p"].is_null() {
+        body.insert("top_p".to_string(), request["top_p"].clone());
+    }
+    if !request["stop"].is_null() {
+        body.insert("stop_sequences".to_string(), request["stop"].clone());
+    }
+    body.extend(config.extra_params.clone());
+
+    Ok(Value::Object(body))
+}
+
+/// The text of message content, which is either a string or an array of content parts.
+fn text_content(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Convert message content into Anthropic's content blocks.
+fn content_blocks(content: &Value) -> Vec<Value> {
+    let parts = match content {
+        Value::String(text) => return text_block(text).into_iter().collect(),
+        Value::Array(parts) => parts,
+        _ => return Vec::new(),
+    };
+
+    parts
+        .iter()
+        .filter_map(|part| match part["type"].as_str() {
+            Some("text") => part["text"].as_str().and_then(text_block),
+            Some("image_url") => part["image_url"]["url"].as_str().map(image_block),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A text block, or `None` for empty text, which the api rejects.
+fn text_block(text: &str) -> Option<Value> {
+    (!text.is_empty()).then(|| json!({ "type": "text", "text": text }))
+}
+
+/// An image block, with the image embedded if it is a data URL.
+fn image_block(url: &str) -> Value {
+    let embedded = url
+        .strip_prefix("data:")
+        .and_then(|data| data.split_once(";base64,"));
+    match embedded {
+        Some((media_type, data)) => json!({
+            "type": "image",
+            "source": { "type": "base64", "media_type": media_type, "data": data },
+        }),
+        None => json!({ "type": "image", "source": { "type": "url", "url": url } }),
+    }
+}
+
+/// The parts of a Messages API response that are used.
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: Option<MessagesUsage>,
+    /// Why the model stopped generating, e.g. `end_turn` or `max_tokens`.
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessagesUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl MessagesResponse {
+    fn into_generated(self) -> GeneratedResponse {
+        let mut content = String::new();
+        for block in self.content {
+            match block {
+                ContentBlock::Text { text } => content.push_str(&text),
+                // The reasoning is only useful for troubleshooting, it isn't posted.
+                ContentBlock::Thinking { thinking } => debug!("Model reasoning: {thinking}"),
+                ContentBlock::Other => {}
+            }
+        }
+
+        let (reasoning, answer) = reasoning::split_inline_reasoning(&content);
+        if let Some(reasoning) = reasoning {
+            debug!("Model reasoning: {reasoning}");
+        }
+
+        GeneratedResponse {
+            content: answer.to_string(),
+            truncated: self.stop_reason.as_deref() == Some("max_tokens"),
+            usage: self.usage.map(|usage| TokenUsage {
+                prompt_tokens: usage.input_tokens,
+                completion_tokens: usage.output_tokens,
+                total_tokens: usage.input_tokens + usage.output_tokens,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_channel::tests::test_config;
+
+    /// The prompt must be sent as the system prompt, and images in Anthropic's format.
+    #[test]
+    fn request_in_messages_format() {
+        let config = test_config("temperature = 1.5\nstop = [\"END\"]\nmax_response_tokens = 0");
+        let history = vec![
+            ChatCompletionRequestMessage::System("Be helpful.".into()),
+            serde_json::from_value(json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/jpeg;base64,AAAA" } }
+                ]
+            }))
+            .expect("Unable to deserialize message"),
+        ];
+        let request = super::super::build_request(&config, history).expect("Unable to build");
+
+        let body = request_body(&config, &request).expect("Unable to convert request");
+        assert_eq!(
+            body,
+            json!({
+                "model": "model",
+                "max_tokens": DEFAULT_MAX_TOKENS,
+                "system": [{ "type": "text", "text": "Be helpful." }],
+                "messages": [{
+                    "role": "user",
+                    "content": [
+                        { "type": "text", "text": "What is this?" },
+                        {
+                            "type": "image",
+                            "source": { "type": "base64", "media_type": "image/jpeg", "data": "AAAA" }
+                        }
+                    ]
+                }],
+                "temperature": MAX_TEMPERATURE,
+                "stop_sequences": ["END"],
+            })
+        );
+    }
+
+    /// The channel prompt and the last message must be marked for caching.
+    #[test]
+    fn cache_control_in_messages_format() {
+        let config = test_config("extra_cache_control = { type = \"ephemeral\" }");
+        let history = vec![
+            ChatCompletionRequestMessage::System("Be helpful.".into()),
+            ChatCompletionRequestMessage::User("old".into()),
+            ChatCompletionRequestMessage::User("new".into()),
+            ChatCompletionRequestMessage::System("Answer in English.".into()),
+        ];
+        let request = super::super::build_request(&config, history).expect("Unable to build");
+
+        let body = request_body(&config, &request).expect("Unable to convert request");
+        assert_eq!(
+            body["system"],
+            json!([
+                { "type": "text", "text": "Be helpful.", "cache_control": { "type": "ephemeral" } },
+                { "type": "text", "text": "Answer in English." }
+            ])
+        );
+        assert_eq!(
+            body["messages"],
+            json!([
+                { "role": "user", "content": [{ "type": "text", "text": "old" }] },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "new", "cache_control": { "type": "ephemeral" } }
+                ] }
+            ])
+        );
+    }
+
+    /// The text and usage must be read from the response, without the reasoning.
+    #[test]
+    fn parse_messages_response() {
+        let response: MessagesResponse =
+            serde_json::from_str(include_str!("test_data/anthropic_response.json"))
+                .expect("Unable to deserialize response");
+
+        let generated = response.into_generated();
+        assert_eq!(
+            generated.content,
+            "Hello! How can I help with your Rust code today?"
+        );
+        assert_eq!(
+            generated.usage,
+            Some(TokenUsage {
+                prompt_tokens: 19,
+                completion_tokens: 14,
+                total_tokens: 33,
+            })
+        );
+    }
+}
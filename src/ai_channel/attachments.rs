@@ -0,0 +1,121 @@
+use anyhow::Context;
+use twilight_model::channel::Attachment;
+
+/// The extensions of files that are read as text, for when discord doesn't know the content type.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "log", "md", "rs", "toml", "json", "yaml", "yml", "csv", "xml", "html", "css", "js",
+    "ts", "py", "c", "h", "cpp", "hpp", "go", "java", "kt", "sh", "sql", "diff", "patch",
+];
+
+/// A file attached to a message that isn't an image.
+#[derive(Debug)]
+pub struct FileAttachment {
+    pub filename: String,
+    pub url: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    is_text: bool,
+}
+
+impl From<&Attachment> for FileAttachment {
+    fn from(attachment: &Attachment) -> Self {
+        let is_text = attachment
+            .content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.starts_with("text/"))
+            || TEXT_EXTENSIONS.contains(&extension(&attachment.filename).as_str());
+
+        Self {
+            filename: attachment.filename.clone(),
+            url: attachment.url.clone(),
+            size: attachment.size,
+            is_text,
+        }
+    }
+}
+
+impl FileAttachment {
+    /// Download the file and format it as a code block, or a note saying it was omitted if it isn't
+    /// a text file or is larger than `max_bytes`.
+    pub async fn format(&self, http: &reqwest::Client, max_bytes: u64) -> String {
+        if !self.is_text || self.size > max_bytes {
+            return self.omitted();
+        }
+
+        match self.download(http).await {
+            Ok(Some(text)) => format_code_block(&self.filename, &text),
+            Ok(None) => self.omitted(),
+            Err(err) => {
+                tracing::error!("Failed to download attachment: {err:?}");
+                self.omitted()
+            }
+        }
+    }
+
+    /// Download the file, returning `None` if it isn't valid UTF-8.
+    async fn download(&self, http: &reqwest::Client) -> anyhow::Result<Option<String>> {
+        let bytes = http
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Discord returned an error")?
+            .bytes()
+            .await?;
+        Ok(String::from_utf8(bytes.into()).ok())
+    }
+
+    fn omitted(&self) -> String {
+        format!("[attachment omitted: {}]", self.filename)
+    }
+}
+
+/// Check if the attachment is an image that can be sent to the LLM.
+pub fn is_image(attachment: &Attachment) -> bool {
+    matches!(
+        extension(&attachment.filename).as_str(),
+        "jpeg" | "jpg" | "png" | "webp" | "gif"
+    )
+}
+
+fn extension(filename: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((_, extension)) => extension.to_ascii_lowercase(),
+        None => String::new(),
+    }
+}
+
+/// Format the file as a markdown code block, using a fence longer than any run of backticks in the
+/// file so it can't be closed early.
+fn format_code_block(filename: &str, text: &str) -> String {
+    let longest_run = text
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    let fence = "`".repeat(longest_run.max(2) + 1);
+
+    format!(
+        "attachment: {filename}\n{fence}{}\n{}\n{fence}",
+        extension(filename),
+        text.trim_end()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Backticks in the file must not end the code block.
+    #[test]
+    fn code_block_fence_is_longer_than_content() {
+        assert_eq!(
+            format_code_block("main.rs", "fn main() {}\n"),
+            "attachment: main.rs\n```rs\nfn main() {}\n```"
+        );
+        assert_eq!(
+            format_code_block("README.md", "```rs\n```"),
+            "attachment: README.md\n````md\n```rs\n```\n````"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use tracing::warn;
+
+/// What to do with a response that matches the blocklist.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistAction {
+    /// Don't send the response at all.
+    #[default]
+    Suppress,
+    /// Replace the matching parts of the response with asterisks.
+    Mask,
+}
+
+/// Patterns that must not appear in messages.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    patterns: Vec<Regex>,
+}
+
+impl Blocklist {
+    /// Parse a blocklist file. Each line is a case-insensitive regex, empty lines and lines
+    /// starting with `#` are ignored.
+    ///
+    /// Invalid patterns are skipped with a warning, so a typo doesn't disable the whole list.
+    pub fn parse(text: &str) -> Self {
+        let patterns = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(
+                |line| match RegexBuilder::new(line).case_insensitive(true).build() {
+                    Ok(regex) => Some(regex),
+                    Err(err) => {
+                        warn!("Ignoring invalid blocklist pattern '{line}': {err}");
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        Self { patterns }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(text))
+    }
+
+    /// Replace every match in the text with asterisks.
+    pub fn mask(&self, text: &str) -> String {
+        self.patterns
+            .iter()
+            .fold(text.to_string(), |text, pattern| {
+                pattern
+                    .replace_all(&text, |captures: &regex::Captures| {
+                        "*".repeat(captures[0].chars().count())
+                    })
+                    .into_owned()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Comments and invalid patterns must not end up in the list.
+    #[test]
+    fn parse_skips_comments_and_invalid_patterns() {
+        let blocklist = Blocklist::parse("# a comment\n\n\\bfoo\\b\n(unclosed\nba[rz]\n");
+
+        assert_eq!(blocklist.patterns.len(), 2);
+        assert!(blocklist.is_match("FOO"));
+        assert!(blocklist.is_match("baz"));
+        assert!(!blocklist.is_match("food"));
+        assert!(!blocklist.is_match("a comment"));
+    }
+
+    /// Only the matching parts must be masked, keeping the length of the text.
+    #[test]
+    fn mask_matches() {
+        let blocklist = Blocklist::parse("\\bfoo\\b\nbär");
+
+        assert_eq!(
+            blocklist.mask("Foo and food and bär"),
+            "*** and food and ***"
+        );
+    }
+}
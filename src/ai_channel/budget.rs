@@ -0,0 +1,165 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::usage::TokenUsage;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Limits the tokens and requests a channel uses each day.
+pub struct DailyBudget {
+    max_tokens: Option<u64>,
+    max_requests: Option<u64>,
+    /// The offset from UTC of the timezone the day starts at midnight in, in seconds.
+    utc_offset_secs: i64,
+    /// The file the usage is saved to, so restarting doesn't reset it.
+    path: Option<PathBuf>,
+    usage: DailyUsage,
+}
+
+/// The usage of the current day, as it is saved to the file.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct DailyUsage {
+    /// The amount of days since the unix epoch, in the configured timezone.
+    day: i64,
+    tokens: u64,
+    requests: u64,
+    /// If the notice that the budget was reached was already sent today.
+    notified: bool,
+}
+
+impl DailyBudget {
+    /// Create the budget from the configuration, continuing with the usage saved in the file if
+    /// there is one. A file that can't be read is logged and the usage starts at zero.
+    pub async fn load(config: &super::Configuration) -> Self {
+        let path = config.budget_state_path.as_deref().map(Path::to_path_buf);
+        let usage = match &path {
+            Some(path) => match tokio::fs::read_to_string(path).await {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                    warn!("Unable to parse the budget at '{}': {err}", path.display());
+                    DailyUsage::default()
+                }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => DailyUsage::default(),
+                Err(err) => {
+                    warn!("Unable to read the budget at '{}': {err}", path.display());
+                    DailyUsage::default()
+                }
+            },
+            None => DailyUsage::default(),
+        };
+
+        Self {
+            max_tokens: config.daily_token_budget,
+            max_requests: config.daily_request_budget,
+            utc_offset_secs: i64::from(config.budget_utc_offset) * 60 * 60,
+            path,
+            usage,
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.max_tokens.is_some() || self.max_requests.is_some()
+    }
+
+    /// Start counting from zero if a new day started since the usage was last recorded.
+    fn roll_over(&mut self, now: SystemTime) {
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64);
+        let day = (secs + self.utc_offset_secs).div_euclid(SECS_PER_DAY);
+        if day != self.usage.day {
+            self.usage = DailyUsage {
+                day,
+                ..DailyUsage::default()
+            };
+        }
+    }
+
+    /// Check if the tokens or requests used today have reached their limit.
+    pub fn is_exceeded(&mut self, now: SystemTime) -> bool {
+        if !self.is_limited() {
+            return false;
+        }
+        self.roll_over(now);
+        self.max_tokens.is_some_and(|max| self.usage.tokens >= max)
+            || self
+                .max_requests
+                .is_some_and(|max| self.usage.requests >= max)
+    }
+
+    /// Returns true the first time it is called each day, so the notice is only sent once.
+    pub async fn take_notice(&mut self) -> bool {
+        if self.usage.notified {
+            return false;
+        }
+        self.usage.notified = true;
+        self.save().await;
+        true
+    }
+
+    /// Record a request to the LLM api, with the tokens it used if they are known.
+    pub async fn record(&mut self, now: SystemTime, usage: Option<TokenUsage>) {
+        if !self.is_limited() {
+            return;
+        }
+        self.roll_over(now);
+        self.usage.requests += 1;
+        self.usage.tokens += usage.map_or(0, |usage| usage.total_tokens);
+        self.save().await;
+    }
+
+    async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let json = match serde_json::to_string(&self.usage) {
+            Ok(json) => json,
+            Err(err) => {
+                error!("Unable to serialize the budget: {err}");
+                return;
+            }
+        };
+        if let Err(err) = tokio::fs::write(path, json).await {
+            error!("Unable to save the budget to '{}': {err}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ai_channel::tests::test_config;
+
+    /// The budget must be exceeded once the limit is reached, and reset at midnight in the
+    /// configured timezone, also after being loaded again.
+    #[tokio::test]
+    async fn budget_resets_daily() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let mut config = test_config("daily_request_budget = 2\nbudget_utc_offset = 2");
+        config.budget_state_path = Some(dir.path().join("budget.json").into_boxed_path());
+
+        // 21:00 UTC, which is 23:00 in the configured timezone.
+        let evening = UNIX_EPOCH + Duration::from_secs(10 * SECS_PER_DAY as u64 + 21 * 60 * 60);
+        let mut budget = DailyBudget::load(&config).await;
+        budget.record(evening, None).await;
+        assert!(!budget.is_exceeded(evening));
+        budget.record(evening, None).await;
+        assert!(budget.is_exceeded(evening));
+        assert!(budget.take_notice().await);
+        assert!(!budget.take_notice().await);
+
+        let mut budget = DailyBudget::load(&config).await;
+        assert!(budget.is_exceeded(evening));
+        assert!(!budget.take_notice().await);
+
+        // Midnight has passed in the configured timezone, but not in UTC.
+        let next_day = evening + Duration::from_secs(2 * 60 * 60);
+        assert!(!budget.is_exceeded(next_day));
+    }
+}
@@ -0,0 +1,81 @@
+use serde_json::Value;
+
+/// Mark the channel prompt and the end of the history in the request body as cacheable, for APIs
+/// that support explicit cache breakpoints.
+///
+/// The next request starts with the same messages, so marking the end of the history lets it
+/// re-use everything up to that point from the cache.
+pub fn add_cache_control(body: &mut Value, cache_control: &Value) {
+    let Some(messages) = body.get_mut("messages").and_then(Value::as_array_mut) else {
+        return;
+    };
+    let last = messages.len().saturating_sub(1);
+
+    for index in [0, last] {
+        if let Some(message) = messages.get_mut(index) {
+            mark_message(message, cache_control);
+        }
+    }
+}
+
+/// The cache control is set on the content parts, so text content is turned into a single part.
+pub fn mark_message(message: &mut Value, cache_control: &Value) {
+    let Some(content) = message.get_mut("content") else {
+        return;
+    };
+
+    if let Value::String(text) = content {
+        *content = serde_json::json!([{ "type": "text", "text": text }]);
+    }
+    if let Some(part) = content
+        .as_array_mut()
+        .and_then(|parts| parts.last_mut())
+        .and_then(Value::as_object_mut)
+    {
+        part.insert("cache_control".to_string(), cache_control.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// The first and last messages must have their last content part marked.
+    #[test]
+    fn prompt_and_last_message_are_marked() {
+        let cache_control = json!({ "type": "ephemeral" });
+        let mut body = json!({
+            "model": "model",
+            "messages": [
+                { "role": "system", "content": "prompt" },
+                { "role": "user", "content": "old" },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "new" },
+                    { "type": "image_url", "image_url": { "url": "https://example.com" } }
+                ] }
+            ]
+        });
+
+        add_cache_control(&mut body, &cache_control);
+
+        assert_eq!(
+            body["messages"],
+            json!([
+                { "role": "system", "content": [
+                    { "type": "text", "text": "prompt", "cache_control": { "type": "ephemeral" } }
+                ] },
+                { "role": "user", "content": "old" },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "new" },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": "https://example.com" },
+                        "cache_control": { "type": "ephemeral" }
+                    }
+                ] }
+            ])
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+
+const FENCE: &str = "```";
+
+/// Limits the size of fenced code blocks in user messages, which can otherwise take up most of
+/// the context.
+#[derive(Debug, Clone, Copy)]
+pub struct CodeBlockLimits {
+    /// Blocks with more lines than this are truncated.
+    pub max_lines: Option<usize>,
+    /// Blocks with more characters than this are truncated.
+    pub max_chars: Option<usize>,
+    /// The amount of lines kept at the start and at the end of truncated blocks.
+    pub kept_lines: usize,
+}
+
+impl CodeBlockLimits {
+    pub fn from_config(config: &super::Configuration) -> Self {
+        Self {
+            max_lines: config.max_code_block_lines,
+            max_chars: config.max_code_block_chars,
+            kept_lines: config.code_block_kept_lines,
+        }
+    }
+
+    fn is_exceeded(&self, lines: &[&str]) -> bool {
+        self.max_lines.is_some_and(|max| lines.len() > max)
+            || self.max_chars.is_some_and(|max| char_len(lines) > max)
+    }
+}
+
+/// Replace the middle of fenced code blocks that exceed the limits with a note saying how many
+/// lines were omitted. Only blocks with the closing fence on its own line are truncated.
+pub fn truncate_code_blocks<'a>(content: &'a str, limits: &CodeBlockLimits) -> Cow<'a, str> {
+    if limits.max_lines.is_none() && limits.max_chars.is_none() {
+        return Cow::Borrowed(content);
+    }
+
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut output: Vec<Cow<str>> = Vec::with_capacity(lines.len());
+    let mut truncated = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        output.push(Cow::Borrowed(line));
+        i += 1;
+
+        // A fence that also closes on the same line isn't a block.
+        let Some(info) = line.trim_start().strip_prefix(FENCE) else {
+            continue;
+        };
+        if info.contains(FENCE) {
+            continue;
+        }
+        let Some(len) = lines[i..].iter().position(|line| line.trim() == FENCE) else {
+            continue;
+        };
+
+        let body = &lines[i..i + len];
+        if let Some((head, omitted, tail)) = split_body(body, limits) {
+            output.extend(head.iter().map(|line| Cow::Borrowed(*line)));
+            output.push(Cow::Owned(format!(
+                "... ({omitted} line{} omitted)",
+                if omitted == 1 { "" } else { "s" }
+            )));
+            output.extend(tail.iter().map(|line| Cow::Borrowed(*line)));
+            truncated = true;
+        } else {
+            output.extend(body.iter().map(|line| Cow::Borrowed(*line)));
+        }
+        output.push(Cow::Borrowed(lines[i + len]));
+        i += len + 1;
+    }
+
+    if truncated {
+        Cow::Owned(output.join("\n"))
+    } else {
+        Cow::Borrowed(content)
+    }
+}
+
+/// Split the body of a block into the lines kept at the start, the amount of omitted lines, and
+/// the lines kept at the end. Returns `None` if the block doesn't need to be truncated.
+fn split_body<'a>(
+    body: &'a [&'a str],
+    limits: &CodeBlockLimits,
+) -> Option<(&'a [&'a str], usize, &'a [&'a str])> {
+    if !limits.is_exceeded(body) {
+        return None;
+    }
+
+    // The kept lines may use half of the characters each, so the truncated block fits.
+    let max_chars = limits.max_chars.map_or(usize::MAX, |max| max / 2);
+    let kept = |lines: &mut dyn Iterator<Item = &&str>| {
+        let mut chars = 0;
+        lines
+            .take(limits.kept_lines)
+            .take_while(|line| {
+                chars += line.chars().count() + 1;
+                chars <= max_chars
+            })
+            .count()
+    };
+    let head = kept(&mut body.iter());
+    let tail = kept(&mut body[head..].iter().rev());
+
+    let omitted = body.len() - head - tail;
+    (omitted > 0).then(|| (&body[..head], omitted, &body[body.len() - tail..]))
+}
+
+/// The amount of characters in the lines, including the newlines between them.
+fn char_len(lines: &[&str]) -> usize {
+    lines
+        .iter()
+        .map(|line| line.chars().count() + 1)
+        .sum::<usize>()
+        .saturating_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Long blocks must keep their first and last lines, while short blocks and the text around
+    /// them are left untouched.
+    #[test]
+    fn truncate_long_code_blocks() {
+        let limits = CodeBlockLimits {
+            max_lines: Some(6),
+            max_chars: None,
+            kept_lines: 2,
+        };
+        let long: Vec<String> = (1..=10).map(|i| format!("line {i}")).collect();
+        let content = format!(
+            "Why does this panic?\n```rust\n{}\n```\nand\n```\nshort\n```",
+            long.join("\n")
+        );
+
+        assert_eq!(
+            truncate_code_blocks(&content, &limits),
+            "Why does this panic?\n```rust\nline 1\nline 2\n... (6 lines omitted)\nline 9\nline 10\n```\nand\n```\nshort\n```"
+        );
+
+        let short =
+            "```\n```\n```\nshort\n```\n```unclosed\nline\nline\nline\nline\nline\nline\nline";
+        assert!(matches!(
+            truncate_code_blocks(short, &limits),
+            Cow::Borrowed(_)
+        ));
+
+        // A single line over the character limit can't be kept.
+        let limits = CodeBlockLimits {
+            max_lines: None,
+            max_chars: Some(100),
+            kept_lines: 2,
+        };
+        let content = format!("```\n{}\n```", "x".repeat(200));
+        assert_eq!(
+            truncate_code_blocks(&content, &limits),
+            "```\n... (1 line omitted)\n```"
+        );
+    }
+}
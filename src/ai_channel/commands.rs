@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use tokio::time::Instant;
+use twilight_http::Client;
+use twilight_model::{
+    application::{
+        command::{Command, CommandType},
+        interaction::{
+            Interaction, InteractionData,
+            application_command::{CommandData, CommandOptionValue},
+        },
+    },
+    channel::message::MessageFlags,
+    guild::Permissions,
+    http::interaction::{InteractionResponse, InteractionResponseType},
+    id::{Id, marker::ChannelMarker},
+};
+use twilight_util::builder::{
+    InteractionResponseDataBuilder,
+    command::{BooleanBuilder, CommandBuilder},
+};
+
+/// Clears the history of the channel.
+pub const CLEAR: &str = "clear";
+/// Shows the state of the channel.
+pub const STATUS: &str = "status";
+/// Toggles logging the requests to and responses from the LLM api.
+pub const RAW_LOG: &str = "raw_log";
+
+/// A snapshot of the state of each conversation in an AI channel, used to answer [`STATUS`].
+pub type Status = HashMap<Id<ChannelMarker>, ConversationStatus>;
+
+/// The state of the channel or one of its threads.
+#[derive(Debug, Clone)]
+pub struct ConversationStatus {
+    /// The amount of messages in the history.
+    pub history_len: usize,
+    /// The estimated amount of tokens the history uses.
+    pub history_tokens: usize,
+    /// When the last response was sent.
+    pub last_response: Option<Instant>,
+}
+
+/// The slash commands used to control the AI channels.
+fn definitions() -> Vec<Command> {
+    vec![
+        CommandBuilder::new(
+            CLEAR,
+            "Clear the bot's conversation history in this channel",
+            CommandType::ChatInput,
+        )
+        .default_member_permissions(Permissions::MANAGE_MESSAGES)
+        .option(BooleanBuilder::new(
+            "reload_prompt",
+            "Also re-read the channel prompt file",
+        ))
+        .build(),
+        CommandBuilder::new(
+            STATUS,
+            "Show the bot's configuration and state in this channel",
+            CommandType::ChatInput,
+        )
+        .default_member_permissions(Permissions::MANAGE_MESSAGES)
+        .build(),
+        CommandBuilder::new(
+            RAW_LOG,
+            "Log the bot's requests to and responses from the LLM api, for debugging",
+            CommandType::ChatInput,
+        )
+        .default_member_permissions(Permissions::ADMINISTRATOR)
+        .option(BooleanBuilder::new("enabled", "Start or stop logging").required(true))
+        .build(),
+    ]
+}
+
+/// Describe the state of the conversation for [`STATUS`].
+pub fn status_message(
+    config: &super::Configuration,
+    status: Option<&ConversationStatus>,
+) -> String {
+    let (history_len, history_tokens, last_response) = match status {
+        Some(status) => (
+            status.history_len,
+            status.history_tokens,
+            status.last_response,
+        ),
+        None => (0, 0, None),
+    };
+
+    format!(
+        "**Model:** {}\n**Image support:** {}\n**History:** {history_len} messages (~{history_tokens} tokens)\n**Last response:** {}",
+        config.model_name,
+        if config.image_support { "on" } else { "off" },
+        match last_response {
+            Some(time) => format!("{}s ago", time.elapsed().as_secs()),
+            None => "never".to_string(),
+        }
+    )
+}
+
+/// Register the slash commands with discord, replacing any previously registered commands.
+pub async fn register_commands(http: &Client) -> anyhow::Result<()> {
+    let application = http
+        .current_user_application()
+        .await
+        .context("Failed to get the bot's application")?
+        .model()
+        .await?;
+
+    http.interaction(application.id)
+        .set_global_commands(&definitions())
+        .await
+        .context("Failed to register slash commands")?;
+
+    Ok(())
+}
+
+/// Get the slash command data of the interaction, if it is a slash command.
+pub fn command_data(interaction: &Interaction) -> Option<&CommandData> {
+    match &interaction.data {
+        Some(InteractionData::ApplicationCommand(data)) => Some(data),
+        _ => None,
+    }
+}
+
+/// Get the value of a boolean option of the command, defaulting to false if it isn't set.
+pub fn bool_option(data: &CommandData, name: &str) -> bool {
+    data.options
+        .iter()
+        .find(|option| option.name == name)
+        .is_some_and(|option| matches!(option.value, CommandOptionValue::Boolean(true)))
+}
+
+/// Check if the user who used the command has the permission.
+///
+/// Discord already hides commands from users without the default permissions, but server admins
+/// can override that.
+pub fn has_permission(interaction: &Interaction, permission: Permissions) -> bool {
+    interaction
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.contains(permission))
+}
+
+/// Respond to the interaction with a message only the user who used the command can see.
+pub async fn respond_ephemeral(
+    http: &Client,
+    interaction: &Interaction,
+    content: &str,
+) -> anyhow::Result<()> {
+    let response = InteractionResponse {
+        kind: InteractionResponseType::ChannelMessageWithSource,
+        data: Some(
+            InteractionResponseDataBuilder::new()
+                .content(content)
+                .flags(MessageFlags::EPHEMERAL)
+                .build(),
+        ),
+    };
+
+    http.interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &response)
+        .await
+        .context("Failed to respond to interaction")?;
+
+    Ok(())
+}
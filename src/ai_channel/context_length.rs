@@ -0,0 +1,53 @@
+use async_openai::error::OpenAIError;
+
+/// Parts of the error messages providers use when the request doesn't fit in the context window.
+const MESSAGES: [&str; 5] = [
+    "maximum context length",
+    "context length",
+    "context window",
+    "prompt is too long",
+    "too many tokens",
+];
+
+/// Check if the error was caused by the request not fitting in the context window of the model.
+///
+/// OpenAI sets an error code for this, other providers are matched by their error message.
+pub fn is_exceeded(err: &anyhow::Error) -> bool {
+    let has_code = err.chain().any(|source| {
+        matches!(
+            source.downcast_ref::<OpenAIError>(),
+            Some(OpenAIError::ApiError(err)) if err.code.as_deref() == Some("context_length_exceeded")
+        )
+    });
+
+    let message = format!("{err:#}").to_lowercase();
+    has_code || MESSAGES.iter().any(|part| message.contains(part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    /// Context length errors must be recognised by their code or message, and other errors not.
+    #[test]
+    fn context_length_errors() {
+        let openai = anyhow::Error::new(OpenAIError::ApiError(ApiError {
+            message: "Please reduce the length of the messages.".to_string(),
+            r#type: None,
+            param: None,
+            code: Some("context_length_exceeded".to_string()),
+        }))
+        .context("LLM api returned an error");
+        assert!(is_exceeded(&openai));
+
+        let anthropic = anyhow::anyhow!(
+            "LLM api returned an error (400 Bad Request): prompt is too long: 210000 tokens > 200000 maximum"
+        );
+        assert!(is_exceeded(&anthropic));
+
+        assert!(!is_exceeded(&anyhow::anyhow!(
+            "LLM api did not respond within 60s"
+        )));
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_openai::types::ChatCompletionRequestMessage;
+use serde_json::Value;
+use tracing::error;
+use twilight_http::Client;
+use twilight_model::{
+    channel::message::AllowedMentions,
+    http::attachment::Attachment,
+    id::{Id, marker::ChannelMarker},
+};
+
+/// Marks the message as debug output, so it isn't mistaken for a response.
+const HEADER: &str = "**Debug:** the messages sent to the LLM for the response above";
+
+/// The maximum amount of characters of the messages that are sent in the message itself, longer
+/// ones are sent as a file. This leaves room for the header in the discord limit.
+const MAX_INLINE_CHARS: usize = 1800;
+
+/// Replaces images, as their data isn't useful for tuning the prompt.
+const OMITTED_DATA: &str = "[data omitted]";
+
+/// Post the messages that were sent to the LLM in the background, in the channel or a DM with
+/// `debug_echo_user` if it is set. Failing to post them is only logged.
+pub fn echo_messages(
+    http: &Arc<Client>,
+    config: &super::Configuration,
+    channel_id: Id<ChannelMarker>,
+    messages: &[ChatCompletionRequestMessage],
+) {
+    let json = match format_messages(messages) {
+        Ok(json) => json,
+        Err(err) => {
+            error!("Failed to format debug echo: {err:?}");
+            return;
+        }
+    };
+
+    let http = http.clone();
+    let user_id = config.debug_echo_user;
+    tokio::spawn(async move {
+        let channel_id = match user_id {
+            Some(user_id) => match http.create_private_channel(user_id).await {
+                Ok(response) => match response.model().await {
+                    Ok(channel) => channel.id,
+                    Err(err) => {
+                        error!("Failed to deserialize debug echo DM channel: {err}");
+                        return;
+                    }
+                },
+                Err(err) => {
+                    error!("Failed to open debug echo DM channel: {err}");
+                    return;
+                }
+            },
+            None => channel_id,
+        };
+
+        let allowed_mentions = AllowedMentions::default();
+        let create_message = http
+            .create_message(channel_id)
+            .allowed_mentions(Some(&allowed_mentions));
+        let res = if json.chars().count() <= MAX_INLINE_CHARS {
+            let content = format!("{HEADER}\n```json\n{json}\n```");
+            create_message.content(&content).await
+        } else {
+            let file = [Attachment::from_bytes(
+                "messages.json".to_string(),
+                json.into_bytes(),
+                0,
+            )];
+            create_message.content(HEADER).attachments(&file).await
+        };
+
+        if let Err(err) = res {
+            error!("Failed to send debug echo: {err}");
+        }
+    });
+}
+
+/// Format the messages as JSON, without the API keys or the data of images.
+fn format_messages(messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<String> {
+    let mut messages = serde_json::to_value(messages).context("Failed to serialize messages")?;
+    omit_data(&mut messages);
+    let json = serde_json::to_string_pretty(&messages)?;
+    Ok(super::raw_log::redact(json))
+}
+
+/// Replace data URLs, which are used to send images, throughout the value.
+fn omit_data(value: &mut Value) {
+    match value {
+        Value::String(text) if text.starts_with("data:") => *text = OMITTED_DATA.to_string(),
+        Value::Array(values) => values.iter_mut().for_each(omit_data),
+        Value::Object(map) => map.values_mut().for_each(omit_data),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_channel::raw_log;
+
+    /// The API keys and image data must not be posted.
+    #[test]
+    fn secrets_and_images_are_removed() {
+        // The keys of every channel are added when they are served.
+        raw_log::add_secrets(["secret-key"]);
+        let messages = [
+            ChatCompletionRequestMessage::System("The password is secret-key".into()),
+            serde_json::from_value(serde_json::json!({
+                "role": "user",
+                "content": [{ "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }]
+            }))
+            .expect("Unable to deserialize message"),
+        ];
+
+        let json = format_messages(&messages).expect("Unable to format messages");
+        assert!(json.contains("The password is [REDACTED]"));
+        assert!(json.contains(OMITTED_DATA));
+        assert!(!json.contains("secret-key"));
+        assert!(!json.contains("AAAA"));
+    }
+}
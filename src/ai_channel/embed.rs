@@ -0,0 +1,135 @@
+use twilight_model::channel::message::Embed;
+use twilight_util::builder::embed::{EmbedBuilder, EmbedFieldBuilder, EmbedFooterBuilder};
+
+/// The limits discord puts on the parts of an embed, in characters.
+const TITLE_LENGTH: usize = 256;
+const DESCRIPTION_LENGTH: usize = 4096;
+const FIELD_COUNT: usize = 25;
+const FIELD_NAME_LENGTH: usize = 256;
+const FIELD_VALUE_LENGTH: usize = 1024;
+const FOOTER_TEXT_LENGTH: usize = 2048;
+const EMBED_TOTAL_LENGTH: usize = 6000;
+
+/// Discord doesn't allow empty field values, so this zero width space is used instead.
+const EMPTY_FIELD_VALUE: &str = "\u{200b}";
+
+/// Build an embed from a response that is structured like a markdown document.
+///
+/// The response must start with a `# Title` line. Each `## Name` heading after it starts a field,
+/// the text before the first field is the description and a last `-# text` line is the footer.
+/// Returns `None` if the response isn't structured this way or doesn't fit in an embed, so it can
+/// be sent as plain content instead without losing any of it.
+pub fn structured_embed(content: &str) -> Option<Embed> {
+    let content = content.trim();
+    let (first_line, rest) = content.split_once('\n').unwrap_or((content, ""));
+    let title = first_line.strip_prefix("# ")?.trim();
+
+    let mut lines: Vec<&str> = rest.lines().collect();
+    let footer = lines
+        .last()
+        .and_then(|line| line.strip_prefix("-# "))
+        .map(str::trim);
+    if footer.is_some() {
+        lines.pop();
+    }
+
+    let mut description = Vec::new();
+    let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+    let mut in_code_block = false;
+    for line in lines {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+
+        match line.strip_prefix("## ") {
+            Some(name) if !in_code_block => fields.push((name.trim(), Vec::new())),
+            _ => match fields.last_mut() {
+                Some((_, value)) => value.push(line),
+                None => description.push(line),
+            },
+        }
+    }
+    let description = description.join("\n").trim().to_string();
+    let fields: Vec<(&str, String)> = fields
+        .into_iter()
+        .map(|(name, value)| (name, value.join("\n").trim().to_string()))
+        .collect();
+
+    let total_length = [title, description.as_str(), footer.unwrap_or_default()]
+        .into_iter()
+        .chain(
+            fields
+                .iter()
+                .flat_map(|(name, value)| [*name, value.as_str()]),
+        )
+        .map(|text| text.chars().count())
+        .sum::<usize>();
+    let fits = title.chars().count() <= TITLE_LENGTH
+        && description.chars().count() <= DESCRIPTION_LENGTH
+        && footer.is_none_or(|footer| footer.chars().count() <= FOOTER_TEXT_LENGTH)
+        && fields.len() <= FIELD_COUNT
+        && fields.iter().all(|(name, value)| {
+            !name.is_empty()
+                && name.chars().count() <= FIELD_NAME_LENGTH
+                && value.chars().count() <= FIELD_VALUE_LENGTH
+        })
+        && total_length <= EMBED_TOTAL_LENGTH;
+    if title.is_empty() || !fits {
+        return None;
+    }
+
+    let mut embed = EmbedBuilder::new().title(title);
+    if !description.is_empty() {
+        embed = embed.description(description);
+    }
+    for (name, value) in fields {
+        let value = if value.is_empty() {
+            EMPTY_FIELD_VALUE.to_string()
+        } else {
+            value
+        };
+        embed = embed.field(EmbedFieldBuilder::new(name, value));
+    }
+    if let Some(footer) = footer {
+        embed = embed.footer(EmbedFooterBuilder::new(footer));
+    }
+
+    Some(embed.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Structured responses must be split into the parts of the embed, and other responses must
+    /// not be sent as embeds.
+    #[test]
+    fn structured_response_as_embed() {
+        let embed = structured_embed(
+            "# Vec::push\nAppends an element.\n## Example\n```rs\n## not a field\nv.push(1);\n```\n## Panics\nIf the capacity overflows.\n-# std::vec::Vec",
+        )
+        .expect("Response should be structured");
+
+        assert_eq!(embed.title.as_deref(), Some("Vec::push"));
+        assert_eq!(embed.description.as_deref(), Some("Appends an element."));
+        let fields: Vec<_> = embed
+            .fields
+            .iter()
+            .map(|field| (field.name.as_str(), field.value.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("Example", "```rs\n## not a field\nv.push(1);\n```"),
+                ("Panics", "If the capacity overflows.")
+            ]
+        );
+        assert_eq!(
+            embed.footer.map(|footer| footer.text).as_deref(),
+            Some("std::vec::Vec")
+        );
+
+        assert!(structured_embed("Just a normal response").is_none());
+        assert!(structured_embed(&format!("# Too long\n{}", "a".repeat(5000))).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tracing::error;
+use twilight_model::{
+    id::{Id, marker::ChannelMarker},
+    util::Timestamp,
+};
+
+/// A response that failed to be generated, written as a line of JSON to the error log.
+#[derive(Debug, Serialize)]
+pub struct FailedGeneration {
+    /// When the generation failed, in ISO 8601.
+    pub timestamp: String,
+    /// The channel or thread the response was for.
+    pub channel_id: Id<ChannelMarker>,
+    /// The models that were tried, in order.
+    pub models: Vec<String>,
+    pub error: String,
+    /// The content of the latest message the response was for, if there was one.
+    pub trigger_message: Option<String>,
+}
+
+impl FailedGeneration {
+    pub fn new(
+        channel_id: Id<ChannelMarker>,
+        models: Vec<String>,
+        error: &anyhow::Error,
+        trigger_message: Option<String>,
+    ) -> Self {
+        Self {
+            timestamp: now_iso_8601(),
+            channel_id,
+            models,
+            error: format!("{error:#}"),
+            trigger_message,
+        }
+    }
+
+    /// Append the failure to the log file in the background. Failing to write it is only logged,
+    /// so a broken log file doesn't affect the channel.
+    pub fn append_to(self, path: &Path) {
+        append_line_in_background(path, self, "error");
+    }
+}
+
+/// The current time in ISO 8601.
+pub fn now_iso_8601() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    Timestamp::from_secs(secs as i64)
+        .map(|timestamp| timestamp.iso_8601().to_string())
+        .unwrap_or_default()
+}
+
+/// Append the value to the file as a line of JSON in the background. Failing to write it is only
+/// logged, mentioning the `log_name`.
+pub fn append_line_in_background(
+    path: &Path,
+    value: impl Serialize + Send + Sync + 'static,
+    log_name: &str,
+) {
+    let path = path.to_path_buf();
+    let log_name = log_name.to_string();
+    tokio::spawn(async move {
+        if let Err(err) = append_line(&path, &value).await {
+            error!(
+                "Unable to write to the {log_name} log at '{}': {err}",
+                path.display()
+            );
+        }
+    });
+}
+
+/// Append the value to the file as a line of JSON, creating the file if it doesn't exist.
+pub async fn append_line(path: &Path, value: &impl Serialize) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    // Tokio only finishes writing to the file in the background without this.
+    file.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each failure must be appended as its own line of JSON.
+    #[tokio::test]
+    async fn failures_are_appended() {
+        let temp_dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let path = temp_dir.path().join("errors.jsonl");
+
+        for message in ["first", "second"] {
+            let failure = FailedGeneration::new(
+                Id::new(1),
+                vec!["model".to_string()],
+                &anyhow::anyhow!("it broke"),
+                Some(message.to_string()),
+            );
+            append_line(&path, &failure)
+                .await
+                .expect("Unable to write error log");
+        }
+
+        let log = std::fs::read_to_string(&path).expect("Unable to read error log");
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Invalid JSON line"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["channel_id"], "1");
+        assert_eq!(lines[0]["error"], "it broke");
+        assert_eq!(lines[1]["trigger_message"], "second");
+    }
+}
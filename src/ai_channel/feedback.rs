@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use serde::Serialize;
+use twilight_model::id::{
+    Id,
+    marker::{ChannelMarker, MessageMarker, UserMarker},
+};
+
+use super::error_log::{append_line_in_background, now_iso_8601};
+
+/// A reaction to one of the bot's responses, written as a line of JSON to the feedback log.
+#[derive(Debug, Serialize)]
+pub struct ResponseFeedback {
+    /// When the reaction was added, in ISO 8601.
+    pub timestamp: String,
+    /// The channel or thread the response was sent in.
+    pub channel_id: Id<ChannelMarker>,
+    pub message_id: Id<MessageMarker>,
+    pub emoji: String,
+    pub user_id: Id<UserMarker>,
+    pub user_name: Option<String>,
+    /// The content of the response, if it is still in the history.
+    pub response: Option<String>,
+}
+
+impl ResponseFeedback {
+    pub fn new(
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+        emoji: String,
+        user_id: Id<UserMarker>,
+        user_name: Option<String>,
+        response: Option<String>,
+    ) -> Self {
+        Self {
+            timestamp: now_iso_8601(),
+            channel_id,
+            message_id,
+            emoji,
+            user_id,
+            user_name,
+            response,
+        }
+    }
+
+    /// Append the feedback to the log file in the background. Failing to write it is only logged.
+    pub fn append_to(self, path: &Path) {
+        append_line_in_background(path, self, "feedback");
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use async_openai::{Client as AIClient, config::OpenAIConfig, types::ChatCompletionRequestMessage};
+use async_trait::async_trait;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use super::{
+    HistoryEntry,
+    summary::{SUMMARY_PREFIX, summarize},
+    tokens::Tokenizer,
+};
+
+/// Decides which messages are removed from the history once it grows too large.
+#[async_trait]
+pub trait HistoryTrimmer: Send + Sync {
+    /// Remove messages from the front of the history until it fits, returning the removed
+    /// messages.
+    async fn trim(
+        &self,
+        history: &mut VecDeque<HistoryEntry>,
+        prompt: &ChatCompletionRequestMessage,
+    ) -> Vec<ChatCompletionRequestMessage>;
+
+    /// Remove the oldest history messages from the messages sent to the LLM until they fit, in case
+    /// the history is still too large after being trimmed. The history is preceded by `leading`
+    /// messages, such as the channel prompt, and followed by `trailing` messages, which are always
+    /// kept along with the latest history message.
+    fn cap(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        leading: usize,
+        trailing: usize,
+    );
+}
+
+/// Limits the history by the amount of messages in it.
+pub struct MessageCount {
+    /// The amount of messages that starts a trim.
+    pub max: usize,
+    /// The amount of messages kept after a trim.
+    pub min: usize,
+}
+
+#[async_trait]
+impl HistoryTrimmer for MessageCount {
+    async fn trim(
+        &self,
+        history: &mut VecDeque<HistoryEntry>,
+        _prompt: &ChatCompletionRequestMessage,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        if history.len() <= self.max {
+            return Vec::new();
+        }
+
+        // Downsize the history buffer by removing some elements from the front until it is back to
+        // `min`. This is to ensure all messages fit in the context window while allowing the LLM
+        // cache to be re-used for the next messages.
+        let remove_from_front = history.len().saturating_sub(self.min);
+        let removed = history
+            .drain(0..remove_from_front)
+            .map(|entry| entry.msg)
+            .collect();
+
+        debug!("Downsized history to {}", history.len());
+        removed
+    }
+
+    fn cap(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        leading: usize,
+        trailing: usize,
+    ) {
+        let history_len = messages.len().saturating_sub(leading + trailing);
+        let excess = history_len.saturating_sub(self.max.max(1));
+        if excess > 0 {
+            messages.drain(leading..leading + excess);
+            debug!("Removed {excess} messages from the request to fit the history size");
+        }
+    }
+}
+
+/// Limits the prompt and the history together by their estimated size in tokens.
+pub struct TokenBudget {
+    pub tokenizer: Tokenizer,
+    pub max_tokens: usize,
+}
+
+#[async_trait]
+impl HistoryTrimmer for TokenBudget {
+    async fn trim(
+        &self,
+        history: &mut VecDeque<HistoryEntry>,
+        prompt: &ChatCompletionRequestMessage,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let prompt_tokens = self.tokenizer.message_token_count(prompt);
+        let mut history_tokens = self
+            .tokenizer
+            .history_token_count(history.iter().map(|entry| &entry.msg));
+        if prompt_tokens + history_tokens <= self.max_tokens {
+            return Vec::new();
+        }
+
+        // Remove the oldest messages until the prompt and the history fit in the budget.
+        let mut removed = Vec::new();
+        while prompt_tokens + history_tokens > self.max_tokens {
+            let Some(entry) = history.pop_front() else {
+                break;
+            };
+            history_tokens -= self.tokenizer.message_token_count(&entry.msg);
+            removed.push(entry.msg);
+        }
+
+        debug!(
+            "Downsized history to {} messages (~{} tokens)",
+            history.len(),
+            prompt_tokens + history_tokens
+        );
+        removed
+    }
+
+    fn cap(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        leading: usize,
+        trailing: usize,
+    ) {
+        let mut tokens = self.tokenizer.history_token_count(messages.iter());
+        let mut excess = 0;
+        while tokens > self.max_tokens && messages.len() > leading + trailing + 1 {
+            tokens -= self
+                .tokenizer
+                .message_token_count(&messages.remove(leading));
+            excess += 1;
+        }
+
+        if excess > 0 {
+            debug!("Removed {excess} messages from the request to fit the context budget");
+        }
+    }
+}
+
+/// Trims the history with another trimmer, then keeps a summary of the removed messages at the
+/// start of the history.
+pub struct Summarizing {
+    pub inner: Box<dyn HistoryTrimmer>,
+    pub client: AIClient<OpenAIConfig>,
+    /// Limits the requests sent at once, shared with the models that generate responses.
+    pub request_limit: Option<Arc<Semaphore>>,
+    pub model_name: String,
+    pub timeout: Duration,
+}
+
+#[async_trait]
+impl HistoryTrimmer for Summarizing {
+    async fn trim(
+        &self,
+        history: &mut VecDeque<HistoryEntry>,
+        prompt: &ChatCompletionRequestMessage,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let removed = self.inner.trim(history, prompt).await;
+        if removed.is_empty() {
+            return removed;
+        }
+
+        // Any previous summary is at the front of the history, so it is removed and summarized
+        // again along with the other messages.
+        let permit = super::acquire_permit(self.request_limit.as_deref()).await;
+        let summary = summarize(
+            &self.client,
+            &self.model_name,
+            removed.clone(),
+            self.timeout,
+        )
+        .await;
+        drop(permit);
+        match summary {
+            Ok(summary) => history.push_front(
+                ChatCompletionRequestMessage::System(format!("{SUMMARY_PREFIX}\n{summary}").into())
+                    .into(),
+            ),
+            Err(err) => warn!("Failed to summarize removed history: {err:?}"),
+        }
+        removed
+    }
+
+    fn cap(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        leading: usize,
+        trailing: usize,
+    ) {
+        self.inner.cap(messages, leading, trailing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(len: usize) -> VecDeque<HistoryEntry> {
+        (0..len)
+            .map(|i| ChatCompletionRequestMessage::User(format!("message {i}").into()).into())
+            .collect()
+    }
+
+    /// The oldest messages must be removed once the history is larger than the maximum, down to
+    /// the minimum.
+    #[tokio::test]
+    async fn message_count_trims_to_min() {
+        let trimmer = MessageCount { max: 4, min: 2 };
+        let prompt = ChatCompletionRequestMessage::System("prompt".into());
+
+        let mut full = history(4);
+        assert!(trimmer.trim(&mut full, &prompt).await.is_empty());
+        assert_eq!(full.len(), 4);
+
+        let mut over = history(5);
+        let removed = trimmer.trim(&mut over, &prompt).await;
+        assert_eq!(removed.len(), 3);
+        assert_eq!(over.len(), 2);
+        assert!(matches!(
+            &over[0].msg,
+            ChatCompletionRequestMessage::User(msg) if format!("{:?}", msg.content).contains("message 3")
+        ));
+    }
+
+    /// The assembled request must be capped to the maximum, keeping the prompt, the messages
+    /// around the history and the latest message.
+    #[test]
+    fn token_budget_caps_request() {
+        let tokenizer = Tokenizer::CharEstimate;
+        let prompt = ChatCompletionRequestMessage::System("prompt".into());
+        let recalled = ChatCompletionRequestMessage::System("recalled".into());
+        let language = ChatCompletionRequestMessage::System("language".into());
+        let mut messages: Vec<_> = [prompt.clone(), recalled.clone()]
+            .into_iter()
+            .chain(history(5).into_iter().map(|entry| entry.msg))
+            .chain([language.clone()])
+            .collect();
+        let trimmer = TokenBudget {
+            tokenizer,
+            max_tokens: tokenizer.history_token_count(&messages[..4])
+                + tokenizer.message_token_count(&language),
+        };
+
+        trimmer.cap(&mut messages, 2, 1);
+        assert_eq!(
+            messages,
+            vec![
+                prompt,
+                recalled,
+                ChatCompletionRequestMessage::User("message 3".into()),
+                ChatCompletionRequestMessage::User("message 4".into()),
+                language,
+            ]
+        );
+
+        // The latest message is kept, even if it doesn't fit.
+        let trimmer = TokenBudget {
+            tokenizer,
+            max_tokens: 0,
+        };
+        trimmer.cap(&mut messages, 2, 1);
+        assert_eq!(messages.len(), 4);
+    }
+
+    /// Only history messages count towards the maximum amount of messages.
+    #[test]
+    fn message_count_caps_request() {
+        let prompt = ChatCompletionRequestMessage::System("prompt".into());
+        let mut messages: Vec<_> = [prompt.clone(), prompt.clone()]
+            .into_iter()
+            .chain(history(5).into_iter().map(|entry| entry.msg))
+            .chain([prompt.clone()])
+            .collect();
+
+        MessageCount { max: 2, min: 1 }.cap(&mut messages, 2, 1);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            messages[2],
+            ChatCompletionRequestMessage::User("message 3".into())
+        );
+        assert_eq!(messages[4], prompt);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+/// The downsized and encoded images of a channel, so the same image is only processed once.
+///
+/// When the cache is full, the least recently used images are evicted.
+#[derive(Debug)]
+pub struct ImageCache {
+    entries: HashMap<String, CachedImage>,
+    /// The total size of the cached images, in bytes.
+    size: usize,
+    max_size: usize,
+    /// Incremented on every use, to know which image was used least recently.
+    uses: u64,
+}
+
+#[derive(Debug)]
+struct CachedImage {
+    image_b64: String,
+    last_used: u64,
+}
+
+impl ImageCache {
+    /// Create a cache that holds at most `max_size` bytes of images. A size of 0 disables caching.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            size: 0,
+            max_size,
+            uses: 0,
+        }
+    }
+
+    /// Get the encoded image downloaded from the url.
+    pub fn get(&mut self, url: &str) -> Option<&str> {
+        self.uses += 1;
+        let entry = self.entries.get_mut(cache_key(url))?;
+        entry.last_used = self.uses;
+        Some(&entry.image_b64)
+    }
+
+    pub fn insert(&mut self, url: &str, image_b64: String) {
+        let key = cache_key(url);
+        if let Some(replaced) = self.entries.remove(key) {
+            self.size -= replaced.image_b64.len();
+        }
+        if image_b64.len() > self.max_size {
+            return;
+        }
+
+        while self.size + image_b64.len() > self.max_size {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.image_b64.len();
+            }
+        }
+
+        self.uses += 1;
+        self.size += image_b64.len();
+        let entry = CachedImage {
+            image_b64,
+            last_used: self.uses,
+        };
+        self.entries.insert(key.to_string(), entry);
+    }
+}
+
+/// Discord signs attachment urls with query parameters that change over time, but the path is
+/// unique to the attachment.
+fn cache_key(url: &str) -> &str {
+    url.split('?').next().unwrap_or(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The least recently used image must be evicted first, keeping the cache within its size.
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ImageCache::new(10);
+        cache.insert("https://cdn/a.png?ex=1", "aaaa".to_string());
+        cache.insert("https://cdn/b.png", "bbbb".to_string());
+        assert_eq!(cache.get("https://cdn/a.png?ex=2"), Some("aaaa"));
+
+        cache.insert("https://cdn/c.png", "cccc".to_string());
+        assert_eq!(cache.get("https://cdn/b.png"), None);
+        assert_eq!(cache.get("https://cdn/a.png"), Some("aaaa"));
+        assert_eq!(cache.size, 8);
+
+        // Images that can never fit are not cached.
+        cache.insert("https://cdn/d.png", "d".repeat(11));
+        assert_eq!(cache.get("https://cdn/d.png"), None);
+    }
+}
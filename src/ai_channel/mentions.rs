@@ -0,0 +1,123 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use regex::{Captures, Regex};
+use twilight_gateway::Event;
+use twilight_model::{
+    channel::{Channel, message::Mention},
+    gateway::payload::incoming::GuildCreate,
+    id::{
+        Id,
+        marker::{ChannelMarker, RoleMarker, UserMarker},
+    },
+};
+
+/// Matches user (`<@id>`, `<@!id>`), role (`<@&id>`) and channel (`<#id>`) mentions.
+static MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(@!?|@&|#)(\d+)>").expect("mention regex is valid"));
+
+/// The names of the things that can be mentioned, learned from gateway events so resolving a
+/// mention never needs a request to discord.
+#[derive(Debug, Default)]
+pub struct MentionNames {
+    users: HashMap<Id<UserMarker>, String>,
+    roles: HashMap<Id<RoleMarker>, String>,
+    channels: HashMap<Id<ChannelMarker>, String>,
+}
+
+impl MentionNames {
+    /// Remember the display name of a user who sent a message.
+    pub fn insert_user(&mut self, id: Id<UserMarker>, name: String) {
+        self.users.insert(id, name);
+    }
+
+    /// Keep the role and channel names up to date with the event.
+    pub fn update(&mut self, event: &Event) {
+        match event {
+            Event::GuildCreate(guild) => {
+                if let GuildCreate::Available(guild) = guild.as_ref() {
+                    self.roles
+                        .extend(guild.roles.iter().map(|role| (role.id, role.name.clone())));
+                    for channel in guild.channels.iter().chain(&guild.threads) {
+                        self.insert_channel(channel);
+                    }
+                }
+            }
+            Event::RoleCreate(role) => {
+                self.roles.insert(role.role.id, role.role.name.clone());
+            }
+            Event::RoleUpdate(role) => {
+                self.roles.insert(role.role.id, role.role.name.clone());
+            }
+            Event::RoleDelete(role) => {
+                self.roles.remove(&role.role_id);
+            }
+            Event::ChannelCreate(channel) => self.insert_channel(channel),
+            Event::ChannelUpdate(channel) => self.insert_channel(channel),
+            Event::ThreadCreate(thread) => self.insert_channel(thread),
+            Event::ThreadUpdate(thread) => self.insert_channel(thread),
+            Event::ChannelDelete(channel) => {
+                self.channels.remove(&channel.id);
+            }
+            Event::ThreadDelete(thread) => {
+                self.channels.remove(&thread.id);
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_channel(&mut self, channel: &Channel) {
+        if let Some(name) = &channel.name {
+            self.channels.insert(channel.id, name.clone());
+        }
+    }
+
+    /// Replace the mentions in the content with the names of what they mention, e.g. `@Alice`.
+    ///
+    /// `mentioned` are the users mentioned in the message, as sent by discord. Mentions of anything
+    /// that isn't known are left as they are.
+    pub fn resolve(&self, content: &str, mentioned: &[Mention]) -> String {
+        MENTION
+            .replace_all(content, |captures: &Captures| {
+                let Ok(id) = captures[2].parse() else {
+                    return captures[0].to_string();
+                };
+                let Some(id) = Id::<UserMarker>::new_checked(id) else {
+                    return captures[0].to_string();
+                };
+
+                let name = match &captures[1] {
+                    "@&" => self.roles.get(&id.cast()).map(|name| format!("@{name}")),
+                    "#" => self.channels.get(&id.cast()).map(|name| format!("#{name}")),
+                    _ => {
+                        let mention = mentioned.iter().find(|mention| mention.id == id);
+                        mention
+                            .and_then(|mention| mention.member.as_ref()?.nick.as_ref())
+                            .or_else(|| self.users.get(&id))
+                            .or(mention.map(|mention| &mention.name))
+                            .map(|name| format!("@{name}"))
+                    }
+                };
+                name.unwrap_or_else(|| captures[0].to_string())
+            })
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Known mentions must be replaced by names, and unknown ones left as they are.
+    #[test]
+    fn resolve_mentions() {
+        let mut names = MentionNames::default();
+        names.insert_user(Id::new(1), "Alice".to_string());
+        names.roles.insert(Id::new(2), "Mods".to_string());
+        names.channels.insert(Id::new(3), "general".to_string());
+
+        assert_eq!(
+            names.resolve("<@1> and <@!1>, ask <@&2> in <#3> or <@4>", &[]),
+            "@Alice and @Alice, ask @Mods in #general or <@4>"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use async_openai::types::{CreateModerationRequest, ModerationInput};
+use serde::Deserialize;
+
+use super::raw_log;
+
+/// The endpoint, as it is shown in the raw log.
+const ENDPOINT: &str = "moderations";
+
+/// Sent by the moderation endpoint. Only the fields that are used are deserialized, as the
+/// categories differ between moderation models.
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+}
+
+/// Check if the moderation endpoint flags the content as potentially harmful.
+pub async fn is_flagged(
+    model: &super::LlmModel,
+    content: &str,
+    timeout: Duration,
+) -> anyhow::Result<bool> {
+    let request = CreateModerationRequest {
+        input: ModerationInput::String(content.to_string()),
+        model: None,
+    };
+
+    raw_log::log_request(ENDPOINT, &request);
+    let _permit = model.acquire_permit().await;
+    let response: serde_json::Value =
+        tokio::time::timeout(timeout, model.client.moderations().create_byot(request))
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Moderation api did not respond within {}s",
+                    timeout.as_secs()
+                )
+            })?
+            .context("Moderation api returned an error")?;
+    let response: ModerationResponse = raw_log::parse(ENDPOINT, response)?;
+
+    Ok(response.results.iter().any(|result| result.flagged))
+}
@@ -0,0 +1,94 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use twilight_http::Client;
+use twilight_model::{
+    id::{Id, marker::ChannelMarker},
+    util::Timestamp,
+};
+
+/// The values of the variables that can be used in the prompt.
+///
+/// The prompt can contain `{{date}}` (the current UTC date, e.g. 2025-01-31), `{{channel}}` (the
+/// name of the AI channel) and `{{guild}}` (the name of the server it is in).
+#[derive(Debug, Default)]
+pub struct PromptVariables {
+    channel: Option<String>,
+    guild: Option<String>,
+}
+
+impl PromptVariables {
+    /// Look up the names of the channel and the server it is in.
+    pub async fn fetch(http: &Client, channel_id: Id<ChannelMarker>) -> anyhow::Result<Self> {
+        let channel = http
+            .channel(channel_id)
+            .await
+            .context("Failed to get the channel")?
+            .model()
+            .await?;
+
+        let guild = match channel.guild_id {
+            Some(guild_id) => Some(
+                http.guild(guild_id)
+                    .await
+                    .context("Failed to get the server of the channel")?
+                    .model()
+                    .await?
+                    .name,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            channel: channel.name,
+            guild,
+        })
+    }
+
+    /// Replace the variables in the prompt with their values. Variables without a known value are
+    /// left as they are.
+    pub fn render(&self, prompt: &str, now: SystemTime) -> String {
+        let mut prompt = prompt.to_string();
+
+        if prompt.contains("{{date}}") {
+            let secs = now
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            if let Ok(timestamp) = Timestamp::from_secs(secs as i64) {
+                // The ISO 8601 format starts with the date, e.g. "2025-01-31T...".
+                let date = timestamp.iso_8601().to_string();
+                prompt = prompt.replace("{{date}}", &date[..10]);
+            }
+        }
+        if let Some(channel) = &self.channel {
+            prompt = prompt.replace("{{channel}}", channel);
+        }
+        if let Some(guild) = &self.guild {
+            prompt = prompt.replace("{{guild}}", guild);
+        }
+
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Known variables must be replaced, and everything else kept as it is.
+    #[test]
+    fn render_variables() {
+        let variables = PromptVariables {
+            channel: Some("rust-help".to_string()),
+            guild: None,
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_738_281_600);
+
+        assert_eq!(
+            variables.render("{{date}} in #{{channel}} of {{guild}}, {{other}}", now),
+            "2025-01-31 in #rust-help of {{guild}}, {{other}}"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::{fmt, time::Duration};
+
+use async_openai::error::OpenAIError;
+
+/// The LLM api rejected the request because the rate limit was reached.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// How long the api asked to wait before sending another request.
+    pub retry_after: Duration,
+    source: OpenAIError,
+}
+
+impl RateLimited {
+    /// Check if the error was caused by a rate limit that tells us how long to wait for.
+    pub fn from_error(err: OpenAIError) -> Result<Self, OpenAIError> {
+        match retry_after(&err) {
+            Some(retry_after) => Ok(Self {
+                retry_after,
+                source: err,
+            }),
+            None => Err(err),
+        }
+    }
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate limited, retry after {:.1}s: {}",
+            self.retry_after.as_secs_f32(),
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Get how long the api asked to wait before retrying, if the error was caused by a rate limit.
+///
+/// `async-openai` doesn't expose the response headers, so the `Retry-After` header can't be read.
+/// Instead this reads the delay from the error message, which most providers include.
+fn retry_after(err: &OpenAIError) -> Option<Duration> {
+    let OpenAIError::ApiError(err) = err else {
+        return None;
+    };
+
+    let message = err.message.to_lowercase();
+    let is_rate_limit = err.code.as_deref() == Some("rate_limit_exceeded")
+        || message.contains("rate limit")
+        || message.contains("resource_exhausted");
+    if !is_rate_limit {
+        return None;
+    }
+
+    parse_retry_after(&message)
+}
+
+/// Find a delay such as "try again in 1.5s" or "retry after 20 seconds" in an error message.
+fn parse_retry_after(message: &str) -> Option<Duration> {
+    const PREFIXES: [&str; 4] = [
+        "try again in ",
+        "retry after ",
+        "retry-after: ",
+        "retrydelay\": \"",
+    ];
+
+    let message = message.to_lowercase();
+    PREFIXES.iter().find_map(|prefix| {
+        let start = message.find(prefix)? + prefix.len();
+        parse_duration(&message[start..])
+    })
+}
+
+/// Parse a duration at the start of the text, such as "1.5s", "300ms", "2m" or "20 seconds".
+///
+/// Numbers without a unit are assumed to be seconds.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let number_len = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let number: f64 = text[..number_len].parse().ok()?;
+    let unit = text[number_len..].trim_start();
+
+    let seconds = if unit.starts_with("ms") || unit.starts_with("millisecond") {
+        number / 1000.0
+    } else if unit.starts_with("minute") || (unit.starts_with('m') && !unit.starts_with("mi")) {
+        number * 60.0
+    } else {
+        number
+    };
+
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    fn api_error(message: &str, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: message.to_string(),
+            r#type: None,
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    /// The delay must be read from the formats used by common providers.
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(
+            parse_retry_after("Rate limit reached for gpt-4o. Please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            parse_retry_after("Please try again in 300ms."),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(
+            parse_retry_after("Too many requests, retry after 20 seconds"),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            parse_retry_after(r#"{"@type": "RetryInfo", "retryDelay": "2m"}"#),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after("Something else went wrong"), None);
+    }
+
+    /// Only rate limit errors must be treated as rate limits.
+    #[test]
+    fn detects_rate_limits() {
+        assert!(
+            RateLimited::from_error(api_error(
+                "Rate limit reached. Please try again in 2s.",
+                Some("rate_limit_exceeded")
+            ))
+            .is_ok()
+        );
+        assert!(
+            RateLimited::from_error(api_error("Invalid model, try again in 2s", None)).is_err()
+        );
+    }
+}
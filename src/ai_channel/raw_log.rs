@@ -0,0 +1,107 @@
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tracing::{debug, trace};
+
+/// Replaces the API keys, in case they are in the prompt or history.
+const REDACTED: &str = "[REDACTED]";
+
+/// If the requests to and responses from the LLM api are logged, shared by every channel.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The API keys of every channel, which are removed from the logged requests and responses.
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Start or stop logging the requests to and responses from the LLM api.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Remove the API keys from the logs.
+pub fn add_secrets<'a>(keys: impl IntoIterator<Item = &'a str>) {
+    let mut secrets = SECRETS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for key in keys {
+        if !key.is_empty() && !secrets.iter().any(|secret| secret == key) {
+            secrets.push(key.to_string());
+        }
+    }
+    // A key could contain another key, so the longest are replaced first.
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+}
+
+/// Log the body of a request sent to the endpoint.
+pub fn log_request(endpoint: &str, body: &impl Serialize) {
+    if !is_enabled() {
+        return;
+    }
+    match serde_json::to_string(body) {
+        Ok(json) => debug!("Raw request to '{endpoint}': {}", redact(json)),
+        Err(err) => debug!("Unable to serialize the raw request to '{endpoint}': {err}"),
+    }
+}
+
+/// Log the body of a response from the endpoint.
+pub fn log_response(endpoint: &str, body: &str) {
+    if is_enabled() {
+        debug!(
+            "Raw response from '{endpoint}': {}",
+            redact(body.to_string())
+        );
+    }
+}
+
+/// Log a chunk of a streamed response from the endpoint. These are logged at trace, as there are
+/// many of them.
+pub fn log_chunk(endpoint: &str, chunk: &Value) {
+    if is_enabled() {
+        trace!("Raw chunk from '{endpoint}': {}", redact(chunk.to_string()));
+    }
+}
+
+/// Log the response from the endpoint, and then deserialize it.
+///
+/// Responses are received as a [`Value`], as `create_byot` doesn't expose the raw body.
+pub fn parse<T: DeserializeOwned>(endpoint: &str, response: Value) -> anyhow::Result<T> {
+    if is_enabled() {
+        log_response(endpoint, &response.to_string());
+    }
+    serde_json::from_value(response)
+        .with_context(|| format!("Failed to parse the response from '{endpoint}'"))
+}
+
+/// Replace the API keys added with [`add_secrets`] in the text.
+pub fn redact(mut json: String) -> String {
+    let secrets = SECRETS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for secret in secrets.iter() {
+        json = json.replace(secret, REDACTED);
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The API keys must not be logged.
+    #[test]
+    fn secrets_are_redacted() {
+        add_secrets(["sk-test", "sk-test-longer"]);
+        assert_eq!(
+            redact(r#"{"content":"my keys are sk-test-longer and sk-test"}"#.to_string()),
+            r#"{"content":"my keys are [REDACTED] and [REDACTED]"}"#
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use tracing::error;
+use twilight_http::{Client, request::channel::reaction::RequestReactionType};
+use twilight_model::id::{
+    Id,
+    marker::{ChannelMarker, MessageMarker},
+};
+
+/// Add the emoji to the message in the background. Failing to add it is only logged.
+pub fn add_reaction(
+    http: &Arc<Client>,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    emoji: String,
+) {
+    let http = http.clone();
+    tokio::spawn(async move {
+        let emoji = RequestReactionType::Unicode { name: &emoji };
+        if let Err(err) = http.create_reaction(channel_id, message_id, &emoji).await {
+            error!("Failed to add reaction: {err}");
+        }
+    });
+}
+
+/// Remove the bot's reaction with the emoji from the messages in the background. Failing to remove
+/// it is only logged.
+pub fn remove_reactions(
+    http: &Arc<Client>,
+    emoji: String,
+    messages: Vec<(Id<ChannelMarker>, Id<MessageMarker>)>,
+) {
+    if messages.is_empty() {
+        return;
+    }
+
+    let http = http.clone();
+    tokio::spawn(async move {
+        let emoji = RequestReactionType::Unicode { name: &emoji };
+        for (channel_id, message_id) in messages {
+            if let Err(err) = http
+                .delete_current_user_reaction(channel_id, message_id, &emoji)
+                .await
+            {
+                error!("Failed to remove reaction: {err}");
+            }
+        }
+    });
+}
+
+/// The messages marked with the seen emoji while their response is generated.
+pub struct SeenMessages {
+    pub channel_id: Id<ChannelMarker>,
+    pub message_ids: Vec<Id<MessageMarker>>,
+}
+
+impl SeenMessages {
+    /// Remove the seen emoji from the messages in the background, replacing it with the done emoji
+    /// if they were responded to.
+    pub fn finish(self, http: &Arc<Client>, config: &super::Configuration, responded: bool) {
+        if config.seen_emoji.is_empty() || self.message_ids.is_empty() {
+            return;
+        }
+
+        let http = http.clone();
+        let seen_emoji = config.seen_emoji.clone();
+        let done_emoji =
+            (responded && !config.done_emoji.is_empty()).then(|| config.done_emoji.clone());
+        tokio::spawn(async move {
+            let seen_emoji = RequestReactionType::Unicode { name: &seen_emoji };
+            let done_emoji = done_emoji
+                .as_deref()
+                .map(|name| RequestReactionType::Unicode { name });
+
+            for message_id in self.message_ids {
+                if let Err(err) = http
+                    .delete_current_user_reaction(self.channel_id, message_id, &seen_emoji)
+                    .await
+                {
+                    error!("Failed to remove seen reaction: {err}");
+                }
+                if let Some(done_emoji) = &done_emoji
+                    && let Err(err) = http
+                        .create_reaction(self.channel_id, message_id, done_emoji)
+                        .await
+                {
+                    error!("Failed to add done reaction: {err}");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,34 @@
+/// Split the reasoning some models put at the start of the content in `<think>` tags from the
+/// answer. If the closing tag hasn't been generated yet, all of the content is reasoning.
+pub fn split_inline_reasoning(content: &str) -> (Option<&str>, &str) {
+    let Some(rest) = content.trim_start().strip_prefix("<think>") else {
+        return (None, content);
+    };
+
+    match rest.split_once("</think>") {
+        Some((reasoning, answer)) => (Some(reasoning.trim()), answer.trim_start()),
+        None => (Some(rest.trim()), ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Only a leading think block is reasoning, and it must be removed from the answer.
+    #[test]
+    fn split_reasoning() {
+        assert_eq!(
+            split_inline_reasoning("<think>\nhmm\n</think>\n\nHello!"),
+            (Some("hmm"), "Hello!")
+        );
+        assert_eq!(
+            split_inline_reasoning("<think>still going"),
+            (Some("still going"), "")
+        );
+        assert_eq!(
+            split_inline_reasoning("Use <think> tags"),
+            (None, "Use <think> tags")
+        );
+    }
+}
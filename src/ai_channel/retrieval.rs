@@ -0,0 +1,420 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_openai::{
+    Client as AIClient,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
+        ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+        CreateEmbeddingRequest, EmbeddingInput,
+    },
+};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info, warn};
+use twilight_model::id::{Id, marker::ChannelMarker};
+
+use super::raw_log;
+
+/// The endpoint, as it is shown in the raw log.
+const ENDPOINT: &str = "embeddings";
+
+/// Starts the system message that holds the recalled messages.
+const RECALLED_PREFIX: &str = "Earlier messages from the conversation that may be relevant, which are no longer in the history:";
+
+/// The maximum amount of messages kept for each conversation, the oldest are forgotten first.
+const MAX_ENTRIES: usize = 2000;
+
+/// Starts the file the index is saved to, followed by the version of the format.
+const INDEX_MAGIC: &[u8; 4] = b"RCBI";
+const INDEX_VERSION: u8 = 1;
+
+/// Keeps the messages removed from the history, so the most relevant ones can be recalled by the
+/// similarity of their embeddings.
+pub struct Retrieval {
+    client: AIClient<OpenAIConfig>,
+    /// Limits the requests sent at once, shared with the models that generate responses.
+    request_limit: Option<Arc<Semaphore>>,
+    model_name: String,
+    top_k: usize,
+    timeout: Duration,
+    /// The file the index is saved to, so restarting doesn't require embedding everything again.
+    path: Option<PathBuf>,
+    /// The removed messages of each conversation, oldest first.
+    conversations: HashMap<Id<ChannelMarker>, Vec<Entry>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    text: String,
+    embedding: Vec<f32>,
+}
+
+/// Sent by the embeddings endpoint. Only the fields that are used are deserialized.
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl Retrieval {
+    /// Create the index from the configuration, continuing with the one saved in the file if
+    /// there is one. A file that can't be read, or was embedded by another model, is logged and the
+    /// index starts empty.
+    pub async fn load(config: &super::Configuration, model: &super::LlmModel) -> Self {
+        let path = config
+            .retrieval_index_path
+            .as_deref()
+            .map(Path::to_path_buf);
+        let conversations = match &path {
+            Some(path) => match tokio::fs::read(path).await {
+                Ok(bytes) => decode(&bytes, &config.embedding_model).unwrap_or_else(|err| {
+                    warn!("Unable to load the index at '{}': {err:#}", path.display());
+                    HashMap::new()
+                }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => {
+                    warn!("Unable to read the index at '{}': {err}", path.display());
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+        if !conversations.is_empty() {
+            info!(
+                "Loaded {} removed messages for retrieval",
+                conversations.values().map(Vec::len).sum::<usize>()
+            );
+        }
+
+        Self {
+            client: model.client.clone(),
+            request_limit: model.request_limit.clone(),
+            model_name: config.embedding_model.clone(),
+            top_k: config.retrieval_top_k,
+            timeout: config.request_timeout(),
+            path,
+            conversations,
+        }
+    }
+
+    /// Embed the messages removed from the history of the conversation and keep them. Failing to
+    /// embed them is only logged, as the conversation can continue without them.
+    pub async fn add(
+        &mut self,
+        channel_id: Id<ChannelMarker>,
+        removed: &[ChatCompletionRequestMessage],
+    ) {
+        let texts: Vec<String> = removed.iter().filter_map(message_text).collect();
+        if texts.is_empty() {
+            return;
+        }
+
+        let embeddings = match self.embed(texts.clone()).await {
+            Ok(embeddings) => embeddings,
+            Err(err) => {
+                warn!("Failed to embed removed history, it can't be recalled: {err:?}");
+                return;
+            }
+        };
+
+        let entries = self.conversations.entry(channel_id).or_default();
+        entries.extend(
+            texts
+                .into_iter()
+                .zip(embeddings)
+                .map(|(text, embedding)| Entry { text, embedding }),
+        );
+        let excess = entries.len().saturating_sub(MAX_ENTRIES);
+        entries.drain(..excess);
+        debug!("Kept {} removed messages for retrieval", entries.len());
+        self.save().await;
+    }
+
+    /// A message with the removed messages most similar to the query, in the order they were sent.
+    pub async fn recall(
+        &self,
+        channel_id: Id<ChannelMarker>,
+        query: &str,
+    ) -> Option<ChatCompletionRequestMessage> {
+        let entries = self.conversations.get(&channel_id)?;
+        if entries.is_empty() || query.trim().is_empty() {
+            return None;
+        }
+
+        let query = match self.embed(vec![query.to_string()]).await {
+            Ok(mut embeddings) => embeddings.pop()?,
+            Err(err) => {
+                warn!("Failed to embed query, responding without recalled messages: {err:?}");
+                return None;
+            }
+        };
+
+        let recalled = most_similar(entries, &query, self.top_k);
+        debug!("Recalled {} earlier messages", recalled.len());
+        let texts: Vec<&str> = recalled
+            .into_iter()
+            .map(|index| entries[index].text.as_str())
+            .collect();
+        Some(ChatCompletionRequestMessage::System(
+            format!("{RECALLED_PREFIX}\n\n{}", texts.join("\n\n")).into(),
+        ))
+    }
+
+    /// Forget the removed messages of the conversation, when its history is cleared.
+    pub async fn clear(&mut self, channel_id: Id<ChannelMarker>) {
+        if self.conversations.remove(&channel_id).is_some() {
+            self.save().await;
+        }
+    }
+
+    /// Forget the removed messages of every conversation.
+    pub async fn clear_all(&mut self) {
+        if !self.conversations.is_empty() {
+            self.conversations.clear();
+            self.save().await;
+        }
+    }
+
+    async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let bytes = encode(&self.conversations, &self.model_name);
+        if let Err(err) = tokio::fs::write(path, bytes).await {
+            error!("Unable to save the index to '{}': {err}", path.display());
+        }
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let amount = texts.len();
+        let request = CreateEmbeddingRequest {
+            model: self.model_name.clone(),
+            input: EmbeddingInput::StringArray(texts),
+            ..Default::default()
+        };
+
+        raw_log::log_request(ENDPOINT, &request);
+        let _permit = super::acquire_permit(self.request_limit.as_deref()).await;
+        let response: serde_json::Value =
+            tokio::time::timeout(self.timeout, self.client.embeddings().create_byot(request))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "Embeddings api did not respond within {}s",
+                        self.timeout.as_secs()
+                    )
+                })?
+                .context("Embeddings api returned an error")?;
+        let mut response: EmbeddingResponse = raw_log::parse(ENDPOINT, response)?;
+        anyhow::ensure!(
+            response.data.len() == amount,
+            "Embeddings api returned {} embeddings for {amount} texts",
+            response.data.len()
+        );
+
+        response.data.sort_by_key(|data| data.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|data| data.embedding)
+            .collect())
+    }
+}
+
+/// Encode the index as the magic and version, the embedding model, and then each entry as its
+/// conversation, text and embedding. Numbers are little endian, and lengths are u32.
+fn encode(conversations: &HashMap<Id<ChannelMarker>, Vec<Entry>>, model_name: &str) -> Vec<u8> {
+    fn put_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+        bytes.extend((value.len() as u32).to_le_bytes());
+        bytes.extend(value);
+    }
+
+    let mut bytes = INDEX_MAGIC.to_vec();
+    bytes.push(INDEX_VERSION);
+    put_bytes(&mut bytes, model_name.as_bytes());
+    for (channel_id, entries) in conversations {
+        for entry in entries {
+            bytes.extend(channel_id.get().to_le_bytes());
+            put_bytes(&mut bytes, entry.text.as_bytes());
+            bytes.extend((entry.embedding.len() as u32).to_le_bytes());
+            bytes.extend(entry.embedding.iter().flat_map(|value| value.to_le_bytes()));
+        }
+    }
+    bytes
+}
+
+/// Decode an index written by [`encode`], which must have been embedded by the model.
+fn decode(
+    mut bytes: &[u8],
+    model_name: &str,
+) -> anyhow::Result<HashMap<Id<ChannelMarker>, Vec<Entry>>> {
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(bytes.len() >= len, "File ends unexpectedly");
+        let (taken, rest) = bytes.split_at(len);
+        *bytes = rest;
+        Ok(taken)
+    }
+    fn take_u32(bytes: &mut &[u8]) -> anyhow::Result<usize> {
+        let value = take(bytes, 4)?.try_into()?;
+        Ok(u32::from_le_bytes(value) as usize)
+    }
+
+    anyhow::ensure!(
+        take(&mut bytes, INDEX_MAGIC.len())? == INDEX_MAGIC,
+        "File is not a retrieval index"
+    );
+    let version = take(&mut bytes, 1)?[0];
+    anyhow::ensure!(
+        version == INDEX_VERSION,
+        "Unsupported index version {version}"
+    );
+    let len = take_u32(&mut bytes)?;
+    let indexed_model = std::str::from_utf8(take(&mut bytes, len)?)?;
+    anyhow::ensure!(
+        indexed_model == model_name,
+        "Index was embedded by '{indexed_model}' instead of '{model_name}'"
+    );
+
+    let mut conversations: HashMap<_, Vec<Entry>> = HashMap::new();
+    while !bytes.is_empty() {
+        let channel_id = u64::from_le_bytes(take(&mut bytes, 8)?.try_into()?);
+        let channel_id = Id::new_checked(channel_id).context("Invalid channel id")?;
+        let len = take_u32(&mut bytes)?;
+        let text = String::from_utf8(take(&mut bytes, len)?.to_vec())?;
+        let dimensions = take_u32(&mut bytes)?;
+        let embedding = take(&mut bytes, dimensions * 4)?
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().expect("chunks are 4 bytes")))
+            .collect();
+        conversations
+            .entry(channel_id)
+            .or_default()
+            .push(Entry { text, embedding });
+    }
+    Ok(conversations)
+}
+
+/// The indices of the `top_k` entries most similar to the query, in ascending order.
+fn most_similar(entries: &[Entry], query: &[f32], top_k: usize) -> Vec<usize> {
+    let mut scored: Vec<(usize, f32)> = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| (index, cosine_similarity(&entry.embedding, query)))
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.truncate(top_k);
+
+    let mut indices: Vec<usize> = scored.into_iter().map(|(index, _)| index).collect();
+    indices.sort_unstable();
+    indices
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// The text of a user or assistant message. Other messages, like summaries, aren't recalled.
+fn message_text(message: &ChatCompletionRequestMessage) -> Option<String> {
+    let text = match message {
+        ChatCompletionRequestMessage::User(msg) => match &msg.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestUserMessageContentPart::Text(text) => {
+                        Some(text.text.as_str())
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        },
+        ChatCompletionRequestMessage::Assistant(msg) => match &msg.content {
+            Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => {
+                format!("[assistant] {text}")
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (!text.trim().is_empty()).then_some(text)
+}
+
+/// The text of the latest user message, which the recalled messages are chosen by.
+pub fn latest_query<'a>(
+    history: impl DoubleEndedIterator<Item = &'a ChatCompletionRequestMessage>,
+) -> Option<String> {
+    history
+        .rev()
+        .find(|message| matches!(message, ChatCompletionRequestMessage::User(_)))
+        .and_then(message_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The most similar entries must be chosen, and kept in the order they were sent.
+    #[test]
+    fn most_similar_in_order() {
+        let entries: Vec<Entry> = [[1.0, 0.0], [0.0, 1.0], [0.9, 0.1], [-1.0, 0.0]]
+            .into_iter()
+            .map(|embedding| Entry {
+                text: String::new(),
+                embedding: embedding.to_vec(),
+            })
+            .collect();
+
+        assert_eq!(most_similar(&entries, &[1.0, 0.0], 2), vec![0, 2]);
+        assert_eq!(most_similar(&entries, &[0.0, 1.0], 1), vec![1]);
+        assert_eq!(most_similar(&entries, &[1.0, 0.0], 10).len(), 4);
+    }
+
+    /// A saved index must decode to the same entries, but not when another model is configured.
+    #[test]
+    fn index_round_trip() {
+        let mut conversations = HashMap::new();
+        conversations.insert(
+            Id::new(1),
+            vec![
+                Entry {
+                    text: "How do I use lifetimes?".to_string(),
+                    embedding: vec![0.25, -1.5, 3.0],
+                },
+                Entry {
+                    text: "[assistant] Like this".to_string(),
+                    embedding: vec![1.0, 0.0, 0.0],
+                },
+            ],
+        );
+        conversations.insert(
+            Id::new(2),
+            vec![Entry {
+                text: "🦀".to_string(),
+                embedding: vec![0.5, 0.5, 0.5],
+            }],
+        );
+
+        let bytes = encode(&conversations, "text-embedding-3-small");
+        let decoded = decode(&bytes, "text-embedding-3-small").expect("Unable to decode index");
+        assert_eq!(decoded, conversations);
+
+        assert!(decode(&bytes, "text-embedding-3-large").is_err());
+        assert!(decode(&bytes[..bytes.len() - 1], "text-embedding-3-small").is_err());
+    }
+}
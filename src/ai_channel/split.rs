@@ -0,0 +1,205 @@
+/// The characters needed to close a code block at the end of a chunk.
+const FENCE_CLOSE: &str = "\n```";
+
+/// Split a message into chunks of at most `limit` characters.
+///
+/// Chunks are split on paragraph boundaries when possible, otherwise on line boundaries. Lines that
+/// are longer than `limit` are split wherever they need to be. When a chunk ends inside of a fenced
+/// code block, the code block is closed and re-opened in the next chunk so the formatting is kept.
+pub fn split_message(content: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    // The line that opened the code block the end of the chunk is in, if any.
+    let mut open_fence: Option<&str> = None;
+    // The byte index of the last paragraph break in the chunk that is outside of a code block.
+    let mut paragraph_break: Option<usize> = None;
+
+    for line in content.split_inclusive('\n') {
+        let is_fence = line.trim_start().starts_with("```");
+        let fence_after_line = match (is_fence, open_fence) {
+            (true, Some(_)) => None,
+            (true, None) => Some(line.trim_end()),
+            (false, fence) => fence,
+        };
+        let reserved = match fence_after_line {
+            Some(_) => FENCE_CLOSE.len(),
+            None => 0,
+        };
+        let line_len = line.chars().count();
+
+        if char_len(&chunk) + line_len + reserved > limit && !chunk.trim().is_empty() {
+            let rest_len = paragraph_break.map(|idx| char_len(&chunk[idx..]));
+
+            match (paragraph_break, rest_len) {
+                // Prefer to move the current paragraph into the next chunk, as long as that doesn't
+                // create another chunk that is too long.
+                (Some(idx), Some(rest_len))
+                    if idx > 0 && rest_len + line_len + reserved <= limit =>
+                {
+                    let rest = chunk.split_off(idx);
+                    push_chunk(&mut chunks, &chunk);
+                    chunk = rest.trim_start_matches('\n').to_string();
+                }
+                _ => {
+                    if open_fence.is_some() {
+                        chunk.truncate(chunk.trim_end().len());
+                        chunk.push_str(FENCE_CLOSE);
+                    }
+                    push_chunk(&mut chunks, &chunk);
+                    chunk = reopen(open_fence, limit);
+                }
+            }
+            paragraph_break = None;
+        }
+
+        // Hard split lines that don't fit in a chunk by themselves. Every iteration takes at least
+        // one character of the line, so this ends even if the limit is tiny.
+        let mut line = line;
+        while !line.is_empty() && char_len(&chunk) + line.chars().count() + reserved > limit {
+            let available = limit.saturating_sub(char_len(&chunk) + reserved).max(1);
+            let split_at = line
+                .char_indices()
+                .nth(available)
+                .map(|(idx, _)| idx)
+                .unwrap_or(line.len());
+            let (head, tail) = line.split_at(split_at);
+            chunk.push_str(head);
+            line = tail;
+
+            if let Some(fence) = fence_after_line.or(open_fence) {
+                chunk.push_str(FENCE_CLOSE);
+                push_chunk(&mut chunks, &chunk);
+                chunk = reopen(Some(fence), limit);
+            } else {
+                push_chunk(&mut chunks, &chunk);
+                chunk = String::new();
+            }
+        }
+
+        if line.trim().is_empty() && fence_after_line.is_none() && !chunk.is_empty() {
+            paragraph_break = Some(chunk.len());
+        }
+        chunk.push_str(line);
+        open_fence = fence_after_line;
+    }
+
+    push_chunk(&mut chunks, &chunk);
+    chunks
+}
+
+/// Take at most the first `limit` characters of the content.
+pub fn truncate_chars(content: &str, limit: usize) -> &str {
+    match content.char_indices().nth(limit) {
+        Some((idx, _)) => &content[..idx],
+        None => content,
+    }
+}
+
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// Add a chunk to the list, ignoring chunks without any content.
+fn push_chunk(chunks: &mut Vec<String>, chunk: &str) {
+    let chunk = chunk.trim_end();
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk.to_string());
+    }
+}
+
+/// Start a new chunk, re-opening the code block if the previous chunk ended in one. The code block
+/// isn't re-opened if the fences alone don't leave room for any content within the limit.
+fn reopen(open_fence: Option<&str>, limit: usize) -> String {
+    match open_fence {
+        Some(fence) if char_len(fence) + 1 + FENCE_CLOSE.len() < limit => format!("{fence}\n"),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Messages that fit within the limit must not be changed.
+    #[test]
+    fn short_message_is_untouched() {
+        assert_eq!(split_message("hello\nworld", 2000), vec!["hello\nworld"]);
+    }
+
+    /// Every chunk must stay within the limit, and no content may be lost.
+    #[test]
+    fn chunks_within_limit() {
+        let content = "some words on a line\n".repeat(200);
+        let chunks = split_message(&content, 100);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 100, "chunk too long: {chunk:?}");
+        }
+        assert_eq!(chunks.join("\n"), content.trim_end());
+    }
+
+    /// Paragraphs should be kept together when they fit in a chunk.
+    #[test]
+    fn splits_on_paragraphs() {
+        let content = "first paragraph line one\nfirst paragraph line two\n\nsecond paragraph line one\nsecond paragraph line two";
+        let chunks = split_message(content, 60);
+
+        assert_eq!(
+            chunks,
+            vec![
+                "first paragraph line one\nfirst paragraph line two",
+                "second paragraph line one\nsecond paragraph line two"
+            ]
+        );
+    }
+
+    /// A code block that is split must be closed and re-opened with the same language.
+    #[test]
+    fn reopens_code_blocks() {
+        let content = format!(
+            "look at this:\n```rust\n{}```\ndone",
+            "let x = 1;\n".repeat(20)
+        );
+        let chunks = split_message(&content, 80);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 80, "chunk too long: {chunk:?}");
+            // Every chunk must have balanced code fences.
+            assert_eq!(chunk.matches("```").count() % 2, 0, "unbalanced: {chunk:?}");
+        }
+        assert!(chunks[1].starts_with("```rust\n"));
+    }
+
+    /// Splitting must finish even when the fences don't fit in the limit, without losing content.
+    #[test]
+    fn tiny_limit_with_code_block() {
+        let content = "```rust\nlet x = 1;\n```";
+        for limit in [1, 5, 10, 11, 12] {
+            let chunks = split_message(content, limit);
+            // The code block may be closed and re-opened between every chunk.
+            let text: String = chunks
+                .concat()
+                .replace(FENCE_CLOSE, "")
+                .replace("```rust", "")
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            assert!(
+                text.contains("letx=1;"),
+                "content lost at {limit}: {chunks:?}"
+            );
+        }
+    }
+
+    /// Lines longer than the limit must still be split.
+    #[test]
+    fn splits_long_lines() {
+        let content = "é".repeat(250);
+        let chunks = split_message(&content, 100);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), content);
+    }
+}
@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use async_openai::{
+    Client as AIClient,
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{ChatCompletionStreamOptions, CreateChatCompletionRequest},
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::time::Instant;
+use tracing::{debug, error};
+use twilight_http::Client;
+use twilight_model::id::{
+    Id,
+    marker::{ChannelMarker, MessageMarker},
+};
+
+use super::{
+    CHAT_ENDPOINT, raw_log, reasoning::split_inline_reasoning, split::truncate_chars, timed_out,
+    usage::TokenUsage,
+};
+
+/// How often the discord message is edited while the response is streamed in.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(750);
+
+/// A single chunk of a streamed response.
+///
+/// Like [`super::ChatCompletionResponse`], only the fields that are used are included as not all
+/// APIs return every field.
+#[derive(Debug, Deserialize)]
+struct ChatCompletionStreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only included in the last chunk.
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+    /// Only included in the last chunk of the choice.
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+    /// The thinking of reasoning models, see [`super::ResponseMessage`].
+    reasoning_content: Option<serde_json::Value>,
+    reasoning: Option<serde_json::Value>,
+}
+
+/// The result of streaming a response into a discord channel.
+pub struct StreamedResponse {
+    /// All of the content received from the LLM, even if an error occurred part way through.
+    pub content: String,
+    /// The discord message the response is being streamed into, if one has been created.
+    pub message_id: Option<Id<MessageMarker>>,
+    /// The tokens used to generate the response, if the api reported them.
+    pub usage: Option<TokenUsage>,
+    /// If the response was cut off by the maximum amount of response tokens.
+    pub truncated: bool,
+    /// The error that stopped the stream early, if any.
+    pub error: Option<anyhow::Error>,
+}
+
+/// Stream a response from the LLM api, posting and editing a discord message as the content
+/// arrives.
+///
+/// Only the first `max_chars` characters are shown while streaming. The caller is expected to do
+/// the final edit of the message once the whole response has been received.
+pub async fn stream_response(
+    client: &AIClient<OpenAIConfig>,
+    mut request: CreateChatCompletionRequest,
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+    reply_to: Option<Id<MessageMarker>>,
+    config: &super::Configuration,
+    max_chars: usize,
+) -> StreamedResponse {
+    let timeout = config.request_timeout();
+    request.stream = Some(true);
+    request.stream_options = Some(ChatCompletionStreamOptions {
+        include_usage: true,
+    });
+
+    let mut streamed = StreamedResponse {
+        content: String::new(),
+        message_id: None,
+        usage: None,
+        truncated: false,
+        error: None,
+    };
+    let body = match super::request_body(config, &request) {
+        Ok(body) => body,
+        Err(err) => {
+            streamed.error = Some(err);
+            return streamed;
+        }
+    };
+
+    raw_log::log_request(CHAT_ENDPOINT, &body);
+    let stream = tokio::time::timeout(
+        timeout,
+        client
+            .chat()
+            .create_stream_byot::<_, serde_json::Value>(body),
+    )
+    .await;
+    let mut stream = match stream {
+        Ok(Ok(stream)) => stream,
+        Err(_) => {
+            streamed.error = Some(timed_out(timeout));
+            return streamed;
+        }
+        Ok(Err(err)) => {
+            streamed.error = Some(anyhow::Error::new(err).context("LLM api returned an error"));
+            return streamed;
+        }
+    };
+
+    let allowed_mentions = config.allowed_mentions();
+    let mut last_update = Instant::now();
+    let mut shown_len = 0;
+    let mut reasoning = String::new();
+    loop {
+        // A stalled stream must not block the channel forever.
+        let chunk = match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => {
+                streamed.error = Some(timed_out(timeout));
+                break;
+            }
+        };
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            // Some APIs close the connection without sending `[DONE]` first.
+            Err(OpenAIError::StreamError(err)) if err.contains("Stream ended") => break,
+            Err(err) => {
+                streamed.error = Some(anyhow::Error::new(err).context("LLM api stream failed"));
+                break;
+            }
+        };
+        raw_log::log_chunk(CHAT_ENDPOINT, &chunk);
+        let chunk: ChatCompletionStreamChunk = match serde_json::from_value(chunk) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                streamed.error =
+                    Some(anyhow::Error::new(err).context("Failed to parse the LLM api stream"));
+                break;
+            }
+        };
+
+        if chunk.usage.is_some() {
+            streamed.usage = chunk.usage;
+        }
+
+        for choice in chunk.choices.into_iter().take(1) {
+            if let Some(content) = choice.delta.content {
+                streamed.content.push_str(&content);
+            }
+            if choice.finish_reason.as_deref() == Some("length") {
+                streamed.truncated = true;
+            }
+            let delta_reasoning = [choice.delta.reasoning_content, choice.delta.reasoning]
+                .into_iter()
+                .find_map(|reasoning| reasoning?.as_str().map(str::to_string));
+            if let Some(delta_reasoning) = delta_reasoning {
+                reasoning.push_str(&delta_reasoning);
+            }
+        }
+
+        if last_update.elapsed() < UPDATE_INTERVAL || streamed.content.len() == shown_len {
+            continue;
+        }
+
+        // Don't show the response while it could still turn into the no response marker.
+        let trimmed = split_inline_reasoning(&streamed.content).1.trim();
+        if config.no_response_marker.starts_with(trimmed) {
+            continue;
+        }
+
+        let shown = truncate_chars(trimmed, max_chars);
+        match streamed.message_id {
+            Some(message_id) => {
+                if let Err(err) = http
+                    .update_message(channel_id, message_id)
+                    .allowed_mentions(Some(&allowed_mentions))
+                    .content(Some(shown))
+                    .await
+                {
+                    error!("Failed to update streamed response message: {err}");
+                }
+            }
+            None => {
+                let mut create_message = http
+                    .create_message(channel_id)
+                    .allowed_mentions(Some(&allowed_mentions))
+                    .content(shown);
+                if let Some(message_id) = reply_to {
+                    create_message = create_message.reply(message_id).fail_if_not_exists(false);
+                }
+
+                streamed.message_id = match create_message.await {
+                    Ok(res) => res.model().await.ok().map(|msg| msg.id),
+                    Err(err) => {
+                        error!("Failed to send streamed response message: {err}");
+                        None
+                    }
+                };
+            }
+        }
+
+        shown_len = streamed.content.len();
+        last_update = Instant::now();
+    }
+
+    // The reasoning is only useful for troubleshooting, it isn't posted or kept in the history.
+    let (inline_reasoning, answer) = split_inline_reasoning(&streamed.content);
+    if let Some(inline_reasoning) = inline_reasoning {
+        reasoning.push_str(inline_reasoning);
+    }
+    streamed.content = answer.to_string();
+    if !reasoning.is_empty() {
+        debug!("Model reasoning: {reasoning}");
+    }
+
+    streamed
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use async_openai::{
+    Client as AIClient,
+    config::OpenAIConfig,
+    types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs},
+};
+
+use super::{create_chat_completion, reasoning::split_inline_reasoning, split::truncate_chars};
+
+/// Starts the system message that holds the summary in the history.
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation in the channel:";
+
+/// The instructions given to the LLM when summarizing.
+const SUMMARY_PROMPT: &str = "You summarize discord conversations. Write a short summary of the conversation, keeping who said what, open questions and any decisions that were made. If the conversation starts with a summary of an even earlier part of the conversation, include the important parts of it in your summary. Respond with only the summary.";
+
+/// The maximum amount of tokens the LLM may use for a summary.
+const SUMMARY_MAX_TOKENS: u32 = 300;
+
+/// The maximum amount of characters of the summary to keep, in case the API ignores the token limit.
+const SUMMARY_MAX_CHARS: usize = 2000;
+
+/// Summarize the messages using the LLM.
+pub async fn summarize(
+    client: &AIClient<OpenAIConfig>,
+    model_name: &str,
+    messages: Vec<ChatCompletionRequestMessage>,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    let messages: Vec<_> = [ChatCompletionRequestMessage::System(SUMMARY_PROMPT.into())]
+        .into_iter()
+        .chain(messages)
+        .chain([ChatCompletionRequestMessage::User(
+            "Summarize the conversation above.".into(),
+        )])
+        .collect();
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model_name)
+        .max_tokens(SUMMARY_MAX_TOKENS)
+        .messages(messages)
+        .build()
+        .context("Failed to build summary request")?;
+
+    let response = create_chat_completion(client, request, timeout).await?;
+    let summary = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .context("LLM summary did not include message content")?;
+    let summary = split_inline_reasoning(&summary).1;
+
+    Ok(truncate_chars(summary.trim(), SUMMARY_MAX_CHARS).to_string())
+}
@@ -0,0 +1,165 @@
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestSystemMessageContentPart,
+    ChatCompletionRequestToolMessageContent, ChatCompletionRequestToolMessageContentPart,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+    ImageDetail,
+};
+use serde::Deserialize;
+
+/// The amount of tokens each message costs on top of its content, for the role and separators.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// The amount of tokens a low detail image costs.
+const TOKENS_PER_IMAGE: usize = 85;
+
+/// The amount of tokens a high detail image costs, assuming it is split into four tiles. The API
+/// may choose high detail when the detail is set to auto.
+const TOKENS_PER_HIGH_DETAIL_IMAGE: usize = TOKENS_PER_IMAGE + 4 * 170;
+
+/// The method used to estimate how many tokens the chat history takes up.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    /// Assumes every token is roughly four characters long, which is close enough for english text
+    /// with the OpenAI tokenizers. Code and other languages will usually use more tokens.
+    CharEstimate,
+}
+
+impl Tokenizer {
+    /// Estimate the amount of tokens in a piece of text.
+    pub fn text_token_count(&self, text: &str) -> usize {
+        match self {
+            Tokenizer::CharEstimate => text.chars().count().div_ceil(4),
+        }
+    }
+
+    /// Estimate the amount of tokens a single message uses when sent to the LLM.
+    pub fn message_token_count(&self, message: &ChatCompletionRequestMessage) -> usize {
+        let content = match message {
+            ChatCompletionRequestMessage::Developer(msg) => match &msg.content {
+                ChatCompletionRequestDeveloperMessageContent::Text(text) => {
+                    self.text_token_count(text)
+                }
+                ChatCompletionRequestDeveloperMessageContent::Array(parts) => parts
+                    .iter()
+                    .map(|part| self.text_token_count(&part.text))
+                    .sum(),
+            },
+            ChatCompletionRequestMessage::System(msg) => match &msg.content {
+                ChatCompletionRequestSystemMessageContent::Text(text) => {
+                    self.text_token_count(text)
+                }
+                ChatCompletionRequestSystemMessageContent::Array(parts) => parts
+                    .iter()
+                    .map(
+                        |ChatCompletionRequestSystemMessageContentPart::Text(part)| {
+                            self.text_token_count(&part.text)
+                        },
+                    )
+                    .sum(),
+            },
+            ChatCompletionRequestMessage::User(msg) => match &msg.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => self.text_token_count(text),
+                ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                    .iter()
+                    .map(|part| match part {
+                        ChatCompletionRequestUserMessageContentPart::Text(part) => {
+                            self.text_token_count(&part.text)
+                        }
+                        ChatCompletionRequestUserMessageContentPart::ImageUrl(image) => {
+                            match image.image_url.detail {
+                                Some(ImageDetail::Low) => TOKENS_PER_IMAGE,
+                                _ => TOKENS_PER_HIGH_DETAIL_IMAGE,
+                            }
+                        }
+                        ChatCompletionRequestUserMessageContentPart::InputAudio(_) => 0,
+                    })
+                    .sum(),
+            },
+            ChatCompletionRequestMessage::Assistant(msg) => match &msg.content {
+                Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => {
+                    self.text_token_count(text)
+                }
+                Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => parts
+                    .iter()
+                    .map(|part| match part {
+                        ChatCompletionRequestAssistantMessageContentPart::Text(part) => {
+                            self.text_token_count(&part.text)
+                        }
+                        ChatCompletionRequestAssistantMessageContentPart::Refusal(part) => {
+                            self.text_token_count(&part.refusal)
+                        }
+                    })
+                    .sum(),
+                None => 0,
+            },
+            ChatCompletionRequestMessage::Tool(msg) => match &msg.content {
+                ChatCompletionRequestToolMessageContent::Text(text) => self.text_token_count(text),
+                ChatCompletionRequestToolMessageContent::Array(parts) => parts
+                    .iter()
+                    .map(|ChatCompletionRequestToolMessageContentPart::Text(part)| {
+                        self.text_token_count(&part.text)
+                    })
+                    .sum(),
+            },
+            ChatCompletionRequestMessage::Function(msg) => msg
+                .content
+                .as_deref()
+                .map(|text| self.text_token_count(text))
+                .unwrap_or(0),
+        };
+
+        content + TOKENS_PER_MESSAGE
+    }
+
+    /// Estimate the amount of tokens the whole history uses when sent to the LLM.
+    pub fn history_token_count<'a>(
+        &self,
+        history: impl IntoIterator<Item = &'a ChatCompletionRequestMessage>,
+    ) -> usize {
+        history
+            .into_iter()
+            .map(|msg| self.message_token_count(msg))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Long messages must count for more than short ones, so a few code pastes can't blow the
+    /// context window while many one-liners don't.
+    #[test]
+    fn long_messages_cost_more() {
+        let tokenizer = Tokenizer::CharEstimate;
+
+        let short = ChatCompletionRequestMessage::User("ok".into());
+        let long = ChatCompletionRequestMessage::User("fn main() {}\n".repeat(100).into());
+
+        assert!(tokenizer.message_token_count(&long) > tokenizer.message_token_count(&short) * 50);
+    }
+
+    /// The history count must be the sum of its messages.
+    #[test]
+    fn history_is_sum_of_messages() {
+        let tokenizer = Tokenizer::CharEstimate;
+
+        let history = VecDeque::from([
+            ChatCompletionRequestMessage::User("hello there".into()),
+            ChatCompletionRequestMessage::Assistant("general kenobi".into()),
+        ]);
+
+        assert_eq!(
+            tokenizer.history_token_count(&history),
+            history
+                .iter()
+                .map(|msg| tokenizer.message_token_count(msg))
+                .sum::<usize>()
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::{ops::AddAssign, path::Path};
+
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{
+    Id,
+    marker::{ChannelMarker, UserMarker},
+};
+
+use super::error_log::append_line_in_background;
+
+/// The amount of tokens used to generate a response, as reported by the LLM api.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Estimate the cost of the tokens in dollars, using the price per 1000 tokens.
+    pub fn cost(&self, cost_per_1k_prompt: f64, cost_per_1k_completion: f64) -> f64 {
+        self.prompt_tokens as f64 / 1000.0 * cost_per_1k_prompt
+            + self.completion_tokens as f64 / 1000.0 * cost_per_1k_completion
+    }
+
+    /// Split the tokens between shares with the weights, rounding down. The last share gets what
+    /// is left, so the shares add up to the usage.
+    pub fn split(&self, weights: &[u64]) -> Vec<TokenUsage> {
+        let total_weight: u64 = weights.iter().sum();
+        let mut left = *self;
+        let mut shares: Vec<TokenUsage> = weights
+            .iter()
+            .map(|&weight| {
+                let share = |tokens: u64| {
+                    if total_weight == 0 {
+                        0
+                    } else {
+                        (u128::from(tokens) * u128::from(weight) / u128::from(total_weight)) as u64
+                    }
+                };
+                let share = TokenUsage {
+                    prompt_tokens: share(self.prompt_tokens),
+                    completion_tokens: share(self.completion_tokens),
+                    total_tokens: share(self.total_tokens),
+                };
+                left.prompt_tokens -= share.prompt_tokens;
+                left.completion_tokens -= share.completion_tokens;
+                left.total_tokens -= share.total_tokens;
+                share
+            })
+            .collect();
+        if let Some(last) = shares.last_mut() {
+            *last += left;
+        }
+        shares
+    }
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.total_tokens += rhs.total_tokens;
+    }
+}
+
+/// How the tokens of a response are attributed to the users whose messages triggered it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CostAttribution {
+    /// Tokens aren't attributed to users.
+    #[default]
+    Off,
+    /// Split between the authors of the messages that triggered the response, by the amount of
+    /// messages each of them sent.
+    Split,
+    /// Attributed to the author of the latest message that triggered the response.
+    Trigger,
+}
+
+/// The tokens of a response attributed to a user, written as a line of JSON to the usage log.
+#[derive(Debug, Serialize)]
+pub struct UserUsage {
+    /// When the response was generated, in ISO 8601.
+    pub timestamp: String,
+    /// The channel or thread the response was sent in.
+    pub channel_id: Id<ChannelMarker>,
+    pub user_id: Id<UserMarker>,
+    pub user_name: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// The estimated cost of the tokens in dollars, if the prices are configured.
+    pub cost: Option<f64>,
+    /// The tokens attributed to the user since the bot started.
+    pub user_total_tokens: u64,
+    /// The estimated cost of the tokens attributed to the user since the bot started.
+    pub user_total_cost: Option<f64>,
+}
+
+impl UserUsage {
+    /// Append the usage to the log file in the background. Failing to write it is only logged.
+    pub fn append_to(self, path: &Path) {
+        append_line_in_background(path, self, "usage");
+    }
+}
+
+/// The users the tokens of a response are attributed to, with their share. `triggers` are the
+/// ids and names of the authors of the messages that triggered the response, oldest first.
+pub fn attribute<'a>(
+    mode: CostAttribution,
+    usage: &TokenUsage,
+    triggers: &[(Id<UserMarker>, &'a str)],
+) -> Vec<(Id<UserMarker>, &'a str, TokenUsage)> {
+    match mode {
+        CostAttribution::Off => Vec::new(),
+        CostAttribution::Trigger => triggers
+            .last()
+            .map(|&(user_id, user_name)| (user_id, user_name, *usage))
+            .into_iter()
+            .collect(),
+        CostAttribution::Split => {
+            let mut users: Vec<(Id<UserMarker>, &str, u64)> = Vec::new();
+            for &(user_id, user_name) in triggers {
+                match users.iter_mut().find(|(id, ..)| *id == user_id) {
+                    Some((.., messages)) => *messages += 1,
+                    None => users.push((user_id, user_name, 1)),
+                }
+            }
+            let weights: Vec<u64> = users.iter().map(|(.., messages)| *messages).collect();
+            users
+                .into_iter()
+                .zip(usage.split(&weights))
+                .map(|((user_id, user_name, _), share)| (user_id, user_name, share))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Prompt and completion tokens must be priced separately.
+    #[test]
+    fn cost() {
+        let usage = TokenUsage {
+            prompt_tokens: 2000,
+            completion_tokens: 500,
+            total_tokens: 2500,
+        };
+
+        assert_eq!(usage.cost(0.5, 2.0), 2.0);
+    }
+
+    /// Split tokens must go to each author by their amount of messages, and add up to the usage.
+    #[test]
+    fn attribute_split() {
+        let usage = TokenUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 101,
+            total_tokens: 1101,
+        };
+        let triggers = [
+            (Id::new(1), "ferris"),
+            (Id::new(2), "corro"),
+            (Id::new(1), "ferris"),
+        ];
+
+        let split = attribute(CostAttribution::Split, &usage, &triggers);
+        assert_eq!(split.len(), 2);
+        assert_eq!((split[0].0, split[0].2.prompt_tokens), (Id::new(1), 666));
+        assert_eq!((split[1].0, split[1].2.prompt_tokens), (Id::new(2), 334));
+        let mut total = TokenUsage::default();
+        split.iter().for_each(|(.., share)| total += *share);
+        assert_eq!(total, usage);
+
+        let triggered = attribute(CostAttribution::Trigger, &usage, &triggers);
+        assert_eq!(triggered.len(), 1);
+        assert_eq!((triggered[0].0, triggered[0].2), (Id::new(1), usage));
+        assert!(attribute(CostAttribution::Off, &usage, &triggers).is_empty());
+    }
+}
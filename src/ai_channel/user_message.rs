@@ -1,26 +1,64 @@
-use std::{io::Cursor, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Cursor,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
+use anyhow::Context;
 use async_openai::types::{
-    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestUserMessage,
-    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
-    ImageDetail, ImageUrl,
+    ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, ImageDetail, ImageUrl,
 };
 use base64::{Engine, prelude::BASE64_STANDARD};
-use image::{GenericImageView, ImageFormat, ImageReader, imageops::FilterType};
-use tokio::sync::{broadcast, mpsc};
-use tracing::error;
+use image::{
+    GenericImageView, ImageError, ImageFormat, ImageReader, codecs::jpeg::JpegEncoder,
+    imageops::FilterType,
+};
+use regex::Regex;
+use serde::Deserialize;
+use tokio::{
+    select,
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
 use twilight_gateway::Event;
+use twilight_http::Client;
 use twilight_model::{
+    application::interaction::{Interaction, application_command::CommandData},
+    channel::{Channel, Message, message::EmojiReactionType},
+    gateway::{GatewayReaction, payload::incoming::GuildCreate},
+    guild::Permissions,
     id::{
         Id,
-        marker::{ChannelMarker, MessageMarker, UserMarker},
+        marker::{ChannelMarker, MessageMarker, UserMarker, WebhookMarker},
     },
+    user::User,
     util::Timestamp,
 };
 
+use super::{
+    attachments::{self, FileAttachment},
+    code_blocks::{CodeBlockLimits, truncate_code_blocks},
+    commands,
+    feedback::ResponseFeedback,
+    image_cache::ImageCache,
+    mentions::MentionNames,
+    raw_log, reactions,
+    split::truncate_chars,
+};
+
 #[derive(Debug)]
 pub struct UserMessage {
     pub message_id: Id<MessageMarker>,
+    /// The channel or thread the message was sent in.
+    pub channel_id: Id<ChannelMarker>,
     pub reply_to: Option<Id<MessageMarker>>,
     pub content: String,
     pub sender_name: String,
@@ -28,13 +66,289 @@ pub struct UserMessage {
     pub sender_id: Id<UserMarker>,
     pub sent_at: Timestamp,
     pub images: Vec<String>,
+    /// The files attached to the message that aren't images.
+    pub attachments: Vec<FileAttachment>,
+    /// The message being replied to, which may no longer be in the history.
+    pub quoted: Option<QuotedMessage>,
+    /// If the message should cause the bot to respond, otherwise it is only used as context.
+    pub triggers_response: bool,
+    /// If the message mentions the bot, explicitly asking it for a response.
+    pub mentions_bot: bool,
+    /// If `cooldown_emoji` was added to the message, as it was sent during the response cooldown.
+    pub cooldown_reacted: bool,
+}
+
+#[derive(Debug)]
+pub struct QuotedMessage {
+    pub author_name: String,
+    pub content: String,
+}
+
+impl QuotedMessage {
+    /// Format the message as a quote to put above the reply, e.g. "> author: text".
+    fn format_quote(&self) -> String {
+        let mut content = truncate_chars(&self.content, MAX_QUOTE_CHARS).to_string();
+        if content.len() < self.content.len() {
+            content.push('…');
+        }
+        format!(
+            "> {}: {}\n",
+            self.author_name,
+            content.replace('\n', "\n> ")
+        )
+    }
+}
+
+/// The maximum amount of characters of a replied to message to include with the reply.
+const MAX_QUOTE_CHARS: usize = 300;
+
+/// Matches custom discord emojis, e.g. `<:ferris:123>` or `<a:ferris:123>` if animated.
+static CUSTOM_EMOJI: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<a?:\w+:\d+>").expect("custom emoji regex is valid"));
+
+/// Sent in place of images that were left out, so the LLM knows there was an image.
+const OMITTED_IMAGE: &str = "[image omitted]";
+
+/// Sent in place of images that failed to download or decode.
+const UNLOADED_IMAGE: &str = "[image could not be loaded]";
+
+/// What to do with new events when the queue of events waiting to be handled is full.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflow {
+    /// Drop the new events.
+    #[default]
+    DropNewest,
+    /// Keep the new events, dropping the oldest events that are waiting to enter the queue.
+    DropOldest,
+    /// Wait for room in the queue. Events from discord may be missed while waiting.
+    Block,
+}
+
+/// How the time a message was sent is included in the message sent to the LLM.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// Not included, which saves a few tokens per message.
+    None,
+    /// The date and time the message was sent.
+    #[default]
+    Absolute,
+    /// The time since the previous message, e.g. "5m after the previous message". Messages without
+    /// a known previous message use the absolute time instead.
+    Relative,
+}
+
+/// The filter used to downsize images.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    /// The fastest, but produces jagged edges.
+    Nearest,
+    /// A good balance between speed and quality.
+    #[default]
+    Triangle,
+    /// The sharpest, which keeps small text like in screenshots of code readable.
+    Lanczos,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// The format images are re-encoded in before they are sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageOutput {
+    /// Smaller, but lossy with the configured quality.
+    #[default]
+    Jpeg,
+    /// Lossless, which keeps text sharp but is larger.
+    Png,
+}
+
+impl ImageOutput {
+    fn mime_type(self) -> &'static str {
+        match self {
+            ImageOutput::Jpeg => "image/jpeg",
+            ImageOutput::Png => "image/png",
+        }
+    }
+}
+
+/// How images are downsized and re-encoded.
+#[derive(Debug, Clone, Copy)]
+struct ImageEncoding {
+    max_dim: u32,
+    filter: ResizeFilter,
+    output: ImageOutput,
+    jpeg_quality: u8,
+}
+
+impl ImageEncoding {
+    fn from_config(config: &super::Configuration) -> Self {
+        Self {
+            max_dim: config.max_image_size,
+            filter: config.image_resize_filter,
+            output: config.image_format,
+            jpeg_quality: config.image_jpeg_quality,
+        }
+    }
+}
+
+/// An event in the channel that the AI channel needs to handle.
+#[derive(Debug)]
+pub enum QueuedEvent {
+    /// A new message was sent.
+    Message(UserMessage),
+    /// A message was edited, this contains the new version of the message.
+    Edit(UserMessage),
+    /// Messages were deleted. These can be sent by users or the bot.
+    Delete {
+        channel_id: Id<ChannelMarker>,
+        ids: Vec<Id<MessageMarker>>,
+    },
+    /// A moderator asked for the history of the channel to be cleared.
+    Clear {
+        channel_id: Id<ChannelMarker>,
+        /// If the prompt file should be read again.
+        reload_prompt: bool,
+    },
+    /// A user asked for the response in the message to be regenerated.
+    Regenerate {
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    },
+    /// A user asked for the truncated response in the message to be continued.
+    Continue {
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    },
+    /// A user reacted to a response with one of the feedback emojis.
+    Feedback(ResponseFeedback),
+}
+
+impl QueuedEvent {
+    /// Show that the queued message will be responded to, and if it waits for the cooldown. This
+    /// is only done once the message is queued, so dropped messages aren't left marked.
+    fn add_reactions(&self, http: &Arc<Client>, config: &super::Configuration) {
+        let QueuedEvent::Message(msg) = self else {
+            return;
+        };
+        if msg.triggers_response && !config.seen_emoji.is_empty() {
+            reactions::add_reaction(
+                http,
+                msg.channel_id,
+                msg.message_id,
+                config.seen_emoji.clone(),
+            );
+        }
+        if msg.cooldown_reacted {
+            reactions::add_reaction(
+                http,
+                msg.channel_id,
+                msg.message_id,
+                config.cooldown_emoji.clone(),
+            );
+        }
+    }
 }
 
 impl UserMessage {
-    /// Serialize the message into the format expected by the LLM.
-    pub fn format_message(&self) -> String {
+    /// Remove the reactions added when the message was queued, as it won't be responded to.
+    pub fn remove_reactions(&self, http: &Arc<Client>, config: &super::Configuration) {
+        if self.triggers_response {
+            reactions::SeenMessages {
+                channel_id: self.channel_id,
+                message_ids: vec![self.message_id],
+            }
+            .finish(http, config, false);
+        }
+        if self.cooldown_reacted {
+            reactions::remove_reactions(
+                http,
+                config.cooldown_emoji.clone(),
+                vec![(self.channel_id, self.message_id)],
+            );
+        }
+    }
+
+    /// Create the message, removing mentions of the bot from the content as they only get the
+    /// bot's attention and would confuse the LLM.
+    ///
+    /// If `names` are given, the remaining mentions are replaced by names.
+    fn from_message(
+        message: &Message,
+        bot_id: Id<UserMarker>,
+        names: Option<&MentionNames>,
+        triggers_response: bool,
+    ) -> Self {
+        let content = strip_mention(&message.content, bot_id);
+
+        Self {
+            message_id: message.id,
+            channel_id: message.channel_id,
+            reply_to: message.reference.as_ref().and_then(|r| r.message_id),
+            content: match names {
+                Some(names) => names.resolve(&content, &message.mentions),
+                None => content,
+            },
+            sender_name: message.author.name.clone(),
+            sender_id: message.author.id,
+            sent_at: message.timestamp,
+            sender_display_name: message
+                .member
+                .as_ref()
+                .and_then(|m| m.nick.clone())
+                .or_else(|| message.author.global_name.clone()),
+            images: message
+                .attachments
+                .iter()
+                .filter(|a| attachments::is_image(a))
+                .map(|a| a.url.clone())
+                .collect(),
+            attachments: message
+                .attachments
+                .iter()
+                .filter(|a| !attachments::is_image(a))
+                .map(FileAttachment::from)
+                .collect(),
+            quoted: message
+                .referenced_message
+                .as_ref()
+                .map(|referenced| QuotedMessage {
+                    author_name: referenced
+                        .author
+                        .global_name
+                        .clone()
+                        .unwrap_or_else(|| referenced.author.name.clone()),
+                    content: match names {
+                        Some(names) => names.resolve(&referenced.content, &referenced.mentions),
+                        None => referenced.content.clone(),
+                    },
+                }),
+            triggers_response,
+            mentions_bot: message.mentions.iter().any(|user| user.id == bot_id),
+            cooldown_reacted: false,
+        }
+    }
+
+    /// Serialize the message into the format expected by the LLM, with the already formatted
+    /// attachments after the content.
+    pub fn format_message(
+        &self,
+        content: &str,
+        attachments: &[String],
+        timestamp: Option<&str>,
+    ) -> String {
         format!(
-            "<msg>message_id: {}\n{}author_name: {}\nauthor_id: {}{}\nsent_at: {}\n{}</msg>",
+            "<msg>message_id: {}\n{}author_name: {}{}\nauthor_id: {}\n{}{}{}{}</msg>",
             self.message_id,
             match self.reply_to {
                 Some(id) => format!("repling_to: {id}\n"),
@@ -46,46 +360,130 @@ impl UserMessage {
                 None => String::new(),
             },
             self.sender_id,
-            self.sent_at.iso_8601(),
-            self.content
+            match timestamp {
+                Some(timestamp) => format!("sent_at: {timestamp}\n"),
+                None => String::new(),
+            },
+            match &self.quoted {
+                Some(quoted) => quoted.format_quote(),
+                None => String::new(),
+            },
+            content,
+            attachments
+                .iter()
+                .map(|attachment| format!("\n{attachment}"))
+                .collect::<String>()
         )
     }
 
+    /// Format the time the message was sent, relative to `previous_sent_at` if that is configured.
+    fn timestamp(
+        &self,
+        format: TimestampFormat,
+        previous_sent_at: Option<Timestamp>,
+    ) -> Option<String> {
+        match (format, previous_sent_at) {
+            (TimestampFormat::None, _) => None,
+            (TimestampFormat::Relative, Some(previous)) => {
+                let secs = (self.sent_at.as_secs() - previous.as_secs()).max(0) as u64;
+                Some(format!("{} after the previous message", format_gap(secs)))
+            }
+            _ => Some(self.sent_at.iso_8601().to_string()),
+        }
+    }
+
     /// Encode the message into the format excpected by the LLM api.
+    ///
+    /// `previous_sent_at` is the time the previous message in the conversation was sent, if it is
+    /// known. Attachments and images are downloaded with `download_http`.
     pub async fn as_chat_completion_message(
         &self,
         config: &super::Configuration,
+        download_http: &reqwest::Client,
+        image_cache: &mut ImageCache,
+        previous_sent_at: Option<Timestamp>,
     ) -> ChatCompletionRequestUserMessage {
+        let timestamp = self.timestamp(config.include_timestamps, previous_sent_at);
+        let content = truncate_code_blocks(&self.content, &CodeBlockLimits::from_config(config));
+        let mut attachments = Vec::new();
+        if config.max_attachment_bytes > 0 {
+            for attachment in &self.attachments {
+                attachments.push(
+                    attachment
+                        .format(download_http, config.max_attachment_bytes)
+                        .await,
+                );
+            }
+        }
+
         if !config.image_support {
             // Not using the content parts ensures maximum compatibility.
-            return self.format_message().into();
+            return self
+                .format_message(&content, &attachments, timestamp.as_deref())
+                .into();
         }
 
         let mut content = vec![ChatCompletionRequestUserMessageContentPart::Text(
-            self.format_message().into(),
+            self.format_message(&content, &attachments, timestamp.as_deref())
+                .into(),
         )];
 
-        for image in &self.images {
-            let image_b64 = match b64_encode_image(image, config.max_image_size).await {
+        let max_images = config.max_images_per_message.unwrap_or(usize::MAX);
+        for (i, image) in self.images.iter().enumerate() {
+            if i >= max_images {
+                content.push(ChatCompletionRequestUserMessageContentPart::Text(
+                    OMITTED_IMAGE.into(),
+                ));
+                continue;
+            }
+
+            if let Some(image_b64) = image_cache.get(image) {
+                content.push(image_content_part(
+                    image_b64,
+                    &config.image_detail,
+                    config.image_format,
+                ));
+                continue;
+            }
+
+            let encoding = ImageEncoding::from_config(config);
+            let image_b64 = match b64_encode_image(download_http, image, encoding).await {
                 Ok(v) => v,
+                // Don't propagate the error up: the CDN can have a hiccup, or users can upload
+                // anything with an image extension. The message is still worth responding to.
                 Err(err) => {
-                    // Don't propagate the error up: there are a lot of reasons why encoding the
-                    // image could go wrong, for example when the users povides an invalid image. It
-                    // seems better to let the chat continue without errors in this case.
-                    error!("Failed to encode image: {err:?}");
+                    warn!("Unable to load image, sending the message without it: {err:#}");
+                    content.push(ChatCompletionRequestUserMessageContentPart::Text(
+                        UNLOADED_IMAGE.into(),
+                    ));
                     continue;
                 }
             };
 
-            content.push(ChatCompletionRequestUserMessageContentPart::ImageUrl(
-                ChatCompletionRequestMessageContentPartImage {
-                    image_url: ImageUrl {
-                        url: format!("data:image/jpeg;base64,{image_b64}"),
-                        // Images can be very expensive in terms of tokens.
-                        detail: Some(ImageDetail::Low),
-                    },
-                },
+            content.push(image_content_part(
+                &image_b64,
+                &config.image_detail,
+                config.image_format,
             ));
+            image_cache.insert(image, image_b64);
+        }
+
+        // Without any images the message is sent as text, like when images aren't supported.
+        let has_images = content.iter().any(|part| {
+            matches!(
+                part,
+                ChatCompletionRequestUserMessageContentPart::ImageUrl(_)
+            )
+        });
+        if !has_images {
+            let text: Vec<_> = content
+                .into_iter()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestUserMessageContentPart::Text(part) => Some(part.text),
+                    _ => None,
+                })
+                .collect();
+            return text.join("\n").into();
         }
 
         ChatCompletionRequestUserMessage {
@@ -95,75 +493,935 @@ impl UserMessage {
     }
 }
 
-/// Queue incoming messages in a certain discord channel into a queue channel.
+/// Format the time between two messages, in the largest units that matter, e.g. "2h 5m".
+fn format_gap(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3_600, secs % 3_600 / 60);
+    if secs < 60 {
+        format!("{secs}s")
+    } else if secs < 3_600 {
+        format!("{minutes}m")
+    } else if secs < 86_400 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{days}d {hours}h")
+    }
+}
+
+/// Create the content part containing a JPEG image.
+fn image_content_part(
+    image_b64: &str,
+    detail: &ImageDetail,
+    output: ImageOutput,
+) -> ChatCompletionRequestUserMessageContentPart {
+    ChatCompletionRequestUserMessageContentPart::ImageUrl(
+        ChatCompletionRequestMessageContentPartImage {
+            image_url: ImageUrl {
+                url: format!("data:{};base64,{image_b64}", output.mime_type()),
+                detail: Some(detail.clone()),
+            },
+        },
+    )
+}
+
+/// Replace the oldest images in the messages with a note, so at most `max_images` are sent.
+pub fn omit_old_images(messages: &mut [ChatCompletionRequestMessage], max_images: usize) {
+    let parts = messages
+        .iter_mut()
+        .rev()
+        .filter_map(|message| match message {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Array(parts),
+                ..
+            }) => Some(parts),
+            _ => None,
+        })
+        .flat_map(|parts| parts.iter_mut().rev());
+
+    let mut images = 0;
+    for part in parts {
+        if matches!(
+            part,
+            ChatCompletionRequestUserMessageContentPart::ImageUrl(_)
+        ) {
+            images += 1;
+            if images > max_images {
+                *part = ChatCompletionRequestUserMessageContentPart::Text(OMITTED_IMAGE.into());
+            }
+        }
+    }
+}
+
+/// The maximum amount of messages discord returns for a single request.
+const MESSAGES_PER_REQUEST: usize = 100;
+
+/// Fetch the latest `limit` messages in the channel, oldest first, so the history doesn't start
+/// empty when the bot starts.
+///
+/// The bot's own messages become assistant messages, messages from other users are filtered the
+/// same way as new messages.
+pub async fn fetch_scrollback(
+    http: &Client,
+    config: &super::Configuration,
+    bot_id: Id<UserMarker>,
+    download_http: &reqwest::Client,
+    image_cache: &mut ImageCache,
+    limit: usize,
+) -> anyhow::Result<Vec<(Id<MessageMarker>, ChatCompletionRequestMessage)>> {
+    // Discord returns the newest messages first.
+    let mut messages: Vec<Message> = Vec::new();
+    while messages.len() < limit {
+        let page_size = (limit - messages.len()).min(MESSAGES_PER_REQUEST) as u16;
+        let request = http.channel_messages(config.channel_id);
+        let page = match messages.last() {
+            Some(oldest) => request.before(oldest.id).limit(page_size).await,
+            None => request.limit(page_size).await,
+        }
+        .context("Failed to get the messages in the channel")?
+        .models()
+        .await?;
+
+        let is_last_page = page.len() < page_size as usize;
+        messages.extend(page);
+        if is_last_page {
+            break;
+        }
+    }
+
+    let mut scrollback = Vec::with_capacity(messages.len());
+    let mut previous_sent_at = None;
+    for message in messages.iter().rev() {
+        if message.author.id == bot_id || is_webhook_response(config, message) {
+            // Error messages and other embeds aren't part of the conversation.
+            if !message.content.is_empty() {
+                scrollback.push((message.id, config.assistant_message(&message.content)));
+            }
+        } else if !is_ignored_author(config, bot_id, &message.author)
+            && is_allowed_author(config, message)
+        {
+            let user_message = UserMessage::from_message(message, bot_id, None, false);
+            scrollback.push((
+                message.id,
+                ChatCompletionRequestMessage::User(
+                    user_message
+                        .as_chat_completion_message(
+                            config,
+                            download_http,
+                            image_cache,
+                            previous_sent_at,
+                        )
+                        .await,
+                ),
+            ));
+            previous_sent_at = Some(user_message.sent_at);
+        }
+    }
+
+    Ok(scrollback)
+}
+
+/// The state published by `serve`, which handling the events depends on.
+pub struct ServeState {
+    pub status: watch::Receiver<commands::Status>,
+    /// When the response cooldown ends, so messages waiting for it can be marked.
+    pub cooldown_until: watch::Receiver<Instant>,
+    /// The webhook responses are posted through, so reactions to them can be recognized.
+    pub webhook_id: watch::Receiver<Option<Id<WebhookMarker>>>,
+}
+
+/// Queue incoming messages, and changes to them, in a certain discord channel into a queue channel.
 pub async fn queue_messages(
     mut events: broadcast::Receiver<Arc<Event>>,
-    queue: mpsc::Sender<UserMessage>,
-    channel_id: Id<ChannelMarker>,
+    queue: mpsc::Sender<QueuedEvent>,
+    config: Arc<super::Configuration>,
+    http: Arc<Client>,
+    bot_id: Id<UserMarker>,
+    state: ServeState,
 ) {
+    let per_user_cooldown = Duration::from_millis(config.per_user_cooldown_ms);
+    // The last time each user sent a message that triggered a response.
+    let mut last_triggered: HashMap<Id<UserMarker>, Instant> = HashMap::new();
+    let mut last_cleanup = Instant::now();
+    // The threads created from the channel.
+    let mut threads = HashSet::new();
+    let mut names = MentionNames::default();
+    // Only warn about empty messages once, as every message is empty when the content intent is
+    // missing.
+    let mut warned_empty = false;
+    // Events waiting for room in the queue, when `queue_overflow` is `drop_oldest`.
+    let mut overflow = VecDeque::new();
+
     loop {
-        let event = events.recv().await;
-        let message = match event.as_deref() {
+        let event = select! {
+            event = events.recv() => event,
+            // Move the waiting events into the queue as soon as there is room.
+            permit = queue.reserve(), if !overflow.is_empty() => {
+                let Ok(permit) = permit else {
+                    return;
+                };
+                if let Some(queued) = overflow.pop_front() {
+                    permit.send(queued);
+                }
+                continue;
+            }
+        };
+        let queued = match event.as_deref() {
             Err(broadcast::error::RecvError::Closed) => return,
-            Err(_) => continue,
-            Ok(Event::MessageCreate(msg)) => msg,
-            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Channel {} fell behind and skipped {skipped} discord events, consider increasing event_buffer_size",
+                    config.channel_id
+                );
+                continue;
+            }
+            Ok(Event::MessageCreate(message)) => {
+                if !is_in_channel(&config, &threads, message.channel_id)
+                    || is_ignored_author(&config, bot_id, &message.author)
+                    || is_webhook_response(&config, message)
+                    || !is_allowed_author(&config, message)
+                {
+                    continue;
+                }
+
+                // There is nothing in the message the LLM could respond to.
+                if message.content.is_empty() && message.attachments.is_empty() {
+                    if !warned_empty {
+                        warn!(
+                            "Ignoring a message without content in channel {}. If every message is empty, the bot is missing the \"Message Content Intent\", which can be enabled in the discord developer portal.",
+                            config.channel_id
+                        );
+                        warned_empty = true;
+                    }
+                    continue;
+                }
+
+                let on_cooldown = last_triggered
+                    .get(&message.author.id)
+                    .is_some_and(|time| time.elapsed() < per_user_cooldown);
+                // Trivial messages are kept as context, but aren't worth a response on their own.
+                let triggers_response = !on_cooldown && !is_trivial(&config, bot_id, message);
+                if on_cooldown && !config.keep_cooldown_messages {
+                    debug!(
+                        "Ignoring message from '{}' on cooldown",
+                        message.author.name
+                    );
+                    continue;
+                }
+
+                if !per_user_cooldown.is_zero() {
+                    if triggers_response {
+                        last_triggered.insert(message.author.id, Instant::now());
+                    }
+
+                    // Forget users whose cooldown has expired, so the map doesn't grow forever.
+                    if last_cleanup.elapsed() >= per_user_cooldown {
+                        last_triggered.retain(|_, time| time.elapsed() < per_user_cooldown);
+                        last_cleanup = Instant::now();
+                    }
+                }
+
+                let names = config.resolve_mentions.then(|| {
+                    names.insert_user(message.author.id, display_name(message));
+                    &names
+                });
+                let mut msg = UserMessage::from_message(message, bot_id, names, triggers_response);
+
+                // Show that the response waits for the cooldown, so the bot doesn't look stuck.
+                msg.cooldown_reacted = triggers_response
+                    && !config.cooldown_emoji.is_empty()
+                    && Instant::now() < *state.cooldown_until.borrow();
+                QueuedEvent::Message(msg)
+            }
+            // Edits are also sent when discord adds embeds to a message, these don't have the
+            // edited timestamp set.
+            Ok(Event::MessageUpdate(message))
+                if message.edited_timestamp.is_some()
+                    && !is_ignored_author(&config, bot_id, &message.author)
+                    && is_allowed_author(&config, message)
+                    && is_in_channel(&config, &threads, message.channel_id) =>
+            {
+                let names = config.resolve_mentions.then_some(&names);
+                QueuedEvent::Edit(UserMessage::from_message(message, bot_id, names, false))
+            }
+            Ok(Event::MessageDelete(delete))
+                if is_in_channel(&config, &threads, delete.channel_id) =>
+            {
+                QueuedEvent::Delete {
+                    channel_id: delete.channel_id,
+                    ids: vec![delete.id],
+                }
+            }
+            Ok(Event::MessageDeleteBulk(delete))
+                if is_in_channel(&config, &threads, delete.channel_id) =>
+            {
+                QueuedEvent::Delete {
+                    channel_id: delete.channel_id,
+                    ids: delete.ids.clone(),
+                }
+            }
+            Ok(Event::ReactionAdd(reaction))
+                if is_regenerate_request(&config, reaction)
+                    && is_in_channel(&config, &threads, reaction.channel_id) =>
+            {
+                QueuedEvent::Regenerate {
+                    channel_id: reaction.channel_id,
+                    message_id: reaction.message_id,
+                }
+            }
+            Ok(Event::ReactionAdd(reaction))
+                if is_continue_request(&config, reaction)
+                    && is_in_channel(&config, &threads, reaction.channel_id) =>
+            {
+                QueuedEvent::Continue {
+                    channel_id: reaction.channel_id,
+                    message_id: reaction.message_id,
+                }
+            }
+            Ok(Event::ReactionAdd(reaction))
+                if is_feedback_reaction(&config, bot_id, *state.webhook_id.borrow(), reaction)
+                    && is_in_channel(&config, &threads, reaction.channel_id) =>
+            {
+                QueuedEvent::Feedback(ResponseFeedback::new(
+                    reaction.channel_id,
+                    reaction.message_id,
+                    reaction_name(reaction).cloned().unwrap_or_default(),
+                    reaction.user_id,
+                    reaction.member.as_ref().map(|m| m.user.name.clone()),
+                    None,
+                ))
+            }
+            Ok(Event::InteractionCreate(interaction)) => {
+                let Some(channel_id) = interaction.channel.as_ref().map(|channel| channel.id)
+                else {
+                    continue;
+                };
+                let Some(data) = commands::command_data(interaction) else {
+                    continue;
+                };
+                if !is_in_channel(&config, &threads, channel_id) {
+                    continue;
+                }
+
+                match handle_command(&http, &config, &state.status, interaction, data, channel_id)
+                    .await
+                {
+                    Some(queued) => queued,
+                    None => continue,
+                }
+            }
+            Ok(event) => {
+                if config.include_threads {
+                    track_threads(&mut threads, config.channel_id, event);
+                }
+                if config.resolve_mentions {
+                    names.update(event);
+                }
+                continue;
+            }
         };
 
-        if message.channel_id != channel_id || message.author.bot {
-            continue;
+        match config.queue_overflow {
+            QueueOverflow::DropNewest => match queue.try_reserve() {
+                Ok(permit) => {
+                    queued.add_reactions(&http, &config);
+                    permit.send(queued);
+                }
+                Err(TrySendError::Full(())) => warn!(
+                    "Queue of channel {} is full, dropping the newest event",
+                    config.channel_id
+                ),
+                Err(TrySendError::Closed(_)) => return,
+            },
+            QueueOverflow::DropOldest => {
+                // Events that are already waiting go first, to keep the events in order.
+                queued.add_reactions(&http, &config);
+                if overflow.is_empty() {
+                    match queue.try_send(queued) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(queued)) => overflow.push_back(queued),
+                        Err(TrySendError::Closed(_)) => return,
+                    }
+                } else {
+                    overflow.push_back(queued);
+                }
+
+                if overflow.len() > queue.max_capacity() {
+                    if let Some(QueuedEvent::Message(msg)) = overflow.pop_front() {
+                        msg.remove_reactions(&http, &config);
+                    }
+                    warn!(
+                        "Queue of channel {} is full, dropping the oldest waiting event",
+                        config.channel_id
+                    );
+                }
+            }
+            QueueOverflow::Block => {
+                let Ok(permit) = queue.reserve().await else {
+                    return;
+                };
+                queued.add_reactions(&http, &config);
+                permit.send(queued);
+            }
         }
+    }
+}
 
-        let res = queue.try_send(UserMessage {
-            message_id: message.id,
-            reply_to: message.reference.as_ref().and_then(|r| r.message_id),
-            content: message.content.clone(),
-            sender_name: message.author.name.clone(),
-            sender_id: message.author.id,
-            sent_at: message.timestamp,
-            sender_display_name: message
-                .member
-                .as_ref()
-                .and_then(|m| m.nick.clone())
-                .or_else(|| message.author.global_name.clone()),
-            images: message
-                .attachments
+/// Respond to a slash command used in the channel, returning the event `serve` needs to handle.
+async fn handle_command(
+    http: &Client,
+    config: &super::Configuration,
+    status: &watch::Receiver<commands::Status>,
+    interaction: &Interaction,
+    data: &CommandData,
+    channel_id: Id<ChannelMarker>,
+) -> Option<QueuedEvent> {
+    if !matches!(
+        data.name.as_str(),
+        commands::CLEAR | commands::STATUS | commands::RAW_LOG
+    ) {
+        return None;
+    }
+
+    let (response, queued) = if data.name == commands::RAW_LOG {
+        // This affects every channel, so it is limited to admins.
+        if commands::has_permission(interaction, Permissions::ADMINISTRATOR) {
+            let enabled = commands::bool_option(data, "enabled");
+            raw_log::set_enabled(enabled);
+            info!(
+                "Raw logging of the LLM api {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+            let response = if enabled {
+                "Requests to and responses from the LLM api are now logged at the debug level, for every channel."
+            } else {
+                "Requests to and responses from the LLM api are no longer logged."
+            };
+            (response.to_string(), None)
+        } else {
+            (
+                "You need the Administrator permission to use this command.".to_string(),
+                None,
+            )
+        }
+    } else if !commands::has_permission(interaction, Permissions::MANAGE_MESSAGES) {
+        (
+            "You need the Manage Messages permission to use this command.".to_string(),
+            None,
+        )
+    } else if data.name == commands::CLEAR {
+        (
+            "Cleared the conversation history.".to_string(),
+            Some(QueuedEvent::Clear {
+                channel_id,
+                reload_prompt: commands::bool_option(data, "reload_prompt"),
+            }),
+        )
+    } else {
+        let status = commands::status_message(config, status.borrow().get(&channel_id));
+        (status, None)
+    };
+
+    if let Err(err) = commands::respond_ephemeral(http, interaction, &response).await {
+        error!("{err:?}");
+    }
+    queued
+}
+
+/// The name the author of the message is shown with in the channel.
+fn display_name(message: &Message) -> String {
+    message
+        .member
+        .as_ref()
+        .and_then(|m| m.nick.clone())
+        .or_else(|| message.author.global_name.clone())
+        .unwrap_or_else(|| message.author.name.clone())
+}
+
+/// Check if the message is too trivial to respond to, such as "ok" or a single emoji, according to
+/// `min_message_chars` and `ignore_only_emoji`. Messages with attachments are never trivial.
+fn is_trivial(config: &super::Configuration, bot_id: Id<UserMarker>, message: &Message) -> bool {
+    if !message.attachments.is_empty() {
+        return false;
+    }
+
+    let content = strip_mention(&message.content, bot_id);
+    content.chars().count() < config.min_message_chars
+        || (config.ignore_only_emoji && is_only_emoji(&content))
+}
+
+/// Check if the text only contains emojis, which can be unicode or custom discord emojis.
+fn is_only_emoji(text: &str) -> bool {
+    let without_custom = CUSTOM_EMOJI.replace_all(text, "");
+    let mut chars = without_custom
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .peekable();
+
+    // Nothing being left is only emoji if there were custom emojis.
+    if chars.peek().is_none() {
+        return without_custom.len() < text.len();
+    }
+    chars.all(|c| {
+        matches!(c,
+            // Pictographs, symbols, flags and skin tones.
+            '\u{1F000}'..='\u{1FAFF}'
+            // Miscellaneous symbols and dingbats.
+            | '\u{2300}'..='\u{23FF}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{2B00}'..='\u{2BFF}'
+            // Joiners, variation selectors, keycaps and tags that combine emojis.
+            | '\u{200D}'
+            | '\u{FE0F}'
+            | '\u{20E3}'
+            | '\u{E0020}'..='\u{E007F}'
+        )
+    })
+}
+
+/// Remove mentions of the user from the content, in both the `<@id>` and legacy `<@!id>` forms.
+fn strip_mention(content: &str, user_id: Id<UserMarker>) -> String {
+    content
+        .replace(&format!("<@{user_id}>"), "")
+        .replace(&format!("<@!{user_id}>"), "")
+        .trim()
+        .to_string()
+}
+
+/// Check if a message was sent in the channel, or one of its threads if they are included.
+fn is_in_channel(
+    config: &super::Configuration,
+    threads: &HashSet<Id<ChannelMarker>>,
+    channel_id: Id<ChannelMarker>,
+) -> bool {
+    channel_id == config.channel_id || (config.include_threads && threads.contains(&channel_id))
+}
+
+/// Check if messages from the author should be ignored. The bot's own messages are always ignored.
+fn is_ignored_author(config: &super::Configuration, bot_id: Id<UserMarker>, author: &User) -> bool {
+    if author.id == bot_id {
+        return true;
+    }
+
+    // Bots responding to each other can get stuck in a loop.
+    author.bot && config.ignore_bots && !config.allowed_bot_ids.contains(&author.id)
+}
+
+/// Check if the message is a response the bot posted through the webhook of the channel.
+fn is_webhook_response(config: &super::Configuration, message: &Message) -> bool {
+    message.webhook_id.is_some()
+        && config.webhook_username.as_deref() == Some(message.author.name.as_str())
+}
+
+/// Check if the author of the message is in the allowed users or has one of the allowed roles. If
+/// neither is set, everyone is allowed.
+fn is_allowed_author(config: &super::Configuration, message: &Message) -> bool {
+    if config.allowed_user_ids.is_empty() && config.allowed_role_ids.is_empty() {
+        return true;
+    }
+
+    config.allowed_user_ids.contains(&message.author.id)
+        || message.member.as_ref().is_some_and(|member| {
+            member
+                .roles
                 .iter()
-                .filter_map(|a| {
-                    let extension = a.filename.rsplit('.').next();
-                    match extension {
-                        Some("jpeg" | "jpg" | "png" | "webp") => Some(a.url.clone()),
-                        _ => None,
-                    }
-                })
-                .collect(),
-        });
+                .any(|role| config.allowed_role_ids.contains(role))
+        })
+}
+
+/// Check if the reaction asks for a response to be regenerated, and the user is allowed to.
+fn is_regenerate_request(config: &super::Configuration, reaction: &GatewayReaction) -> bool {
+    let is_regenerate_emoji = !config.regenerate_emoji.is_empty()
+        && reaction_name(reaction) == Some(&config.regenerate_emoji);
+
+    let is_bot = reaction.member.as_ref().is_some_and(|m| m.user.bot);
+    let is_allowed = config
+        .regenerate_allowed_users
+        .as_ref()
+        .is_none_or(|users| users.contains(&reaction.user_id));
+
+    is_regenerate_emoji && !is_bot && is_allowed
+}
+
+/// Check if the reaction asks for a truncated response to be continued.
+fn is_continue_request(config: &super::Configuration, reaction: &GatewayReaction) -> bool {
+    let is_continue_emoji = !config.continue_emoji.is_empty()
+        && reaction_name(reaction) == Some(&config.continue_emoji);
+    let is_bot = reaction.member.as_ref().is_some_and(|m| m.user.bot);
+    is_continue_emoji && !is_bot
+}
+
+/// Check if the reaction is feedback from a user on one of the bot's messages. Responses posted
+/// through the webhook have it as their author.
+fn is_feedback_reaction(
+    config: &super::Configuration,
+    bot_id: Id<UserMarker>,
+    webhook_id: Option<Id<WebhookMarker>>,
+    reaction: &GatewayReaction,
+) -> bool {
+    let is_feedback_emoji =
+        reaction_name(reaction).is_some_and(|name| config.feedback_emojis.contains(name));
+    let on_bot_message = reaction.message_author_id.is_some_and(|author_id| {
+        author_id == bot_id || webhook_id.is_some_and(|webhook_id| author_id == webhook_id.cast())
+    });
+    let is_bot = reaction.member.as_ref().is_some_and(|m| m.user.bot) || reaction.user_id == bot_id;
+    is_feedback_emoji && on_bot_message && !is_bot
+}
 
-        if let Err(mpsc::error::TrySendError::Closed(_)) = res {
+/// The name of the emoji of the reaction, which is the emoji itself for unicode emojis.
+fn reaction_name(reaction: &GatewayReaction) -> Option<&String> {
+    match &reaction.emoji {
+        EmojiReactionType::Unicode { name } => Some(name),
+        EmojiReactionType::Custom { name, .. } => name.as_ref(),
+    }
+}
+
+/// Keep track of which threads were created from the channel.
+fn track_threads(
+    threads: &mut HashSet<Id<ChannelMarker>>,
+    channel_id: Id<ChannelMarker>,
+    event: &Event,
+) {
+    let created: &[Channel] = match event {
+        Event::GuildCreate(guild) => match guild.as_ref() {
+            GuildCreate::Available(guild) => &guild.threads,
+            GuildCreate::Unavailable(_) => return,
+        },
+        Event::ThreadCreate(thread) => std::slice::from_ref(&thread.0),
+        Event::ThreadUpdate(thread) => std::slice::from_ref(&thread.0),
+        Event::ThreadListSync(sync) => &sync.threads,
+        Event::ThreadDelete(thread) => {
+            threads.remove(&thread.id);
             return;
         }
-    }
+        _ => return,
+    };
+
+    threads.extend(
+        created
+            .iter()
+            .filter(|thread| thread.parent_id == Some(channel_id))
+            .map(|thread| thread.id),
+    );
 }
 
-async fn b64_encode_image(image_url: &str, max_dim: u32) -> anyhow::Result<String> {
-    let image_bytes = reqwest::get(image_url).await?.bytes().await?;
+async fn b64_encode_image(
+    http: &reqwest::Client,
+    image_url: &str,
+    encoding: ImageEncoding,
+) -> anyhow::Result<String> {
+    // Expired CDN links respond with an error page, which would otherwise fail to decode.
+    let image_bytes = http
+        .get(image_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(BASE64_STANDARD.encode(downsize_image(&image_bytes, encoding)?))
+}
+
+/// Decode the image and re-encode it in the configured format, no larger than `max_dim` in
+/// either dimension.
+///
+/// Only the first frame of animated images (GIF or WebP) is kept.
+fn downsize_image(image_bytes: &[u8], encoding: ImageEncoding) -> Result<Vec<u8>, ImageError> {
     let img = ImageReader::new(Cursor::new(image_bytes))
         .with_guessed_format()?
         .decode()?;
 
     // Make the image smaller while preserving the aspect ratio to save on tokens.
+    let max_dim = encoding.max_dim;
     let img = if img.dimensions().0 > max_dim || img.dimensions().1 > max_dim {
-        img.resize(max_dim, max_dim, FilterType::Triangle)
+        img.resize(max_dim, max_dim, encoding.filter.into())
     } else {
         img
     };
 
-    // Ensure the image is always in a color format supported in JPEG. Transparent pixels would
-    // otherwise fail to decode and make the bot ignore the image.
-    let img = img.into_rgb8();
-
     let mut img_bytes = Vec::new();
-    img.write_to(&mut Cursor::new(&mut img_bytes), ImageFormat::Jpeg)?;
+    match encoding.output {
+        ImageOutput::Jpeg => {
+            // Ensure the image is always in a color format supported in JPEG. Transparent pixels
+            // would otherwise fail to decode and make the bot ignore the image.
+            let img = img.into_rgb8();
+            img.write_with_encoder(JpegEncoder::new_with_quality(
+                &mut img_bytes,
+                encoding.jpeg_quality,
+            ))?;
+        }
+        ImageOutput::Png => img.write_to(&mut Cursor::new(&mut img_bytes), ImageFormat::Png)?,
+    }
+
+    Ok(img_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Long quoted messages must be cut off, and every line of the quote must be marked as quoted.
+    #[test]
+    fn quote_is_capped() {
+        let quoted = QuotedMessage {
+            author_name: "ferris".to_string(),
+            content: format!("first line\n{}", "a".repeat(1000)),
+        };
+
+        let quote = quoted.format_quote();
+        assert!(quote.starts_with("> ferris: first line\n> aaa"));
+        assert!(quote.ends_with("a…\n"));
+        assert!(quote.chars().count() < MAX_QUOTE_CHARS + 20);
+    }
+
+    /// Animated images must be reduced to their first frame, and downsized like other images.
+    #[test]
+    fn animated_gif_is_downsized() {
+        use image::{Delay, Frame, RgbaImage, codecs::gif::GifEncoder};
+
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].map(|color| {
+                Frame::from_parts(
+                    RgbaImage::from_pixel(100, 50, image::Rgba(color)),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                )
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+
+        let encoding = ImageEncoding {
+            max_dim: 20,
+            filter: ResizeFilter::Triangle,
+            output: ImageOutput::Jpeg,
+            jpeg_quality: 75,
+        };
+        let jpeg = downsize_image(&gif, encoding).unwrap();
+        let img = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap();
+        assert_eq!(img.dimensions(), (20, 10));
+        // The first frame is red.
+        assert!(img.to_rgb8().get_pixel(10, 5)[0] > 200);
+
+        assert!(downsize_image(b"not an image", encoding).is_err());
+    }
 
-    Ok(BASE64_STANDARD.encode(img_bytes))
+    /// Images must be encoded in the configured format.
+    #[test]
+    fn image_is_encoded_as_png() {
+        let mut png = Vec::new();
+        image::RgbaImage::from_pixel(100, 50, image::Rgba([0, 255, 0, 128]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let encoding = ImageEncoding {
+            max_dim: 20,
+            filter: ResizeFilter::Lanczos,
+            output: ImageOutput::Png,
+            jpeg_quality: 75,
+        };
+        let encoded = downsize_image(&png, encoding).unwrap();
+        let img = image::load_from_memory_with_format(&encoded, ImageFormat::Png).unwrap();
+        assert_eq!(img.dimensions(), (20, 10));
+        // Transparency is kept, unlike with JPEG.
+        assert_eq!(img.to_rgba8().get_pixel(10, 5)[3], 128);
+    }
+
+    /// Only the newest images must be kept.
+    #[test]
+    fn old_images_are_omitted() {
+        let image_message = |images: usize| -> ChatCompletionRequestMessage {
+            let mut parts = vec![ChatCompletionRequestUserMessageContentPart::Text(
+                "text".into(),
+            )];
+            parts.extend(
+                (0..images).map(|_| image_content_part("", &ImageDetail::Low, ImageOutput::Jpeg)),
+            );
+            ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Array(parts),
+                ..Default::default()
+            }
+            .into()
+        };
+        let image_count = |message: &ChatCompletionRequestMessage| match message {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Array(parts),
+                ..
+            }) => parts
+                .iter()
+                .filter(|part| {
+                    matches!(
+                        part,
+                        ChatCompletionRequestUserMessageContentPart::ImageUrl(_)
+                    )
+                })
+                .count(),
+            _ => 0,
+        };
+
+        let mut messages = vec![
+            image_message(2),
+            ChatCompletionRequestMessage::User("no images".into()),
+            image_message(2),
+        ];
+        omit_old_images(&mut messages, 3);
+
+        assert_eq!(
+            messages.iter().map(image_count).collect::<Vec<_>>(),
+            [1, 0, 2]
+        );
+    }
+
+    /// A message from ferris that triggers a response.
+    fn test_message(content: &str) -> UserMessage {
+        UserMessage {
+            message_id: Id::new(1),
+            channel_id: Id::new(2),
+            reply_to: None,
+            content: content.to_string(),
+            sender_name: "ferris".to_string(),
+            sender_display_name: None,
+            sender_id: Id::new(3),
+            sent_at: Timestamp::from_secs(0).unwrap(),
+            images: Vec::new(),
+            attachments: Vec::new(),
+            quoted: None,
+            triggers_response: true,
+            mentions_bot: false,
+            cooldown_reacted: false,
+        }
+    }
+
+    /// The author must be included, so the LLM can tell the users in the channel apart.
+    #[test]
+    fn formatted_message_includes_author() {
+        let msg = UserMessage {
+            sender_display_name: Some("Ferris the Crab".to_string()),
+            ..test_message("hello")
+        };
+
+        let formatted = msg.format_message(&msg.content, &["attachment: a.txt".to_string()], None);
+        assert!(formatted.contains("author_name: ferris (Ferris the Crab)\nauthor_id: 3\n"));
+        assert!(formatted.ends_with("hello\nattachment: a.txt</msg>"));
+    }
+
+    /// Images that fail to download must be replaced by a note, sending the message as text.
+    #[tokio::test]
+    async fn unloaded_image_falls_back_to_text() {
+        let config = crate::ai_channel::tests::test_config("image_support = true");
+        let msg = UserMessage {
+            // Nothing listens on the discard port, so the download fails.
+            images: vec!["http://127.0.0.1:9/image.png".to_string()],
+            ..test_message("what is this?")
+        };
+
+        let message = msg
+            .as_chat_completion_message(
+                &config,
+                &reqwest::Client::new(),
+                &mut ImageCache::new(1024),
+                None,
+            )
+            .await;
+        let ChatCompletionRequestUserMessageContent::Text(text) = message.content else {
+            panic!("Message should be sent as text: {message:?}");
+        };
+        assert!(text.contains("what is this?"));
+        assert!(text.ends_with(UNLOADED_IMAGE));
+    }
+
+    /// Relative timestamps must give the time since the previous message, when it is known.
+    #[test]
+    fn relative_timestamps() {
+        let msg = UserMessage {
+            sent_at: Timestamp::from_secs(7_500).unwrap(),
+            ..test_message("hello")
+        };
+        let previous = Timestamp::from_secs(0).ok();
+
+        assert_eq!(
+            msg.timestamp(TimestampFormat::Relative, previous)
+                .as_deref(),
+            Some("2h 5m after the previous message")
+        );
+        assert_eq!(
+            msg.timestamp(TimestampFormat::Relative, None),
+            msg.timestamp(TimestampFormat::Absolute, previous)
+        );
+        assert_eq!(msg.timestamp(TimestampFormat::None, previous), None);
+    }
+
+    /// Both forms of the bot's mention must be removed, without touching other mentions.
+    #[test]
+    fn bot_mention_is_stripped() {
+        let bot_id = Id::new(123);
+
+        assert_eq!(strip_mention("<@123> hello", bot_id), "hello");
+        assert_eq!(strip_mention("hi <@!123>", bot_id), "hi");
+        assert_eq!(strip_mention("<@123> meet <@456>", bot_id), "meet <@456>");
+    }
+
+    /// Feedback must be recognized on responses posted by the bot and through its webhook, but
+    /// not on other messages or from the bot itself.
+    #[test]
+    fn feedback_on_webhook_responses() {
+        let config = crate::ai_channel::tests::test_config("webhook_username = \"Ferris\"");
+        let bot_id = Id::new(1);
+        let webhook_id = Some(Id::new(2));
+        let reaction = |author_id: u64, user_id: u64| GatewayReaction {
+            burst: false,
+            burst_colors: Vec::new(),
+            channel_id: Id::new(3),
+            emoji: EmojiReactionType::Unicode {
+                name: "👍".to_string(),
+            },
+            guild_id: None,
+            member: None,
+            message_author_id: Some(Id::new(author_id)),
+            message_id: Id::new(4),
+            user_id: Id::new(user_id),
+        };
+
+        assert!(is_feedback_reaction(
+            &config,
+            bot_id,
+            webhook_id,
+            &reaction(1, 5)
+        ));
+        assert!(is_feedback_reaction(
+            &config,
+            bot_id,
+            webhook_id,
+            &reaction(2, 5)
+        ));
+        assert!(!is_feedback_reaction(
+            &config,
+            bot_id,
+            None,
+            &reaction(2, 5)
+        ));
+        assert!(!is_feedback_reaction(
+            &config,
+            bot_id,
+            webhook_id,
+            &reaction(6, 5)
+        ));
+        assert!(!is_feedback_reaction(
+            &config,
+            bot_id,
+            webhook_id,
+            &reaction(2, 1)
+        ));
+    }
+
+    /// Text with anything other than emojis must not count as only emoji.
+    #[test]
+    fn only_emoji() {
+        assert!(is_only_emoji("👍"));
+        assert!(is_only_emoji("😂 😂"));
+        assert!(is_only_emoji("👨‍👩‍👧 🇬🇧 ❤️"));
+        assert!(is_only_emoji("<:ferris:123456> <a:party:654321>"));
+        assert!(!is_only_emoji(""));
+        assert!(!is_only_emoji("ok 👍"));
+        assert!(!is_only_emoji("<:ferris:123456> hi"));
+    }
 }
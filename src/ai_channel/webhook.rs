@@ -0,0 +1,125 @@
+use anyhow::Context;
+use tokio::sync::watch;
+use tracing::{debug, info};
+use twilight_http::Client;
+use twilight_model::{
+    channel::message::{AllowedMentions, Embed},
+    id::{
+        Id,
+        marker::{ChannelMarker, MessageMarker, WebhookMarker},
+    },
+};
+
+/// The name of the webhooks the bot creates, so they can be found again after restarting.
+const WEBHOOK_NAME: &str = "AI channel responses";
+
+/// Posts responses through a webhook of the channel, so they are shown with the configured name
+/// and avatar instead of the bot's.
+pub struct WebhookPoster {
+    /// The AI channel, which threads share the webhook of.
+    channel_id: Id<ChannelMarker>,
+    username: String,
+    avatar_url: Option<String>,
+    /// The webhook and its token, once it has been found or created.
+    webhook: Option<(Id<WebhookMarker>, String)>,
+    /// Receives the id of the webhook, which is the author of the responses.
+    id_tx: watch::Sender<Option<Id<WebhookMarker>>>,
+}
+
+impl WebhookPoster {
+    /// Create the poster if the channel posts through a webhook.
+    pub fn new(
+        config: &super::Configuration,
+        id_tx: watch::Sender<Option<Id<WebhookMarker>>>,
+    ) -> Option<Self> {
+        Some(Self {
+            channel_id: config.channel_id,
+            username: config.webhook_username.clone()?,
+            avatar_url: config.webhook_avatar_url.clone(),
+            webhook: None,
+            id_tx,
+        })
+    }
+
+    /// Find or create the webhook, if it isn't known yet, returning it and its token.
+    pub async fn connect(&mut self, http: &Client) -> anyhow::Result<(Id<WebhookMarker>, String)> {
+        if let Some(webhook) = &self.webhook {
+            return Ok(webhook.clone());
+        }
+        let webhook = find_or_create(http, self.channel_id).await?;
+        self.id_tx.send_replace(Some(webhook.0));
+        Ok(self.webhook.insert(webhook).clone())
+    }
+
+    /// Post the message in the channel or one of its threads, returning the id of the message.
+    pub async fn send(
+        &mut self,
+        http: &Client,
+        channel_id: Id<ChannelMarker>,
+        content: Option<&str>,
+        embeds: &[Embed],
+        allowed_mentions: &AllowedMentions,
+    ) -> anyhow::Result<Id<MessageMarker>> {
+        let (webhook_id, token) = self.connect(http).await?;
+
+        let mut execute = http
+            .execute_webhook(webhook_id, &token)
+            .username(&self.username)
+            .embeds(embeds)
+            .allowed_mentions(Some(allowed_mentions));
+        if let Some(avatar_url) = &self.avatar_url {
+            execute = execute.avatar_url(avatar_url);
+        }
+        if let Some(content) = content {
+            execute = execute.content(content);
+        }
+        if channel_id != self.channel_id {
+            execute = execute.thread_id(channel_id);
+        }
+
+        let res = execute.wait().await;
+        // The webhook may have been deleted, so it is looked up again for the next message.
+        if res.is_err() {
+            self.webhook = None;
+        }
+        let message = res
+            .context("Failed to execute webhook")?
+            .model()
+            .await
+            .context("Failed to deserialize webhook message")?;
+        Ok(message.id)
+    }
+}
+
+/// Find the webhook the bot created in the channel before, or create it.
+async fn find_or_create(
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+) -> anyhow::Result<(Id<WebhookMarker>, String)> {
+    let webhooks = http
+        .channel_webhooks(channel_id)
+        .await
+        .context("Failed to list the webhooks of the channel, is the Manage Webhooks permission missing?")?
+        .models()
+        .await?;
+    // The token is only included for webhooks the bot can post with.
+    let existing = webhooks.into_iter().find_map(|webhook| {
+        (webhook.name.as_deref() == Some(WEBHOOK_NAME))
+            .then_some(webhook.token.map(|token| (webhook.id, token)))
+            .flatten()
+    });
+    if let Some(webhook) = existing {
+        debug!("Using existing webhook for '{channel_id}'");
+        return Ok(webhook);
+    }
+
+    let webhook = http
+        .create_webhook(channel_id, WEBHOOK_NAME)
+        .await
+        .context("Failed to create webhook, is the Manage Webhooks permission missing?")?
+        .model()
+        .await?;
+    info!("Created webhook for '{channel_id}'");
+    let token = webhook.token.context("Created webhook has no token")?;
+    Ok((webhook.id, token))
+}
@@ -1,20 +1,60 @@
 use std::{
     env,
+    net::SocketAddr,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
 };
 
 use anyhow::Context;
 use serde::Deserialize;
 
-use crate::ai_channel;
+use crate::{ai_channel, presence::ActivityConfig};
+pub(crate) mod env_vars;
 pub(crate) mod file_watch;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct Configuration {
     /// The bot's discord token.
     pub token: String,
-    #[serde(default, rename = "ai_channel")]
     pub ai_channels: Vec<ai_channel::Configuration>,
+    /// The address to serve prometheus metrics on. No metrics are served if this is not set.
+    pub metrics_address: Option<SocketAddr>,
+    /// The address to serve the `/healthz` and `/readyz` checks on. They are also served on
+    /// `metrics_address` if it is set.
+    pub health_address: Option<SocketAddr>,
+    /// The maximum amount of requests sent to the LLM apis at once, across every AI channel. Not
+    /// limited if this is not set.
+    pub max_concurrent_requests: Option<NonZeroUsize>,
+    /// The amount of discord events buffered for the AI channels. Channels that fall further behind
+    /// than this skip the oldest events.
+    pub event_buffer_size: NonZeroUsize,
+    /// The activity shown in the bot's presence. No activity is shown if this is not set.
+    pub activity: Option<ActivityConfig>,
+}
+
+/// The configuration as it is written in the file, before the channel defaults are applied.
+#[derive(Debug, Deserialize)]
+struct RawConfiguration {
+    token: String,
+    #[serde(default, rename = "ai_channel")]
+    ai_channels: Vec<config::Map<String, config::Value>>,
+    /// Values used for every AI channel that doesn't set them itself.
+    #[serde(default)]
+    ai_channel_defaults: config::Map<String, config::Value>,
+    #[serde(default)]
+    metrics_address: Option<SocketAddr>,
+    #[serde(default)]
+    health_address: Option<SocketAddr>,
+    #[serde(default)]
+    max_concurrent_requests: Option<NonZeroUsize>,
+    #[serde(default = "default_event_buffer_size")]
+    event_buffer_size: NonZeroUsize,
+    #[serde(default)]
+    activity: Option<ActivityConfig>,
+}
+
+fn default_event_buffer_size() -> NonZeroUsize {
+    NonZeroUsize::new(16).expect("16 is not zero")
 }
 
 impl Configuration {
@@ -32,9 +72,38 @@ impl Configuration {
             .build()
             .context("failed to build config")?;
 
-        config
+        let raw: RawConfiguration = config
             .try_deserialize()
-            .context("failed to deserialize config")
+            .context("failed to deserialize config")?;
+
+        let ai_channels = raw
+            .ai_channels
+            .into_iter()
+            .enumerate()
+            .map(|(index, channel)| {
+                let mut merged = raw.ai_channel_defaults.clone();
+                merged.extend(channel);
+                config::Value::new(None, merged)
+                    .try_deserialize::<ai_channel::Configuration>()
+                    .with_context(|| format!("failed to deserialize ai_channel {index}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let config = Self {
+            token: raw.token,
+            ai_channels,
+            metrics_address: raw.metrics_address,
+            health_address: raw.health_address,
+            max_concurrent_requests: raw.max_concurrent_requests,
+            event_buffer_size: raw.event_buffer_size,
+            activity: raw.activity,
+        };
+
+        for ai_channel in &config.ai_channels {
+            ai_channel.warn_unusual_settings();
+        }
+
+        Ok(config)
     }
 
     /// Reads the configuration from the locations specified in the environment variable. The paths
@@ -73,4 +142,39 @@ mod tests {
         Configuration::read([example_toml.as_path()])
             .expect("Unable to parse example configuration file.");
     }
+
+    /// Channels must use the defaults for the values they don't set, and their own values
+    /// otherwise.
+    #[test]
+    fn ai_channel_defaults() {
+        let temp_dir = tempfile::tempdir().expect("Unable to create tempoary directory");
+        let path = temp_dir.path().join("bot.toml");
+        std::fs::write(
+            &path,
+            r#"
+            token = "token"
+
+            [ai_channel_defaults]
+            llm_api_key = "shared key"
+            model_name = "shared model"
+
+            [[ai_channel]]
+            channel_id = 1
+            prompt_path = "prompt.txt"
+
+            [[ai_channel]]
+            channel_id = 2
+            model_name = "own model"
+            prompt_path = "prompt.txt"
+            "#,
+        )
+        .expect("Unable to write config to temp file");
+
+        let config = Configuration::read([path.as_path()]).expect("Unable to parse config");
+        assert_eq!(config.ai_channels.len(), 2);
+        assert_eq!(config.ai_channels[0].get_llm_api_key(), "shared key");
+        assert_eq!(config.ai_channels[0].get_model_name(), "shared model");
+        assert_eq!(config.ai_channels[1].get_llm_api_key(), "shared key");
+        assert_eq!(config.ai_channels[1].get_model_name(), "own model");
+    }
 }
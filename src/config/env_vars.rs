@@ -0,0 +1,62 @@
+use serde::{Deserialize, Deserializer, de::Error};
+
+/// Replace every `${NAME}` in the value with the value of the `NAME` environment variable.
+///
+/// This lets secrets be kept out of the configuration file. Referencing a variable that isn't set
+/// is an error, so a missing secret is noticed at startup.
+pub fn expand(value: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(format!("Unclosed '${{' in '{value}'"));
+        };
+
+        let name = &rest[start + 2..start + 2 + len];
+        let var = std::env::var(name)
+            .map_err(|err| format!("Unable to read environment variable '{name}': {err}"))?;
+        expanded.push_str(&var);
+
+        rest = &rest[start + 2 + len + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Deserialize a string, expanding the environment variables in it with [`expand`].
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    expand(&value).map_err(D::Error::custom)
+}
+
+/// Deserialize an optional string, expanding the environment variables in it with [`expand`].
+pub fn deserialize_optional<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| expand(&value).map_err(D::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Variables must be replaced by their values, and unset variables must be an error.
+    #[test]
+    fn expand_variables() {
+        let path = std::env::var("PATH").expect("PATH should be set");
+
+        assert_eq!(expand("no variables").unwrap(), "no variables");
+        assert_eq!(expand("${PATH}").unwrap(), path);
+        assert_eq!(
+            expand("a${PATH}b${PATH}").unwrap(),
+            format!("a{path}b{path}")
+        );
+        assert!(expand("${BOT_TEST_VARIABLE_THAT_IS_NOT_SET}").is_err());
+        assert!(expand("${PATH").is_err());
+    }
+}
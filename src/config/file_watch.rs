@@ -1,35 +1,83 @@
 use anyhow::anyhow;
 use notify::{Config, Event, RecommendedWatcher, Watcher};
-use std::{fs::File, path::Path};
-use tokio::sync::watch;
+use std::{
+    fs::File,
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    time::timeout,
+};
+
+/// How long the file must go without changes before it is reloaded. Editors can write a file in
+/// several chunks, which would otherwise reload it several times, possibly while half written.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
 
 /// Reads the channel prompt into a [`watch`] channel.
 ///
 /// The [`watch::Receiver`] will have its value updated when the channel prompt file is modified.
+/// An empty prompt is an error, as the LLM would respond without any instructions.
 #[doc(alias = "read_prompt")]
 pub async fn load_prompt(
     prompt_path: &Path,
 ) -> Result<(watch::Sender<Box<str>>, watch::Receiver<Box<str>>), std::io::Error> {
-    let current_prompt = tokio::fs::read_to_string(&prompt_path)
-        .await?
-        .into_boxed_str();
+    let (sender, receiver) = load_file(prompt_path).await?;
+    if receiver.borrow().trim().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("The prompt file '{}' is empty", prompt_path.display()),
+        ));
+    }
+
+    Ok((sender, receiver))
+}
+
+/// Reads a file into a [`watch`] channel, like [`load_prompt`] but allowing the file to be empty.
+pub async fn load_file(
+    path: &Path,
+) -> Result<(watch::Sender<Box<str>>, watch::Receiver<Box<str>>), std::io::Error> {
+    let contents = tokio::fs::read_to_string(&path).await?.into_boxed_str();
 
-    Ok(watch::channel(current_prompt))
+    Ok(watch::channel(contents))
 }
 
 /// Monitors the channel prompt file for changes.
 ///
+/// If the file is changed to be empty, the previous prompt is kept.
+///
 /// # Panics
 /// If this function is called from outside of a tokio runtime.
 pub fn monitor_prompt(path: &Path, prompt_sender: watch::Sender<Box<str>>) -> anyhow::Result<()> {
+    monitor(path, prompt_sender, false)
+}
+
+/// Monitors a file loaded with [`load_file`] for changes, like [`monitor_prompt`] but allowing the
+/// file to become empty.
+///
+/// # Panics
+/// If this function is called from outside of a tokio runtime.
+pub fn monitor_file(path: &Path, sender: watch::Sender<Box<str>>) -> anyhow::Result<()> {
+    monitor(path, sender, true)
+}
+
+fn monitor(
+    path: &Path,
+    prompt_sender: watch::Sender<Box<str>>,
+    allow_empty: bool,
+) -> anyhow::Result<()> {
     // Normalises the path.
     // The path is compared with to filter events later.
     let Ok(prompt_path) = path.canonicalize() else {
         return Err(anyhow!("Unable to get canonical path for channel prompt",));
     };
 
+    let prompt_path = prompt_path.into_boxed_path();
+    let (reload_sender, reload_receiver) = mpsc::unbounded_channel();
     let mut watcher = match RecommendedWatcher::new(
-        create_event_handler(prompt_sender.clone(), prompt_path.clone().into_boxed_path()),
+        create_event_handler(reload_sender, prompt_path.clone()),
         Config::default(),
     ) {
         Ok(var) => var,
@@ -53,7 +101,10 @@ pub fn monitor_prompt(path: &Path, prompt_sender: watch::Sender<Box<str>>) -> an
 
     // Watcher needs to live for duration of program.
     tokio::spawn(async move {
-        prompt_sender.closed().await;
+        select! {
+            _ = prompt_sender.closed() => {}
+            _ = reload_debounced(reload_receiver, &prompt_sender, &prompt_path, allow_empty) => {}
+        }
         // Ensure task takes ownership of watcher.
         drop(watcher);
     });
@@ -61,15 +112,11 @@ pub fn monitor_prompt(path: &Path, prompt_sender: watch::Sender<Box<str>>) -> an
     Ok(())
 }
 
-/// Creates the event handler for updating the channel prompt.
+/// Creates the event handler that requests a reload of the channel prompt when it changes.
 fn create_event_handler(
-    sender: watch::Sender<Box<str>>,
+    reload: mpsc::UnboundedSender<()>,
     prompt_path: Box<Path>,
 ) -> impl FnMut(Result<Event, notify::Error>) {
-    let mut last_modified = File::open(&prompt_path)
-        .and_then(|file| file.metadata())
-        .and_then(|metadata| metadata.modified());
-
     move |event| {
         let event: Event = match event {
             Ok(var) => var,
@@ -84,72 +131,120 @@ fn create_event_handler(
         };
 
         // Access events spam (personal experience).
-        if !(event.kind.is_modify() || event.kind.is_other()) {
+        if !(event.kind.is_modify()
+            || event.kind.is_other()
+            || event.kind.is_create()
+            || event.kind.is_remove())
+        {
             return;
         }
 
-        // Check if the event was for this channel prompt path
-        let for_prompt_file = event
-            .paths
-            .iter()
-            .filter(|path| {
-                path.canonicalize()
+        // Check if the event was for this channel prompt path. A deleted file can't be
+        // canonicalized, but the paths of events are relative to the canonical watched directory.
+        let for_prompt_file = event.paths.iter().any(|path| {
+            **path == *prompt_path
+                || path
+                    .canonicalize()
                     .ok()
                     .is_some_and(|path| *path == *prompt_path)
-            })
-            .count()
-            != 0;
+        });
 
         if !for_prompt_file {
             return;
         }
 
-        // Check if we have read in this version of the file before
-        let modified = File::open(&prompt_path)
-            .and_then(|file| file.metadata())
-            .and_then(|metadata| metadata.modified());
-
-        match (modified, &mut last_modified) {
-            (Ok(modified), Ok(last_modified)) => {
-                if modified == *last_modified {
-                    tracing::debug!(
-                        "Prompt file '{}' has not been modified since last read. Skipping updating prompt in memory.",
-                        prompt_path.display()
-                    );
-                    return;
-                }
-
-                *last_modified = modified;
-            }
-            (Ok(modified), last_modified @ Err(_)) => {
-                *last_modified = Ok(modified);
-            }
-            (Err(_), Ok(_)) | (Err(_), Err(_)) => {
-                tracing::warn!(
-                    "Unable to verify if '{}' prompt file has been modified or not. Updating regardless.",
-                    prompt_path.display()
-                );
-            }
+        // Editors that save by deleting and recreating the file trigger a create event next, which
+        // reloads the prompt.
+        if event.kind.is_remove() {
+            tracing::warn!(
+                "Prompt file '{}' was deleted, keeping the previous prompt until it is created again",
+                prompt_path.display()
+            );
+            return;
         }
 
-        let new_prompt = match std::fs::read_to_string(&prompt_path) {
-            Ok(var) => var.into_boxed_str(),
-            Err(err) => {
-                tracing::error!(
-                    "Unable to read prompts file at '{}' : '{err}'",
+        _ = reload.send(());
+    }
+}
+
+/// Reload the channel prompt once no more reloads have been requested for [`RELOAD_DEBOUNCE`].
+async fn reload_debounced(
+    mut requests: mpsc::UnboundedReceiver<()>,
+    sender: &watch::Sender<Box<str>>,
+    prompt_path: &Path,
+    allow_empty: bool,
+) {
+    let mut last_modified = File::open(prompt_path)
+        .and_then(|file| file.metadata())
+        .and_then(|metadata| metadata.modified());
+
+    while requests.recv().await.is_some() {
+        while let Ok(Some(())) = timeout(RELOAD_DEBOUNCE, requests.recv()).await {}
+        reload(sender, prompt_path, allow_empty, &mut last_modified);
+    }
+}
+
+/// Read the channel prompt into the [`watch`] channel, if it was modified since it was last read.
+fn reload(
+    sender: &watch::Sender<Box<str>>,
+    prompt_path: &Path,
+    allow_empty: bool,
+    last_modified: &mut io::Result<SystemTime>,
+) {
+    // Check if we have read in this version of the file before
+    let modified = File::open(prompt_path)
+        .and_then(|file| file.metadata())
+        .and_then(|metadata| metadata.modified());
+
+    match (modified, last_modified) {
+        (Ok(modified), Ok(last_modified)) => {
+            if modified == *last_modified {
+                tracing::debug!(
+                    "Prompt file '{}' has not been modified since last read. Skipping updating prompt in memory.",
                     prompt_path.display()
                 );
                 return;
             }
-        };
 
-        sender.send_modify(|prompt| *prompt = new_prompt);
+            *last_modified = modified;
+        }
+        (Ok(modified), last_modified @ Err(_)) => {
+            *last_modified = Ok(modified);
+        }
+        (Err(_), Ok(_)) | (Err(_), Err(_)) => {
+            tracing::warn!(
+                "Unable to verify if '{}' prompt file has been modified or not. Updating regardless.",
+                prompt_path.display()
+            );
+        }
+    }
+
+    let new_prompt = match std::fs::read_to_string(prompt_path) {
+        Ok(var) => var.into_boxed_str(),
+        Err(err) => {
+            tracing::error!(
+                "Unable to read prompts file at '{}' : '{err}'",
+                prompt_path.display()
+            );
+            return;
+        }
+    };
 
-        tracing::info!(
-            "Updated channel prompts for file at '{}'",
+    // Editors can truncate the file before writing the new contents.
+    if !allow_empty && new_prompt.trim().is_empty() {
+        tracing::warn!(
+            "Prompt file '{}' is empty, keeping the previous prompt",
             prompt_path.display()
         );
+        return;
     }
+
+    sender.send_modify(|prompt| *prompt = new_prompt);
+
+    tracing::info!(
+        "Updated channel prompts for file at '{}'",
+        prompt_path.display()
+    );
 }
 
 #[cfg(test)]
@@ -215,6 +310,41 @@ mod tests {
         }
     }
 
+    /// Empty prompts must not be loaded, and must not replace the previous prompt.
+    #[tokio::test]
+    async fn empty_prompt_is_rejected() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let mut prompt_file = tempdir.path().to_path_buf();
+        prompt_file.push("prompt.txt");
+        let prompt_file = prompt_file.as_path();
+
+        write(prompt_file, " \n").expect("Unable to write dummy prompt data");
+        assert!(load_prompt(prompt_file).await.is_err());
+        assert!(load_file(prompt_file).await.is_ok());
+
+        write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
+        let (prompt_sender, mut prompt_receiver) = load_prompt(prompt_file)
+            .await
+            .expect("Unable to load prompt file");
+
+        monitor_prompt(prompt_file, prompt_sender).expect("Unable to monitor channel prompt");
+
+        // Prevent race condition where file is written to before watcher inits.
+        sleep(Duration::from_millis(200)).await;
+
+        write(prompt_file, "").expect("Unable to empty prompt file");
+
+        // Ensure the debounced reload has enough time to run
+        sleep(RELOAD_DEBOUNCE + Duration::from_millis(200)).await;
+
+        assert!(!prompt_receiver.has_changed().unwrap());
+        assert_eq!(
+            *prompt_receiver.borrow_and_update(),
+            "Test prompt data".into()
+        );
+    }
+
     /// If a prompt file is deleted then the old contents will remain as the prompt.
     #[tokio::test]
     async fn prompt_is_deleted() {
@@ -237,9 +367,87 @@ mod tests {
 
         std::fs::remove_file(prompt_file).expect("Unable to remove prompt file");
 
-        // Ensure callback has enough time to run
+        // Ensure the debounced reload has enough time to run
+        sleep(RELOAD_DEBOUNCE + Duration::from_millis(200)).await;
+
+        assert_eq!(*prompt_receiver.borrow(), "Test prompt data".into());
+    }
+
+    /// If a deleted prompt file is created again then its new contents must be loaded.
+    #[tokio::test]
+    async fn prompt_is_recreated() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let mut prompt_file = tempdir.path().to_path_buf();
+        prompt_file.push("prompt.txt");
+        let prompt_file = prompt_file.as_path();
+
+        write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
+
+        let (prompt_sender, prompt_receiver) = load_prompt(prompt_file)
+            .await
+            .expect("Unable to load prompt file");
+
+        monitor_prompt(prompt_file, prompt_sender).expect("Unable to monitor channel prompt");
+
+        // Prevent race condition where file is modified to before watcher inits.
         sleep(Duration::from_millis(200)).await;
 
+        std::fs::remove_file(prompt_file).expect("Unable to remove prompt file");
+        sleep(RELOAD_DEBOUNCE + Duration::from_millis(200)).await;
         assert_eq!(*prompt_receiver.borrow(), "Test prompt data".into());
+
+        write(prompt_file, "New prompt data!").expect("Unable to recreate prompt file");
+
+        let mut checks = 0;
+        loop {
+            sleep(Duration::from_millis(100)).await;
+
+            if *prompt_receiver.borrow() == "New prompt data!".into() {
+                break;
+            }
+
+            checks += 1;
+            if checks == 20 {
+                panic!(
+                    "The shared prompt was not updated within ~2 sec after the prompt file was recreated."
+                );
+            }
+        }
+    }
+
+    /// Several writes in quick succession must only reload the prompt once, with the last value.
+    #[tokio::test]
+    async fn reloads_are_debounced() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let mut prompt_file = tempdir.path().to_path_buf();
+        prompt_file.push("prompt.txt");
+        let prompt_file = prompt_file.as_path();
+
+        write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
+
+        let (prompt_sender, mut prompt_receiver) = load_prompt(prompt_file)
+            .await
+            .expect("Unable to load prompt file");
+
+        monitor_prompt(prompt_file, prompt_sender).expect("Unable to monitor channel prompt");
+
+        // Prevent race condition where file is written to before watcher inits.
+        sleep(Duration::from_millis(200)).await;
+
+        for i in 0..5 {
+            write(prompt_file, format!("Prompt {i}")).expect("Unable to write new prompt data");
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(2), prompt_receiver.changed())
+            .await
+            .expect("The prompt was not reloaded within ~2 sec")
+            .expect("The prompt sender was dropped");
+        assert_eq!(*prompt_receiver.borrow_and_update(), "Prompt 4".into());
+
+        sleep(RELOAD_DEBOUNCE + Duration::from_millis(200)).await;
+        assert!(!prompt_receiver.has_changed().unwrap());
     }
 }
@@ -1,8 +1,11 @@
 use tracing::error;
-use twilight_http::Client;
+use twilight_http::{Client, Response};
 use twilight_model::{
-    channel::Message,
-    id::{Id, marker::ChannelMarker},
+    channel::{Message, message::Embed},
+    id::{
+        Id,
+        marker::{ChannelMarker, MessageMarker},
+    },
 };
 use twilight_util::builder::embed::EmbedBuilder;
 
@@ -19,10 +22,7 @@ pub async fn send_error_msg(
 ) -> Option<Message> {
     let res = http
         .create_message(channel_id)
-        .embeds(&[EmbedBuilder::new()
-            .color(ERROR_COLOR)
-            .description(message)
-            .build()])
+        .embeds(&[error_embed(message)])
         .await;
     let res = match res {
         Ok(res) => res,
@@ -32,10 +32,47 @@ pub async fn send_error_msg(
         }
     };
 
+    deserialize_message(res).await
+}
+
+/// Utility function to replace an existing message with an error message.
+///
+/// Logs any errors that may occur while editing the message. When successful, returns the edited
+/// message.
+pub async fn edit_to_error_msg(
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    message: &str,
+) -> Option<Message> {
+    let res = http
+        .update_message(channel_id, message_id)
+        .content(None)
+        .embeds(Some(&[error_embed(message)]))
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            error!("Failed to edit message into error message: {err}");
+            return None;
+        }
+    };
+
+    deserialize_message(res).await
+}
+
+fn error_embed(message: &str) -> Embed {
+    EmbedBuilder::new()
+        .color(ERROR_COLOR)
+        .description(message)
+        .build()
+}
+
+async fn deserialize_message(res: Response<Message>) -> Option<Message> {
     match res.model().await {
         Ok(res) => Some(res),
         Err(err) => {
-            error!("Failed to deserialize message creation response: {err}");
+            error!("Failed to deserialize message response: {err}");
             None
         }
     }
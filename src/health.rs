@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use twilight_model::id::{Id, marker::ChannelMarker};
+
+/// The health of the gateway connection and every channel, shared by the whole program.
+static HEALTH: LazyLock<Health> = LazyLock::new(Health::default);
+
+/// Record if the gateway is connected to discord.
+pub fn set_gateway_connected(connected: bool) {
+    HEALTH.gateway_connected.store(connected, Ordering::Relaxed);
+}
+
+/// The status and body of the `/healthz` response.
+pub fn liveness() -> (&'static str, String) {
+    response(HEALTH.is_alive())
+}
+
+/// The status and body of the `/readyz` response.
+pub fn readiness() -> (&'static str, String) {
+    response(HEALTH.is_ready())
+}
+
+fn response(healthy: bool) -> (&'static str, String) {
+    if healthy {
+        ("200 OK", "ok\n".to_string())
+    } else {
+        ("503 Service Unavailable", "unavailable\n".to_string())
+    }
+}
+
+/// Reports the status of a channel while its task runs. The channel is reported as stopped once
+/// this is dropped.
+pub struct ChannelHealth {
+    channel_id: Id<ChannelMarker>,
+}
+
+impl ChannelHealth {
+    /// Start reporting the status of the channel, which hasn't loaded its prompt yet.
+    pub fn register(channel_id: Id<ChannelMarker>) -> Self {
+        HEALTH.with_channel(channel_id, |status| *status = ChannelStatus::default());
+        Self { channel_id }
+    }
+
+    pub fn prompt_loaded(&self) {
+        HEALTH.with_channel(self.channel_id, |status| status.prompt_loaded = true);
+    }
+
+    /// The channel has started handling messages.
+    pub fn running(&self) {
+        HEALTH.with_channel(self.channel_id, |status| status.running = true);
+    }
+}
+
+impl Drop for ChannelHealth {
+    fn drop(&mut self) {
+        HEALTH.with_channel(self.channel_id, |status| status.running = false);
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    gateway_connected: AtomicBool,
+    channels: Mutex<BTreeMap<Id<ChannelMarker>, ChannelStatus>>,
+}
+
+#[derive(Default)]
+struct ChannelStatus {
+    prompt_loaded: bool,
+    running: bool,
+}
+
+impl Health {
+    fn with_channel(&self, channel_id: Id<ChannelMarker>, f: impl FnOnce(&mut ChannelStatus)) {
+        let mut channels = self
+            .channels
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(channels.entry(channel_id).or_default());
+    }
+
+    /// The gateway is connected and at least one channel is running.
+    fn is_alive(&self) -> bool {
+        let channels = self
+            .channels
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.gateway_connected.load(Ordering::Relaxed)
+            && channels.values().any(|status| status.running)
+    }
+
+    /// The bot is alive and every channel has loaded its prompt.
+    fn is_ready(&self) -> bool {
+        let prompts_loaded = self
+            .channels
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .all(|status| status.prompt_loaded);
+        self.is_alive() && prompts_loaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Readiness must also require every prompt to be loaded, liveness only a running channel.
+    #[test]
+    fn liveness_and_readiness() {
+        let health = Health::default();
+        health.with_channel(Id::new(1), |status| status.running = true);
+        health.with_channel(Id::new(2), |_| {});
+        assert!(!health.is_alive());
+
+        health.gateway_connected.store(true, Ordering::Relaxed);
+        assert!(health.is_alive());
+        assert!(!health.is_ready());
+
+        health.with_channel(Id::new(1), |status| status.prompt_loaded = true);
+        health.with_channel(Id::new(2), |status| status.prompt_loaded = true);
+        assert!(health.is_ready());
+    }
+}
@@ -1,17 +1,35 @@
 mod ai_channel;
 mod config;
 mod error;
+mod health;
+mod metrics;
+mod presence;
+mod tools;
 
-use std::{path::Path, sync::Arc};
-use tokio::{select, sync::broadcast};
-use tracing::{error, info, level_filters::LevelFilter};
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tokio::{
+    select,
+    sync::{broadcast, watch},
+};
+use tracing::{error, info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{EnvFilter, filter::Directive};
 use twilight_cache_inmemory::{DefaultInMemoryCache, InMemoryCache, ResourceType};
 use twilight_gateway::{
-    CloseFrame, Event, EventTypeFlags, Intents, Shard, ShardId, StreamExt as _,
+    CloseFrame, ConfigBuilder, Event, EventTypeFlags, Intents, Shard, ShardId, StreamExt as _,
 };
+
+use presence::Presence;
 use twilight_http::Client as HttpClient;
 
+/// How long to wait for the AI channels to finish their current response when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The code the gateway closes the connection with when the bot asks for privileged intents that
+/// aren't enabled for it.
+const DISALLOWED_INTENTS_CLOSE_CODE: u16 = 4014;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::fmt()
@@ -24,12 +42,37 @@ async fn main() -> anyhow::Result<()> {
 
     let config = config::Configuration::read_with_env("CONFIG_PATH", [Path::new("bot.toml")])?;
 
-    let shard = Shard::new(
-        ShardId::ONE,
+    let mut problems = 0;
+    for ai_channel in &config.ai_channels {
+        if let Err(channel_problems) = ai_channel.validate() {
+            for problem in &channel_problems {
+                error!(
+                    "Invalid configuration for channel '{}': {problem}",
+                    ai_channel.get_channel_id()
+                );
+            }
+            problems += channel_problems.len();
+        }
+    }
+    if problems > 0 {
+        anyhow::bail!("The configuration has {problems} problem(s), see the errors above");
+    }
+
+    let mut shard_config = ConfigBuilder::new(
         config.token.clone(),
-        Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT,
+        // Guild events are needed to know which threads belong to the AI channels.
+        Intents::GUILDS
+            | Intents::GUILD_MESSAGES
+            | Intents::GUILD_MESSAGE_REACTIONS
+            | Intents::MESSAGE_CONTENT,
     );
+    // The presence is sent when connecting, so it is also restored after reconnecting.
+    if let Some(activity) = &config.activity {
+        shard_config = shard_config.presence(activity.initial_presence());
+    }
+    let shard = Shard::with_config(ShardId::ONE, shard_config.build());
     let shard_sender = shard.sender();
+    let presence = Arc::new(Presence::new(config.activity, shard.sender()));
 
     let http = Arc::new(HttpClient::builder().token(config.token).build());
 
@@ -41,16 +84,50 @@ async fn main() -> anyhow::Result<()> {
 
     // All incoming events are sent through the broadcast channel and each event is handled by every
     // task that handles events.
-    let (event_tx, event_rx) = broadcast::channel(16);
+    let (event_tx, event_rx) = broadcast::channel(config.event_buffer_size.get());
+
+    let mut addresses: Vec<_> = [config.metrics_address, config.health_address]
+        .into_iter()
+        .flatten()
+        .collect();
+    addresses.dedup();
+    for address in addresses {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(address).await {
+                error!("Unable to serve metrics and health checks on {address}: {err}");
+            }
+        });
+    }
+
+    let http2 = http.clone();
+    tokio::spawn(async move {
+        if let Err(err) = ai_channel::register_commands(&http2).await {
+            error!("{err:?}");
+        }
+    });
+
+    // Looked up before connecting, so messages are never handled without knowing which are the
+    // bot's own.
+    let bot_id = http
+        .current_user()
+        .await
+        .context("Failed to get the bot user, is the token valid?")?
+        .model()
+        .await
+        .context("Failed to deserialize the bot user")?
+        .id;
 
     info!("Serving {} AI channel(s)", config.ai_channels.len());
-    for ai_channel_config in config.ai_channels {
-        tokio::spawn(ai_channel::serve(
-            ai_channel_config,
-            event_rx.resubscribe(),
-            http.clone(),
-        ));
-    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let ai_channels = ai_channel::serve_all(
+        config.ai_channels,
+        &event_rx,
+        http.clone(),
+        bot_id,
+        shutdown_rx,
+        config.max_concurrent_requests,
+        presence,
+    );
 
     info!("Listening for events");
     select! {
@@ -62,6 +139,19 @@ async fn main() -> anyhow::Result<()> {
         },
     }
     _ = shard_sender.close(CloseFrame::NORMAL);
+
+    info!("Shutting down");
+    shutdown_tx.send_replace(true);
+    // Dropping the channels when the timeout is reached aborts any responses still in progress.
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, ai_channels.join_all())
+        .await
+        .is_err()
+    {
+        warn!(
+            "AI channels did not stop within {}s, aborting them",
+            SHUTDOWN_TIMEOUT.as_secs()
+        );
+    }
     Ok(())
 }
 
@@ -78,8 +168,19 @@ async fn handle_events(
             continue;
         };
 
+        match &event {
+            Event::Ready(_) | Event::Resumed => health::set_gateway_connected(true),
+            Event::GatewayClose(_) => health::set_gateway_connected(false),
+            _ => {}
+        }
+
         if let Event::GatewayClose(Some(info)) = &event {
             error!(code = info.code, reason = %info.reason, "Gateway connection closed");
+            if info.code == DISALLOWED_INTENTS_CLOSE_CODE {
+                error!(
+                    "The bot is not allowed to receive the content of messages. Enable the \"Message Content Intent\" of the bot in the discord developer portal."
+                );
+            }
         }
 
         // Update the cache with the event.
@@ -0,0 +1,280 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+use twilight_model::id::{Id, marker::ChannelMarker};
+
+use crate::health;
+
+/// The upper bounds of the response latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 8] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// The metrics of every channel, shared by the whole program.
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Record that a response was generated (or failed to be) for the channel.
+pub fn record_response(channel_id: Id<ChannelMarker>, latency: Duration, success: bool) {
+    METRICS.record_response(channel_id, latency, success);
+}
+
+/// Record the tokens used to generate a response for the channel.
+pub fn record_tokens(channel_id: Id<ChannelMarker>, prompt_tokens: u64, completion_tokens: u64) {
+    METRICS.with_channel(channel_id, |metrics| {
+        metrics.prompt_tokens += prompt_tokens;
+        metrics.completion_tokens += completion_tokens;
+    });
+}
+
+/// Record a feedback reaction to a response in the channel.
+pub fn record_feedback(channel_id: Id<ChannelMarker>, emoji: &str) {
+    METRICS.with_channel(channel_id, |metrics| {
+        *metrics.feedback.entry(emoji.to_string()).or_default() += 1;
+    });
+}
+
+/// Record that messages in the channel were processed.
+pub fn record_messages(channel_id: Id<ChannelMarker>, count: usize) {
+    METRICS.with_channel(channel_id, |metrics| metrics.messages += count as u64);
+}
+
+/// Serve the metrics in the prometheus text format on `/metrics`, and the health checks on
+/// `/healthz` and `/readyz`.
+pub async fn serve(address: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Serving metrics and health checks on http://{address}");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream).await {
+                debug!("Error serving metrics request: {err}");
+            }
+        });
+    }
+}
+
+/// Respond to a single HTTP request.
+async fn handle_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    // Only the request line is needed, so there's no need to read the whole request.
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", METRICS.render()),
+        "/healthz" => health::liveness(),
+        "/readyz" => health::readiness(),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[derive(Default)]
+struct Metrics {
+    channels: Mutex<BTreeMap<Id<ChannelMarker>, ChannelMetrics>>,
+}
+
+#[derive(Default)]
+struct ChannelMetrics {
+    requests: u64,
+    errors: u64,
+    messages: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// The amount of responses in each of the [`LATENCY_BUCKETS`].
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    /// The amount of feedback reactions to responses, by emoji.
+    feedback: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    fn with_channel(&self, channel_id: Id<ChannelMarker>, f: impl FnOnce(&mut ChannelMetrics)) {
+        let mut channels = self
+            .channels
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(channels.entry(channel_id).or_default());
+    }
+
+    fn record_response(&self, channel_id: Id<ChannelMarker>, latency: Duration, success: bool) {
+        self.with_channel(channel_id, |metrics| {
+            metrics.requests += 1;
+            if !success {
+                metrics.errors += 1;
+            }
+
+            let latency = latency.as_secs_f64();
+            metrics.latency_sum += latency;
+            for (bucket, upper_bound) in metrics.latency_buckets.iter_mut().zip(LATENCY_BUCKETS) {
+                if latency <= upper_bound {
+                    *bucket += 1;
+                }
+            }
+        });
+    }
+
+    /// Render the metrics in the prometheus text format.
+    fn render(&self) -> String {
+        let channels = self
+            .channels
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut out = String::new();
+
+        write_counter(
+            &mut out,
+            &channels,
+            "bot_llm_requests_total",
+            "Responses requested from the LLM api.",
+            |metrics| metrics.requests,
+        );
+        write_counter(
+            &mut out,
+            &channels,
+            "bot_llm_errors_total",
+            "Responses that failed to be generated.",
+            |metrics| metrics.errors,
+        );
+        write_counter(
+            &mut out,
+            &channels,
+            "bot_messages_processed_total",
+            "Discord messages processed.",
+            |metrics| metrics.messages,
+        );
+
+        let name = "bot_llm_tokens_total";
+        _ = writeln!(
+            out,
+            "# HELP {name} Tokens used by the LLM api.\n# TYPE {name} counter"
+        );
+        for (channel_id, metrics) in channels.iter() {
+            _ = writeln!(
+                out,
+                "{name}{{channel_id=\"{channel_id}\",kind=\"prompt\"}} {}",
+                metrics.prompt_tokens
+            );
+            _ = writeln!(
+                out,
+                "{name}{{channel_id=\"{channel_id}\",kind=\"completion\"}} {}",
+                metrics.completion_tokens
+            );
+        }
+
+        let name = "bot_response_feedback_total";
+        _ = writeln!(
+            out,
+            "# HELP {name} Feedback reactions to responses.\n# TYPE {name} counter"
+        );
+        for (channel_id, metrics) in channels.iter() {
+            for (emoji, count) in &metrics.feedback {
+                _ = writeln!(
+                    out,
+                    "{name}{{channel_id=\"{channel_id}\",emoji=\"{emoji}\"}} {count}"
+                );
+            }
+        }
+
+        let name = "bot_llm_response_seconds";
+        _ = writeln!(
+            out,
+            "# HELP {name} Time taken to generate a response.\n# TYPE {name} histogram"
+        );
+        for (channel_id, metrics) in channels.iter() {
+            for (count, upper_bound) in metrics.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+                _ = writeln!(
+                    out,
+                    "{name}_bucket{{channel_id=\"{channel_id}\",le=\"{upper_bound}\"}} {count}"
+                );
+            }
+            _ = writeln!(
+                out,
+                "{name}_bucket{{channel_id=\"{channel_id}\",le=\"+Inf\"}} {}",
+                metrics.requests
+            );
+            _ = writeln!(
+                out,
+                "{name}_sum{{channel_id=\"{channel_id}\"}} {}",
+                metrics.latency_sum
+            );
+            _ = writeln!(
+                out,
+                "{name}_count{{channel_id=\"{channel_id}\"}} {}",
+                metrics.requests
+            );
+        }
+
+        out
+    }
+}
+
+/// Write a counter with a value for each channel.
+fn write_counter(
+    out: &mut String,
+    channels: &BTreeMap<Id<ChannelMarker>, ChannelMetrics>,
+    name: &str,
+    help: &str,
+    value: impl Fn(&ChannelMetrics) -> u64,
+) {
+    _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+    for (channel_id, metrics) in channels {
+        _ = writeln!(
+            out,
+            "{name}{{channel_id=\"{channel_id}\"}} {}",
+            value(metrics)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Recorded values must show up in the rendered metrics, labeled by channel.
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::default();
+        let channel_id = Id::new(42);
+
+        metrics.record_response(channel_id, Duration::from_millis(1500), true);
+        metrics.record_response(channel_id, Duration::from_secs(100), false);
+        metrics.with_channel(channel_id, |m| m.prompt_tokens += 10);
+        metrics.with_channel(channel_id, |m| {
+            m.feedback.insert("👍".to_string(), 3);
+        });
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("bot_llm_requests_total{channel_id=\"42\"} 2\n"));
+        assert!(rendered.contains("bot_llm_errors_total{channel_id=\"42\"} 1\n"));
+        assert!(rendered.contains("bot_llm_tokens_total{channel_id=\"42\",kind=\"prompt\"} 10\n"));
+        assert!(
+            rendered.contains("bot_response_feedback_total{channel_id=\"42\",emoji=\"👍\"} 3\n")
+        );
+        assert!(
+            rendered.contains("bot_llm_response_seconds_bucket{channel_id=\"42\",le=\"1\"} 0\n")
+        );
+        assert!(
+            rendered.contains("bot_llm_response_seconds_bucket{channel_id=\"42\",le=\"2\"} 1\n")
+        );
+        assert!(
+            rendered.contains("bot_llm_response_seconds_bucket{channel_id=\"42\",le=\"+Inf\"} 2\n")
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use tracing::debug;
+use twilight_gateway::MessageSender;
+use twilight_model::gateway::{
+    OpCode,
+    payload::outgoing::{UpdatePresence, update_presence::UpdatePresencePayload},
+    presence::{Activity, ActivityType, MinimalActivity, Status},
+};
+
+/// The activity shown in the bot's presence.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActivityConfig {
+    #[serde(default)]
+    kind: ActivityKind,
+    text: String,
+    /// Shown instead of `text` while a response is generated. If not set, the activity doesn't
+    /// change.
+    generating_text: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ActivityKind {
+    /// Only the text is shown.
+    #[default]
+    Custom,
+    Playing,
+    Listening,
+    Watching,
+    Competing,
+}
+
+impl ActivityConfig {
+    /// The presence showing the text as the activity.
+    pub fn presence(&self, text: &str) -> UpdatePresencePayload {
+        let kind = match self.kind {
+            ActivityKind::Custom => ActivityType::Custom,
+            ActivityKind::Playing => ActivityType::Playing,
+            ActivityKind::Listening => ActivityType::Listening,
+            ActivityKind::Watching => ActivityType::Watching,
+            ActivityKind::Competing => ActivityType::Competing,
+        };
+        let mut activity = Activity::from(MinimalActivity {
+            kind,
+            name: text.to_string(),
+            url: None,
+        });
+        // Discord shows the state of custom activities, not their name.
+        if let ActivityKind::Custom = self.kind {
+            activity.state = Some(text.to_string());
+        }
+
+        UpdatePresencePayload {
+            activities: vec![activity],
+            afk: false,
+            since: None,
+            status: Status::Online,
+        }
+    }
+
+    /// The presence the bot starts with.
+    pub fn initial_presence(&self) -> UpdatePresencePayload {
+        self.presence(&self.text)
+    }
+}
+
+/// Updates the presence of the bot while responses are generated, shared by every channel.
+pub struct Presence {
+    activity: Option<ActivityConfig>,
+    sender: MessageSender,
+    /// The amount of responses being generated.
+    generating: Mutex<usize>,
+}
+
+impl Presence {
+    pub fn new(activity: Option<ActivityConfig>, sender: MessageSender) -> Self {
+        Self {
+            activity,
+            sender,
+            generating: Mutex::new(0),
+        }
+    }
+
+    /// Show the generating activity until the returned guard is dropped. When several responses
+    /// are generated at once, it is shown until all of them are done.
+    pub fn generating(&self) -> Generating<'_> {
+        let mut generating = self.lock();
+        *generating += 1;
+        if *generating == 1
+            && let Some(activity) = &self.activity
+            && let Some(text) = &activity.generating_text
+        {
+            self.update(activity.presence(text));
+        }
+        Generating(self)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+        self.generating
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn update(&self, presence: UpdatePresencePayload) {
+        let command = UpdatePresence {
+            d: presence,
+            op: OpCode::PresenceUpdate,
+        };
+        // This only fails when the gateway connection is closed.
+        if let Err(err) = self.sender.command(&command) {
+            debug!("Unable to update the presence: {err}");
+        }
+    }
+}
+
+/// Shows the generating activity while it exists, see [`Presence::generating`].
+pub struct Generating<'a>(&'a Presence);
+
+impl Drop for Generating<'_> {
+    fn drop(&mut self) {
+        let mut generating = self.0.lock();
+        *generating -= 1;
+        if *generating == 0
+            && let Some(activity) = &self.0.activity
+            && activity.generating_text.is_some()
+        {
+            self.0.update(activity.initial_presence());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Custom activities must put the text in the state, which is what discord shows.
+    #[test]
+    fn activity_presence() {
+        let custom = ActivityConfig {
+            kind: ActivityKind::Custom,
+            text: "Helping in #help".to_string(),
+            generating_text: None,
+        };
+        let activity = &custom.initial_presence().activities[0];
+        assert_eq!(activity.kind, ActivityType::Custom);
+        assert_eq!(activity.state.as_deref(), Some("Helping in #help"));
+
+        let listening = ActivityConfig {
+            kind: ActivityKind::Listening,
+            ..custom
+        };
+        let activity = &listening.presence("#help").activities[0];
+        assert_eq!(activity.kind, ActivityType::Listening);
+        assert_eq!(activity.name, "#help");
+        assert_eq!(activity.state, None);
+    }
+}
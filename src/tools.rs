@@ -0,0 +1,224 @@
+mod crate_search;
+mod playground;
+
+use std::time::Duration;
+
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+/// A function the LLM can call while generating a response.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// The name the LLM uses to call the tool.
+    fn name(&self) -> &'static str;
+
+    /// Describes to the LLM what the tool does and when to use it.
+    fn description(&self) -> &'static str;
+
+    /// The JSON schema of the arguments the tool accepts.
+    fn json_schema(&self) -> Value;
+
+    /// Run the tool with the arguments provided by the LLM, returning the result to give back to
+    /// the LLM.
+    async fn call(&self, args: Value) -> anyhow::Result<String>;
+}
+
+/// The tools that can be enabled for a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolKind {
+    /// Search crates.io for crates.
+    CrateSearch,
+    /// Run a snippet of rust code on the rust playground.
+    RustPlayground,
+}
+
+/// The longest a tool may take before the call is given up, so a stalled api can't block the
+/// channel.
+const TOOL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The tools that are available in a channel.
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+    timeout: Duration,
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        Self {
+            tools: Vec::new(),
+            timeout: TOOL_TIMEOUT,
+        }
+    }
+}
+
+impl ToolRegistry {
+    /// Create a registry with the given tools enabled.
+    pub fn new(kinds: &[ToolKind]) -> Self {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("rustcentral_bot/", env!("CARGO_PKG_VERSION")))
+            .timeout(TOOL_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        let mut registry = Self::default();
+        for kind in kinds {
+            let tool: Box<dyn Tool> = match kind {
+                ToolKind::CrateSearch => Box::new(crate_search::CrateSearch::new(http.clone())),
+                ToolKind::RustPlayground => Box::new(playground::RustPlayground::new(http.clone())),
+            };
+            registry.register(tool);
+        }
+        registry
+    }
+
+    /// Make a tool available to the LLM. Replaces any tool with the same name.
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        self.tools.retain(|existing| existing.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// The definitions of every tool, in the format expected by the LLM api.
+    pub fn definitions(&self) -> Vec<ChatCompletionTool> {
+        self.tools
+            .iter()
+            .map(|tool| ChatCompletionTool {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionObject {
+                    name: tool.name().to_string(),
+                    description: Some(tool.description().to_string()),
+                    parameters: Some(tool.json_schema()),
+                    strict: None,
+                },
+            })
+            .collect()
+    }
+
+    /// Call the tool with the given name.
+    ///
+    /// Errors are returned as the result of the call, so the LLM can see what went wrong and
+    /// respond accordingly.
+    pub async fn call(&self, name: &str, args: &str) -> String {
+        let Some(tool) = self.tools.iter().find(|tool| tool.name() == name) else {
+            warn!("LLM tried to call unknown tool '{name}'");
+            return format!("Error: there is no tool called '{name}'");
+        };
+
+        let args = match serde_json::from_str(args) {
+            Ok(args) => args,
+            Err(err) => return format!("Error: the arguments are not valid JSON: {err}"),
+        };
+
+        debug!("Calling tool '{name}' with {args}");
+        match tokio::time::timeout(self.timeout, tool.call(args)).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => {
+                warn!("Tool '{name}' failed: {err:?}");
+                format!("Error: {err}")
+            }
+            Err(_) => {
+                warn!(
+                    "Tool '{name}' did not finish within {}s",
+                    self.timeout.as_secs()
+                );
+                format!(
+                    "Error: the tool did not finish within {}s",
+                    self.timeout.as_secs()
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait]
+    impl Tool for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn description(&self) -> &'static str {
+            "Returns the text it is given."
+        }
+
+        fn json_schema(&self) -> Value {
+            serde_json::json!({
+                "type": "object",
+                "properties": { "text": { "type": "string" } },
+                "required": ["text"],
+            })
+        }
+
+        async fn call(&self, args: Value) -> anyhow::Result<String> {
+            args["text"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow::anyhow!("missing text"))
+        }
+    }
+
+    /// Calls must be dispatched to the tool with the matching name.
+    #[tokio::test]
+    async fn dispatches_to_tool() {
+        let mut registry = ToolRegistry::default();
+        registry.register(Box::new(Echo));
+
+        assert_eq!(registry.call("echo", r#"{"text":"hello"}"#).await, "hello");
+    }
+
+    /// Mistakes made by the LLM must be returned to it rather than failing the response.
+    #[tokio::test]
+    async fn errors_are_returned_to_llm() {
+        let mut registry = ToolRegistry::default();
+        registry.register(Box::new(Echo));
+
+        assert!(registry.call("unknown", "{}").await.starts_with("Error"));
+        assert!(registry.call("echo", "not json").await.starts_with("Error"));
+        assert!(registry.call("echo", "{}").await.starts_with("Error"));
+    }
+
+    struct Stalled;
+
+    #[async_trait]
+    impl Tool for Stalled {
+        fn name(&self) -> &'static str {
+            "stalled"
+        }
+
+        fn description(&self) -> &'static str {
+            "Never finishes."
+        }
+
+        fn json_schema(&self) -> Value {
+            serde_json::json!({ "type": "object" })
+        }
+
+        async fn call(&self, _args: Value) -> anyhow::Result<String> {
+            std::future::pending().await
+        }
+    }
+
+    /// A tool that doesn't finish must be given up on, returning an error to the LLM.
+    #[tokio::test]
+    async fn stalled_tools_time_out() {
+        let mut registry = ToolRegistry {
+            timeout: Duration::from_millis(10),
+            ..ToolRegistry::default()
+        };
+        registry.register(Box::new(Stalled));
+
+        assert!(registry.call("stalled", "{}").await.starts_with("Error"));
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::Tool;
+
+/// The maximum amount of crates to return to the LLM.
+const MAX_RESULTS: u32 = 5;
+
+/// Searches crates.io for crates matching a query.
+pub struct CrateSearch {
+    http: reqwest::Client,
+}
+
+impl CrateSearch {
+    pub fn new(http: reqwest::Client) -> Self {
+        Self { http }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    crates: Vec<CrateInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateInfo {
+    name: String,
+    max_version: String,
+    description: Option<String>,
+    downloads: u64,
+}
+
+#[async_trait]
+impl Tool for CrateSearch {
+    fn name(&self) -> &'static str {
+        "search_crates"
+    }
+
+    fn description(&self) -> &'static str {
+        "Search crates.io for rust crates. Returns the name, latest version, description and documentation link of the best matches."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "The search terms, for example \"async http client\".",
+                },
+            },
+            "required": ["query"],
+        })
+    }
+
+    async fn call(&self, args: Value) -> anyhow::Result<String> {
+        let query = args["query"]
+            .as_str()
+            .context("missing the 'query' argument")?;
+
+        let response: SearchResponse = self
+            .http
+            .get("https://crates.io/api/v1/crates")
+            .query(&[("q", query), ("per_page", &MAX_RESULTS.to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if response.crates.is_empty() {
+            return Ok(format!("No crates found for '{query}'"));
+        }
+
+        Ok(response
+            .crates
+            .iter()
+            .map(|info| {
+                format!(
+                    "{} {} ({} downloads): {}\ndocs: https://docs.rs/{}",
+                    info.name,
+                    info.max_version,
+                    info.downloads,
+                    info.description.as_deref().unwrap_or("no description"),
+                    info.name
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::Tool;
+
+/// The maximum amount of characters of output to return to the LLM.
+const MAX_OUTPUT_CHARS: usize = 2000;
+
+/// Runs rust code on the official rust playground.
+pub struct RustPlayground {
+    http: reqwest::Client,
+}
+
+impl RustPlayground {
+    pub fn new(http: reqwest::Client) -> Self {
+        Self { http }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecuteResponse {
+    success: bool,
+    stdout: String,
+    stderr: String,
+}
+
+#[async_trait]
+impl Tool for RustPlayground {
+    fn name(&self) -> &'static str {
+        "run_rust"
+    }
+
+    fn description(&self) -> &'static str {
+        "Compile and run a rust program on the rust playground using the latest stable compiler. Returns the compiler output and what the program printed."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "description": "The full source of the program, including a main function.",
+                },
+            },
+            "required": ["code"],
+        })
+    }
+
+    async fn call(&self, args: Value) -> anyhow::Result<String> {
+        let code = args["code"]
+            .as_str()
+            .context("missing the 'code' argument")?;
+
+        let response: ExecuteResponse = self
+            .http
+            .post("https://play.rust-lang.org/execute")
+            .json(&json!({
+                "channel": "stable",
+                "mode": "debug",
+                "edition": "2024",
+                "crateType": "bin",
+                "tests": false,
+                "backtrace": false,
+                "code": code,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let output = format!(
+            "success: {}\nstderr:\n{}\nstdout:\n{}",
+            response.success, response.stderr, response.stdout
+        );
+
+        Ok(match output.char_indices().nth(MAX_OUTPUT_CHARS) {
+            Some((idx, _)) => format!("{}\n(output truncated)", &output[..idx]),
+            None => output,
+        })
+    }
+}